@@ -0,0 +1,72 @@
+use std::hint::black_box;
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use tokio::sync::mpsc;
+
+use pico_rs_8::cpu::CPU;
+use pico_rs_8::display::{Display, PixelBuffer};
+use pico_rs_8::emulator::ROM_ADDRESS;
+use pico_rs_8::memory::Memory;
+use pico_rs_8::quirks::Quirks;
+
+// A tight loop touching every opcode family except DXYN, which blocks on the frame channel
+const PROGRAM: [u16; 18] = [
+    0x6005, // V0 = 5
+    0x6107, // V1 = 7
+    0x8014, // V0 += V1
+    0x8015, // V0 -= V1
+    0x8012, // V0 &= V1
+    0x8011, // V0 |= V1
+    0x8013, // V0 ^= V1
+    0x8016, // V0 >>= 1
+    0x800E, // V0 <<= 1
+    0x3005, // Skip if V0 == 5
+    0x4105, // Skip if V1 != 5
+    0x7001, // V0 += 1
+    0xA300, // I = 0x300
+    0xF01E, // I += V0
+    0xF033, // BCD of V0 at I
+    0xF165, // Load V0..V1 from I
+    0x9010, // Skip if V0 != V1
+    0x1200, // Jump back to the start
+];
+
+const STEPS: usize = 1000;
+
+fn setup() -> (CPU, Memory, Display, mpsc::Receiver<PixelBuffer>) {
+    let (sender, receiver) = mpsc::channel::<PixelBuffer>(1);
+    let mut memory: Memory = Memory::new();
+    for (i, opcode) in PROGRAM.iter().enumerate() {
+        let address: usize = ROM_ADDRESS as usize + i * 2;
+        memory.data[address..address + 2].copy_from_slice(&opcode.to_be_bytes());
+    }
+    (CPU::new(ROM_ADDRESS, Quirks::default()), memory, Display::new(64, 32, sender), receiver)
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let keys: [bool; 16] = [false; 16];
+    let mut group = c.benchmark_group("decode");
+
+    let (mut cpu, mut memory, mut display, _receiver) = setup();
+    group.bench_function("jump_table", |b| {
+        b.iter(|| {
+            for _ in 0..STEPS {
+                let _ = cpu.decode(&mut memory, &mut display, black_box(&keys));
+            }
+        })
+    });
+
+    let (mut cpu, mut memory, mut display, _receiver) = setup();
+    group.bench_function("match", |b| {
+        b.iter(|| {
+            for _ in 0..STEPS {
+                let _ = cpu.decode_match(&mut memory, &mut display, black_box(&keys));
+            }
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_decode);
+criterion_main!(benches);
@@ -0,0 +1,55 @@
+#![no_main]
+
+use std::sync::atomic::{AtomicU16, AtomicU64};
+use std::sync::Arc;
+
+use libfuzzer_sys::fuzz_target;
+use tokio::sync::mpsc;
+
+use pico_rs_8::cpu::CpuView;
+use pico_rs_8::display::PixelBuffer;
+use pico_rs_8::emulator::{Emulator, EmulatorCommand, EmulatorData, EmulatorEvent};
+use pico_rs_8::quirks::Quirks;
+use pico_rs_8::trace::TraceEntry;
+
+// Enough cycles to get through loops and subroutines without a spinning ROM stalling the fuzzer
+const CYCLES: usize = 10_000;
+
+// Load arbitrary bytes as a ROM and run it, any panic is reported as a crash
+fuzz_target!(|rom: &[u8]| {
+    let (_font_file_content_sender, font_file_content) = mpsc::channel::<Vec<u8>>(1);
+    let keys: Arc<AtomicU16> = Arc::new(AtomicU16::new(0));
+    let cycles: Arc<AtomicU64> = Arc::new(AtomicU64::new(0));
+    let (_command_sender, commands) = mpsc::channel::<EmulatorCommand>(1);
+    let (events, _event_receiver) = mpsc::channel::<EmulatorEvent>(16);
+    let (cpu_state, _cpu_state_receiver) = mpsc::channel::<CpuView>(1);
+    let (opcode_counts, _opcode_counts_receiver) = mpsc::channel::<Vec<(u16, u64)>>(1);
+    let (recent_opcodes, _recent_opcodes_receiver) = mpsc::channel::<Vec<TraceEntry>>(1);
+    let (pixel_buffer_sender, mut pixel_buffer_receiver) = mpsc::channel::<PixelBuffer>(1);
+
+    let mut emulator: Emulator = Emulator::new(
+        EmulatorData {
+            font_file_content,
+            keys,
+            commands,
+            events,
+            cpu_state,
+            opcode_counts,
+            recent_opcodes,
+            cycles,
+            cpu_hz: 0, // Unlimited
+        },
+        pixel_buffer_sender,
+        Quirks::default(),
+    );
+    emulator.set_font(&Emulator::get_default_font());
+    if emulator.load_rom(rom).is_err() {
+        return;
+    }
+
+    for _ in 0..CYCLES {
+        emulator.cycle();
+        // DXYN blocks until its frame is taken
+        while pixel_buffer_receiver.try_recv().is_ok() {}
+    }
+});
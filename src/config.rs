@@ -0,0 +1,43 @@
+use std::path::PathBuf;
+
+// Directory where the emulator keeps its files, e.g. ~/.config/pico-rs-8
+pub fn config_dir() -> PathBuf {
+    let base: PathBuf = if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME") {
+        PathBuf::from(dir)
+    } else if let Some(dir) = std::env::var_os("APPDATA") {
+        // Windows
+        PathBuf::from(dir)
+    } else if let Some(home) = std::env::var_os("HOME") {
+        PathBuf::from(home).join(".config")
+    } else {
+        PathBuf::from(".")
+    };
+    base.join("pico-rs-8")
+}
+
+// GUI preferences are kept as key=value lines in <config dir>/settings.txt
+fn settings_path() -> PathBuf {
+    config_dir().join("settings.txt")
+}
+
+pub fn load_setting(key: &str) -> Option<String> {
+    let settings: String = std::fs::read_to_string(settings_path()).ok()?;
+    settings
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(name, _)| name.trim() == key)
+        .map(|(_, value)| value.trim().to_string())
+}
+
+// Replace or add one setting, keeping the rest of the file
+pub fn save_setting(key: &str, value: &str) -> std::io::Result<()> {
+    let settings: String = std::fs::read_to_string(settings_path()).unwrap_or_default();
+    let mut lines: Vec<String> = settings
+        .lines()
+        .filter(|line| line.split_once('=').is_none_or(|(name, _)| name.trim() != key))
+        .map(str::to_string)
+        .collect();
+    lines.push(format!("{}={}", key, value));
+    std::fs::create_dir_all(config_dir())?;
+    std::fs::write(settings_path(), lines.join("\n") + "\n")
+}
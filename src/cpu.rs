@@ -1,64 +1,223 @@
-use std::sync::{Arc, Mutex};
 
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::disasm;
 use crate::memory::{Memory};
 use crate::display::Display;
-use crate::emulator::{FONT_ADDRESS};
+use crate::emulator::{FONT_ADDRESS, FONT_BIG_ADDRESS};
+use crate::quirks::{LoadStoreIncrement, Quirks};
+
+
+// An opcode from an extension the current settings don't run, see disasm::extension_family
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PlatformMismatch {
+    pub pc: u16,
+    pub opcode: u16,
+    pub family: &'static str,
+    pub platform: &'static str,
+}
+
+// A problem with the instruction decode just ran. The PC has still moved on past it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuError {
+    UnknownOpcode(u16),
+    StackOverflow, // 2NNN with all 16 stack slots in use
+    StackUnderflow, // 00EE with nothing on the stack
+    InvalidRegister(usize),
+}
+
+impl std::fmt::Display for CpuError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CpuError::UnknownOpcode(opcode) => write!(f, "Unknown opcode {:04X}", opcode),
+            CpuError::StackOverflow => write!(f, "Stack overflow: cannot call subroutine"),
+            CpuError::StackUnderflow => write!(f, "Stack underflow: cannot return from subroutine"),
+            CpuError::InvalidRegister(register) => write!(f, "Invalid register index: {}", register),
+        }
+    }
+}
 
+impl std::error::Error for CpuError {}
 
+// What DXYN does with sprite rows past the end of RAM
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpriteReadOverflow {
+    Wrap, // Read on from address 0
+    Clamp, // Drop the rows past the end
+    #[default]
+    WarnAndClamp, // Drop them and warn, the first time per ROM load. Usually a bad I in the ROM.
+}
 
+// Handles opcodes the core doesn't recognize, see CPU::custom_handler
+pub type CustomOpcodeHandler = dyn FnMut(&mut CPU, u16, &mut Memory, &mut Display) -> bool + Send;
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
     pub pc: u16, // Program Counter
     pub sp: u8,  // Stack Pointer
     pub stack: [u16; 16], // Stack for subroutine calls
     pub v: [u8; 16], // General Purpose Registers (from 0 to F)
     pub i: u16, // Index Register
-    pub delay_timer: Arc<Mutex<u8>>, // Delay Timer
-    pub sound_timer: Arc<Mutex<u8>>, // Sound Timer
+    pub delay_timer: u8, // Delay Timer
+    pub sound_timer: u8, // Sound Timer
+    timer_hz: u32, // How often the timers count down, see set_timer_hz and tick_timers
+    pub chip8e: bool, // Enables the CHIP-8E extra opcodes
+    pub schip: bool, // Enables the SUPER-CHIP extra opcodes
+    pub xochip: bool, // Enables the XO-CHIP extra opcodes
+    pub halted: bool, // 00FD has run, the program is over until a reset or state load
+    pub quirks: Quirks, // The sprite wrap quirks are applied by Display
+    timer_wait_started: bool, // FX4F has set the delay timer and is waiting for it to run out
+    pub fx0a_min_hold_frames: u32, // FX0A only takes a key held this many 60 Hz frames, 0 takes it at once
+    fx0a_held: Option<(u8, u32)>, // The key FX0A is watching and the frames it has been held, see count_frame
+    fx0a_keys_up: Option<[bool; 16]>, // Keys that were up at some point since FX0A started waiting
+    fx0a_key_down: Option<u8>, // The key FX0A took, it finishes once the key is released
+    pub sprite_read_overflow: SpriteReadOverflow, // DXYN sprites that run past the end of RAM
+    sprite_overflow_warned: bool, // WarnAndClamp has warned since the CPU was created
+    memory_wrap_warned: bool, // FX33, FX55 or FX65 has warned about running past the end of RAM
+    pub flags: [u8; FLAG_COUNT], // SUPER-CHIP flag registers, kept over resets, see Emulator::persist_flags
+    flags_changed: bool, // FX75 has run since take_flags_changed
+    pub font_char_size: u8, // Bytes per font character, FX29 points I at FONT_ADDRESS + VX * font_char_size
+    warnings: Vec<String>, // Problems hit while running, until the emulator takes them
+    decode_error: Option<CpuError>, // Set by a handler, returned by decode
+    rng: StdRng, // Source for CXNN, see seed_rng
+    platform_mismatches: Vec<PlatformMismatch>, // Until the emulator takes them
+    mismatched_families: Vec<&'static str>, // Families already reported, each is only reported once per CPU
+    // Called with the opcode for anything the built-in decoding doesn't recognize, only after every
+    // built-in handler (including CHIP-8E ones while chip8e is on) has passed on it. The handler returns
+    // true if it ran the opcode. PC still points at the opcode during the call; if the handler leaves it
+    // there, PC moves on by 2 as usual, otherwise execution continues wherever the handler set it.
+    // Returning false falls through to the unknown opcode warning.
+    pub custom_handler: Option<Box<CustomOpcodeHandler>>,
 }
 
-// In this mode, the CPU will set VX = VY when left and right shifting
-const SHIFT_SET_MODE: bool = true;
-// In this mode, the CPU will add VX to NNN in the BNNN instruction
-const JUMP_VX_MODE: bool = false;
+// A copy of the CPU registers, without the timers
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CpuSnapshot {
+    pub pc: u16,
+    pub sp: u8,
+    pub stack: [u16; 16],
+    pub v: [u8; 16],
+    pub i: u16,
+}
+
+// What a debugger shows: the registers and both timers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuView {
+    pub pc: u16,
+    pub sp: u8,
+    pub i: u16,
+    pub v: [u8; 16],
+    pub stack: [u16; 16],
+    pub delay: u8,
+    pub sound: u8,
+    pub beeping: bool, // See CPU::is_beeping
+}
+
+// SUPER-CHIP flag registers, FX75 and FX85 reach V0 to V7
+pub const FLAG_COUNT: usize = 8;
+
+// The timer rate on every CHIP-8 platform
+pub const DEFAULT_TIMER_HZ: u32 = 60;
+
+// Warnings held until the emulator takes them, extras are dropped
+const MAX_WARNINGS: usize = 16;
+
+// What decode does with the PC after a handler runs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PcAction {
+    // Move on to the next instruction
+    Continue,
+    // The handler already set the PC (a jump, call, return, or staying put to wait)
+    Jumped,
+}
+
+// Every opcode handler has the same signature so they can be stored in the dispatch table
+type OpcodeHandler = fn(&mut CPU, &mut Memory, &mut Display, &[bool; 16], u16) -> PcAction;
+
+// Handlers indexed by the first nibble of the opcode.
+// The 0x0, 0x8, 0xE and 0xF families share a first nibble, so their entries dispatch again on the low bits.
+const DISPATCH_TABLE: [OpcodeHandler; 16] = [
+    CPU::dispatch_0xxx,
+    CPU::op_1nnn,
+    CPU::op_2nnn,
+    CPU::op_3xnn,
+    CPU::op_4xnn,
+    CPU::dispatch_5xyn,
+    CPU::op_6xnn,
+    CPU::op_7xnn,
+    CPU::dispatch_8xyn,
+    CPU::op_9xy0,
+    CPU::op_annn,
+    CPU::dispatch_bxxx,
+    CPU::op_cxnn,
+    CPU::op_dxyn,
+    CPU::dispatch_exxx,
+    CPU::dispatch_fxxx,
+];
+
+// Opcode field helpers
+fn x(opcode: u16) -> usize {
+    ((opcode >> 8) & 0x0F) as usize
+}
+fn y(opcode: u16) -> usize {
+    ((opcode >> 4) & 0x0F) as usize
+}
+fn n(opcode: u16) -> usize {
+    (opcode & 0x0F) as usize
+}
+fn nn(opcode: u16) -> u8 {
+    (opcode & 0xFF) as u8
+}
+fn nnn(opcode: u16) -> u16 {
+    opcode & 0x0FFF
+}
+
+// Registers X to Y for 5XY2 and 5XY3, counting down when X is above Y
+fn register_range(x: usize, y: usize) -> Vec<usize> {
+    if x <= y { (x..=y).collect() } else { (y..=x).rev().collect() }
+}
+
+// VX can hold any byte, but there are only 16 keys, so only the low nibble selects the key
+fn key_pressed(keys: &[bool; 16], key: u8) -> bool {
+    keys[(key & 0x0F) as usize]
+}
 
 impl CPU {
     // Run a rom
-    pub fn new(program_counter: u16) -> Self {
-        let delay_timer: Arc<Mutex<u8>> = Arc::new(Mutex::new(0));
-        let delay_timer_thread: Arc<Mutex<u8>> = Arc::clone(&delay_timer);
-        let sound_timer: Arc<Mutex<u8>> = Arc::new(Mutex::new(0));
-        let sound_timer_thread: Arc<Mutex<u8>> = Arc::clone(&sound_timer);
-
-        std::thread::spawn(move || {
-            loop {
-                // Delay Timer Lock
-                {
-                    let mut delay_timer: std::sync::MutexGuard<'_, u8> = delay_timer_thread.lock().unwrap();
-                    if *delay_timer > 0 {
-                        *delay_timer -= 1;
-                    }
-                }
-                // Sound Timer Lock
-                {
-                    let mut sound_timer: std::sync::MutexGuard<'_, u8> = sound_timer_thread.lock().unwrap();
-                    if *sound_timer > 0 {
-                        *sound_timer -= 1;
-                    }
-                }
-                
-                std::thread::sleep(std::time::Duration::from_millis(1000 / 60)); // Approximately 60Hz
-            }
-        });
-        
-        // Create a thread that seperately decrements the timers at 60Hz
+    pub fn new(program_counter: u16, quirks: Quirks) -> Self {
         CPU {
             pc: program_counter,
             sp: 0,
             stack: [0; 16],
             v: [0; 16],
             i: 0,
-            delay_timer: delay_timer,
-            sound_timer: sound_timer,
+            delay_timer: 0,
+            sound_timer: 0,
+            timer_hz: DEFAULT_TIMER_HZ,
+            chip8e: false,
+            schip: false,
+            xochip: false,
+            halted: false,
+            quirks,
+            timer_wait_started: false,
+            fx0a_min_hold_frames: 0,
+            fx0a_held: None,
+            fx0a_keys_up: None,
+            fx0a_key_down: None,
+            flags: [0; FLAG_COUNT],
+            flags_changed: false,
+            font_char_size: 5,
+            sprite_read_overflow: SpriteReadOverflow::default(),
+            sprite_overflow_warned: false,
+            memory_wrap_warned: false,
+            custom_handler: None,
+            rng: StdRng::seed_from_u64(rand::random()),
+            platform_mismatches: Vec::new(),
+            mismatched_families: Vec::new(),
+            warnings: Vec::new(),
+            decode_error: None,
         }
 
     }
@@ -67,452 +226,1609 @@ impl CPU {
         self.pc = pc;
     }
 
-    pub fn decode(&mut self, memory: &mut Memory, display: &mut Display, keys: &[bool; 16]) {
-        // Opcode is a 16 bit value with two bytes
-        let opcode: u16 = (memory.data[self.pc as usize] as u16) << 8 | memory.data[self.pc as usize + 1] as u16;
-        // There are 4 nibbles
-        let nibbles: [u8; 4] = [
-            (opcode >> 12) as u8, // First nibble
-            (opcode >> 8 & 0x0F) as u8, // Second nibble
-            (opcode >> 4 & 0x0F) as u8, // Third nibble
-            (opcode & 0x0F) as u8, // Fourth nibble
-        ];
+    // Make CXNN return the same numbers every run with this seed
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn snapshot(&self) -> CpuSnapshot {
+        CpuSnapshot {
+            pc: self.pc,
+            sp: self.sp,
+            stack: self.stack,
+            v: self.v,
+            i: self.i,
+        }
+    }
+
+    pub fn view(&self) -> CpuView {
+        CpuView {
+            pc: self.pc,
+            sp: self.sp,
+            i: self.i,
+            v: self.v,
+            stack: self.stack,
+            delay: self.delay_timer,
+            sound: self.sound_timer,
+            beeping: self.is_beeping(),
+        }
+    }
+
+    // Whether the buzzer sounds, which takes a sound timer of at least the sound_timer_min quirk
+    pub fn is_beeping(&self) -> bool {
+        self.sound_timer > 0 && self.sound_timer >= self.quirks.sound_timer_min
+    }
+
+    // Overwrite the registers with the snapshot. The timers are left alone
+    pub fn restore(&mut self, snapshot: &CpuSnapshot) {
+        self.pc = snapshot.pc;
+        self.sp = snapshot.sp;
+        self.stack = snapshot.stack;
+        self.v = snapshot.v;
+        self.i = snapshot.i;
+        self.halted = false;
+        self.fx0a_held = None;
+        self.fx0a_keys_up = None;
+        self.fx0a_key_down = None;
+    }
+
+    // Move past the next instruction, for the skip opcodes. On XO-CHIP, F000 NNNN is 4 bytes long
+    // and the skip goes past all of it.
+    fn skip_next(&mut self, memory: &Memory) {
+        let next: u16 = self.pc.wrapping_add(2);
+        let next_opcode: u16 = (memory.read(next as usize) as u16) << 8 | memory.read(next as usize + 1) as u16;
+        let length: u16 = if self.xochip && next_opcode == 0xF000 { 4 } else { 2 };
+        self.pc = self.pc.wrapping_add(length);
+    }
+
+    // Return address on top of the stack, if there is one
+    fn stack_top(&self) -> Option<u16> {
+        let top: usize = (self.sp as usize).checked_sub(1)?;
+        self.stack.get(top).copied()
+    }
+
+    // Opcode is a 16 bit value with two bytes
+    pub fn fetch(&self, memory: &Memory) -> u16 {
+        (memory.read(self.pc as usize) as u16) << 8 | memory.read(self.pc as usize + 1) as u16
+    }
+
+    pub fn decode(&mut self, memory: &mut Memory, display: &mut Display, keys: &[bool; 16]) -> Result<(), CpuError> {
+        let opcode: u16 = self.fetch(memory);
         // This println runs every execution, but severely lags the emulator if printing (because turns out IO is slow)
         // This should only be enabled whenever testing because it messes with key input and display timing
         //println!("Executing Opcode: {:04X} at PC: {:04X}", opcode, self.pc);
-        match nibbles {
-            // 00E0: Clear the display
-            [0x0, 0x0, 0xE, 0x0] => {
-                display.clear();
-            }
-            // 00EE: Return from a subroutine
-            [0x0, 0x0, 0xE, 0xE] => {
-                if self.sp > 0 {
-                    // Set PC to address at the top
-                    self.pc = self.stack[self.sp as usize - 1];
-                    // Pop stack pointer
-                    self.sp -= 1;
-
-                } else {
-                    println!("Stack underflow: Cannot return from subroutine");
-                }
-            }
-            // 1NNN: Jump to location NNN
-            [0x1, _, _, _] => {
-                let address = ((nibbles[1] as u16) << 8) | ((nibbles[2] as u16) << 4) | nibbles[3] as u16;
-                self.pc = address;
-                // Do not increment PC here, as it is set directly
-                self.pc -= 2;
-            }
-            // 2NNN: Call Subroutine at NNN
-            [0x2, _, _, _] => {
-                let address: u16 = ((nibbles[1] as u16) << 8) | ((nibbles[2] as u16) << 4) | nibbles[3] as u16;
-                if self.sp < 15 {
-                    self.stack[self.sp as usize] = self.pc;
-                    self.sp += 1;
-                    // Set PC to address, minus 2 to account for increment
-                    self.pc = address - 2; 
-                } else {
-                    println!("Stack overflow: Cannot call subroutine");
-                }
-            }
-            
-            // 3XNN: Skip Next Instruction if VX == NN
-            [0x3, _, _, _] => {
-                let vx: usize = nibbles[1] as usize;
-                let val_vx: u8 = self.v[vx];
-                let nn: u8 = ((nibbles[2]) << 4) | nibbles[3];
-                if vx < 16 && val_vx == nn {
-                    self.pc += 2; // Skip next instruction
-                }
-            }
-            // 4XNN: Skip Next Instruction if VX != NN
-            [0x4, _, _, _] => {
-                let vx: usize = nibbles[1] as usize;
-                let val_vx: u8 = self.v[vx];
-                let nn: u8 = ((nibbles[2]) << 4) | nibbles[3];
-                if vx < 16 && val_vx != nn {
-                    self.pc += 2; // Skip next instruction
-                }
-            }
-            // 5XY0: Skip Next Instruction if VX == VY
-            [0x5, _, _, 0x0] => {
-                let vx: usize = nibbles[1] as usize;
-                let vy: usize = nibbles[2] as usize;
-                let val_vx: u8 = self.v[vx];
-                let val_vy: u8 = self.v[vy];
-                if vx < 16 && vy < 16 && val_vx == val_vy{
-                    self.pc += 2; // Skip next instruction
-                }
-            }
-            // 6XNN: Set VX to NN
-            [0x6, _, _, _] => {
-                let vx = nibbles[1] as usize;
-                let nn = ((nibbles[2] as u16) << 4) | nibbles[3] as u16;
-                if vx < 16 {
-                    self.v[vx] = nn as u8;
-                } else {
-                    println!("Invalid register index: {}", vx);
-                }
-            }
-            // 7XNN: Add NN to VX
-            [0x7, _, _, _] => {
-                // Add NN to Vx
-                let vx = nibbles[1] as usize;
-                let nn = ((nibbles[2] as u16) << 4) | nibbles[3] as u16;
-                if vx < 16 {
-                    self.v[vx] = self.v[vx].wrapping_add(nn as u8);
-                } else {
-                    println!("Invalid register index: {}", vx);
-                }
-            }
-            // Arithmetic
-            // 8XY0: Set VX to VY
-            [0x8, _, _, 0x0] => {
-                let vx = nibbles[1] as usize;
-                let vy = nibbles[2] as usize;
-
-                if vx < 16 && vy < 16 {
-                    self.v[vx] = self.v[vy];
-                } else {
-                    println!("Invalid register index: {} or {}", vx, vy);
-                }
-            }
-            // 8XY1: Set VX to VX OR VY
-            [0x8, _, _, 0x1] => {
-                let vx: usize = nibbles[1] as usize;
-                let vy = nibbles[2] as usize;
-                self.v[0xF] = 0;
-                if vx < 16 && vy < 16 {
-                    self.v[vx] |= self.v[vy];
-                } else {
-                    println!("Invalid register index: {} or {}", vx, vy);
-                }
-            }
-            // 8XY2: Set VX to VX AND VY
-            [0x8, _, _, 0x2] => {
-                let vx: usize = nibbles[1] as usize;
-                let vy: usize = nibbles[2] as usize;
-                self.v[0xF] = 0;
-                if vx < 16 && vy < 16 {
-                    self.v[vx] &= self.v[vy];
-                } else {
-                    println!("Invalid register index: {} or {}", vx, vy);
-                }
-            }
-            // 8XY3: Set VX to VX XOR VY
-            [0x8, _, _, 0x3] => {
-                let vx: usize = nibbles[1] as usize;
-                let vy: usize = nibbles[2] as usize;
-                self.v[0xF] = 0;
-                if vx < 16 && vy < 16 {
-                    self.v[vx] ^= self.v[vy];
-                } else {
-                    println!("Invalid register index: {} or {}", vx, vy);
-                }
-            }
-            // 8XY4: Set VX to VX + VY, VF is set to carry
-            [0x8, _, _, 0x4] => {
-                let vx = nibbles[1] as usize;
-                let vy = nibbles[2] as usize;
-                if vx < 16 && vy < 16 {
-                    let (result, carry) = self.v[vx].overflowing_add(self.v[vy]);
-                    self.v[vx] = result;
-                    self.v[0xF] = carry as u8;
-                } else {
-                    println!("Invalid register index: {} or {}", vx, vy);
-                }
-            }
-            // 8XY5: Set VX to VX - VY, VF is set to NOT borrow
-            [0x8, _, _, 0x5] => {
-                let vx = nibbles[1] as usize;
-                let vy = nibbles[2] as usize;
-                if vx < 16 && vy < 16 {                    
-                    let (result, borrow) = self.v[vx].overflowing_sub(self.v[vy]);
-                    self.v[vx] = result;
-
-                    self.v[0xF] = (!borrow) as u8; // Set VF to 1 if no borrow, 0 if borrow
-                    
-                } else {
-                    println!("Invalid register index: {} or {}", vx, vy);
-                }
-            }
-            // 8XY6: Shift VX right by 1, VF is set to the least significant bit of VX
-            [0x8, _, _, 0x6] => {
-                let vx: u8 = nibbles[1] as u8;
-                let vy: u8 = nibbles[2] as u8;
-                if vx < 16 {
-                    if SHIFT_SET_MODE {
-                        self.v[vx as usize] = self.v[vy as usize];
-                    }
-                    let bit: u8 = self.v[vx as usize] & 0x01; // Get the least significant bit
-                    self.v[vx as usize] >>= 1; // Shift right
-                    self.v[0xF] = bit; // Set VF to LSB of VX
-                } else {
-                    println!("Invalid register index: {}", vx);
-                }
+        let pc_action: PcAction = DISPATCH_TABLE[(opcode >> 12) as usize](self, memory, display, keys, opcode);
+        // Increment the program counter unless the handler moved it
+        if pc_action == PcAction::Continue {
+            self.pc = self.pc.wrapping_add(2);
+        }
+        self.decode_error.take().map_or(Ok(()), Err)
+    }
+
+    // Reference implementation dispatching with a single match over the nibbles.
+    // Kept so the decode benchmark can compare it against the dispatch table.
+    #[doc(hidden)]
+    pub fn decode_match(&mut self, memory: &mut Memory, display: &mut Display, keys: &[bool; 16]) -> Result<(), CpuError> {
+        let opcode: u16 = self.fetch(memory);
+        let nibbles: [u8; 4] = [
+            (opcode >> 12) as u8,
+            (opcode >> 8 & 0x0F) as u8,
+            (opcode >> 4 & 0x0F) as u8,
+            (opcode & 0x0F) as u8,
+        ];
+        let handler: OpcodeHandler = match nibbles {
+            [0x0, 0x0, 0xE, 0x0] => CPU::op_00e0,
+            [0x0, 0x0, 0xE, 0xE] => CPU::op_00ee,
+            [0x0, 0x0, 0xE, 0xD] if self.chip8e => CPU::op_00ed,
+            [0x0, 0x0, 0xF, 0x2] if self.chip8e => CPU::op_00f2,
+            [0x0, 0x1, 0x5, 0x1] if self.chip8e => CPU::op_0151,
+            [0x0, 0x1, 0x8, 0x8] if self.chip8e => CPU::op_0188,
+            [0x0, 0x0, 0xC, _] if self.schip => CPU::op_00cn,
+            [0x0, 0x0, 0xF, 0xB] if self.schip => CPU::op_00fb,
+            [0x0, 0x0, 0xF, 0xC] if self.schip => CPU::op_00fc,
+            [0x0, 0x0, 0xF, 0xD] if self.schip => CPU::op_00fd,
+            [0x0, 0x0, 0xF, 0xE] if self.schip => CPU::op_00fe,
+            [0x0, 0x0, 0xF, 0xF] if self.schip => CPU::op_00ff,
+            [0x1, _, _, _] => CPU::op_1nnn,
+            [0x2, _, _, _] => CPU::op_2nnn,
+            [0x3, _, _, _] => CPU::op_3xnn,
+            [0x4, _, _, _] => CPU::op_4xnn,
+            [0x5, _, _, 0x0] => CPU::op_5xy0,
+            [0x5, _, _, 0x1] if self.chip8e => CPU::op_5xy1,
+            [0x5, _, _, 0x2] if self.chip8e => CPU::op_5xy2,
+            [0x5, _, _, 0x3] if self.chip8e => CPU::op_5xy3,
+            [0x6, _, _, _] => CPU::op_6xnn,
+            [0x7, _, _, _] => CPU::op_7xnn,
+            [0x8, _, _, 0x0] => CPU::op_8xy0,
+            [0x8, _, _, 0x1] => CPU::op_8xy1,
+            [0x8, _, _, 0x2] => CPU::op_8xy2,
+            [0x8, _, _, 0x3] => CPU::op_8xy3,
+            [0x8, _, _, 0x4] => CPU::op_8xy4,
+            [0x8, _, _, 0x5] => CPU::op_8xy5,
+            [0x8, _, _, 0x6] => CPU::op_8xy6,
+            [0x8, _, _, 0x7] => CPU::op_8xy7,
+            [0x8, _, _, 0xE] => CPU::op_8xye,
+            [0x9, _, _, _] => CPU::op_9xy0,
+            [0xA, _, _, _] => CPU::op_annn,
+            [0xB, 0xB, _, _] if self.chip8e => CPU::op_bbnn,
+            [0xB, 0xF, _, _] if self.chip8e => CPU::op_bfnn,
+            [0xB, _, _, _] => CPU::op_bnnn,
+            [0xC, _, _, _] => CPU::op_cxnn,
+            [0xD, _, _, _] => CPU::op_dxyn,
+            [0xE, _, 0x9, 0xE] => CPU::op_ex9e,
+            [0xE, _, 0xA, 0x1] => CPU::op_exa1,
+            [0xF, 0x0, 0x0, 0x0] if self.xochip => CPU::op_f000,
+            [0xF, _, 0x0, 0x1] if self.xochip => CPU::op_fn01,
+            [0xF, _, 0x0, 0x7] => CPU::op_fx07,
+            [0xF, _, 0x0, 0xA] => CPU::op_fx0a,
+            [0xF, _, 0x1, 0x5] => CPU::op_fx15,
+            [0xF, _, 0x1, 0x8] => CPU::op_fx18,
+            [0xF, _, 0x1, 0xB] if self.chip8e => CPU::op_fx1b,
+            [0xF, _, 0x1, 0xE] => CPU::op_fx1e,
+            [0xF, _, 0x2, 0x9] => CPU::op_fx29,
+            [0xF, _, 0x3, 0x0] if self.schip => CPU::op_fx30,
+            [0xF, _, 0x7, 0x5] if self.schip => CPU::op_fx75,
+            [0xF, _, 0x8, 0x5] if self.schip => CPU::op_fx85,
+            [0xF, _, 0x3, 0x3] => CPU::op_fx33,
+            [0xF, _, 0x4, 0xF] if self.chip8e => CPU::op_fx4f,
+            [0xF, _, 0x5, 0x5] => CPU::op_fx55,
+            [0xF, _, 0x6, 0x5] => CPU::op_fx65,
+            _ => CPU::op_unknown,
+        };
+        if handler(self, memory, display, keys, opcode) == PcAction::Continue {
+            self.pc = self.pc.wrapping_add(2);
+        }
+        self.decode_error.take().map_or(Ok(()), Err)
+    }
+
+    // Secondary dispatch for the 0x0 family
+    fn dispatch_0xxx(&mut self, memory: &mut Memory, display: &mut Display, keys: &[bool; 16], opcode: u16) -> PcAction {
+        let handler: OpcodeHandler = match opcode {
+            0x00E0 => CPU::op_00e0,
+            0x00EE => CPU::op_00ee,
+            0x00ED if self.chip8e => CPU::op_00ed,
+            0x00F2 if self.chip8e => CPU::op_00f2,
+            0x0151 if self.chip8e => CPU::op_0151,
+            0x0188 if self.chip8e => CPU::op_0188,
+            0x00C0..=0x00CF if self.schip => CPU::op_00cn,
+            0x00FB if self.schip => CPU::op_00fb,
+            0x00FC if self.schip => CPU::op_00fc,
+            0x00FD if self.schip => CPU::op_00fd,
+            0x00FE if self.schip => CPU::op_00fe,
+            0x00FF if self.schip => CPU::op_00ff,
+            _ => CPU::op_unknown,
+        };
+        handler(self, memory, display, keys, opcode)
+    }
+
+    // Secondary dispatch for the 0x5 family, selected by the last nibble
+    fn dispatch_5xyn(&mut self, memory: &mut Memory, display: &mut Display, keys: &[bool; 16], opcode: u16) -> PcAction {
+        let handler: OpcodeHandler = match n(opcode) {
+            0x0 => CPU::op_5xy0,
+            0x1 if self.chip8e => CPU::op_5xy1,
+            0x2 if self.chip8e => CPU::op_5xy2,
+            0x3 if self.chip8e => CPU::op_5xy3,
+            _ => CPU::op_unknown,
+        };
+        handler(self, memory, display, keys, opcode)
+    }
+
+    // Secondary dispatch for the 0xB family. CHIP-8E takes over BBNN and BFNN for relative branches
+    fn dispatch_bxxx(&mut self, memory: &mut Memory, display: &mut Display, keys: &[bool; 16], opcode: u16) -> PcAction {
+        let handler: OpcodeHandler = match x(opcode) {
+            0xB if self.chip8e => CPU::op_bbnn,
+            0xF if self.chip8e => CPU::op_bfnn,
+            _ => CPU::op_bnnn,
+        };
+        handler(self, memory, display, keys, opcode)
+    }
+
+    // Secondary dispatch for the 0x8 family, selected by the last nibble
+    fn dispatch_8xyn(&mut self, memory: &mut Memory, display: &mut Display, keys: &[bool; 16], opcode: u16) -> PcAction {
+        let handler: OpcodeHandler = match n(opcode) {
+            0x0 => CPU::op_8xy0,
+            0x1 => CPU::op_8xy1,
+            0x2 => CPU::op_8xy2,
+            0x3 => CPU::op_8xy3,
+            0x4 => CPU::op_8xy4,
+            0x5 => CPU::op_8xy5,
+            0x6 => CPU::op_8xy6,
+            0x7 => CPU::op_8xy7,
+            0xE => CPU::op_8xye,
+            _ => CPU::op_unknown,
+        };
+        handler(self, memory, display, keys, opcode)
+    }
+
+    // Secondary dispatch for the 0xE family, selected by the low byte
+    fn dispatch_exxx(&mut self, memory: &mut Memory, display: &mut Display, keys: &[bool; 16], opcode: u16) -> PcAction {
+        let handler: OpcodeHandler = match nn(opcode) {
+            0x9E => CPU::op_ex9e,
+            0xA1 => CPU::op_exa1,
+            _ => CPU::op_unknown,
+        };
+        handler(self, memory, display, keys, opcode)
+    }
+
+    // Secondary dispatch for the 0xF family, selected by the low byte
+    fn dispatch_fxxx(&mut self, memory: &mut Memory, display: &mut Display, keys: &[bool; 16], opcode: u16) -> PcAction {
+        let handler: OpcodeHandler = match nn(opcode) {
+            0x00 if opcode == 0xF000 && self.xochip => CPU::op_f000,
+            0x01 if self.xochip => CPU::op_fn01,
+            0x07 => CPU::op_fx07,
+            0x0A => CPU::op_fx0a,
+            0x15 => CPU::op_fx15,
+            0x18 => CPU::op_fx18,
+            0x1B if self.chip8e => CPU::op_fx1b,
+            0x1E => CPU::op_fx1e,
+            0x29 => CPU::op_fx29,
+            0x30 if self.schip => CPU::op_fx30,
+            0x75 if self.schip => CPU::op_fx75,
+            0x85 if self.schip => CPU::op_fx85,
+            0x33 => CPU::op_fx33,
+            0x4F if self.chip8e => CPU::op_fx4f,
+            0x55 => CPU::op_fx55,
+            0x65 => CPU::op_fx65,
+            _ => CPU::op_unknown,
+        };
+        handler(self, memory, display, keys, opcode)
+    }
+
+    // Count the delay and sound timers down this many times a second instead of 60.
+    // FX07 and the beep follow the timers, so both run at the new rate.
+    pub fn set_timer_hz(&mut self, hz: u32) {
+        self.timer_hz = hz.max(1);
+    }
+
+    pub fn timer_hz(&self) -> u32 {
+        self.timer_hz
+    }
+
+    // Count the delay and sound timers down once. The emulator calls this timer_hz times per second
+    // of emulated time, see Emulator::advance_clock.
+    pub fn tick_timers(&mut self) {
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
+    }
+
+    // Keep a problem for the GUI log
+    fn warn(&mut self, message: String) {
+        // A ROM stuck on a bad opcode shouldn't grow this forever
+        if self.warnings.len() < MAX_WARNINGS {
+            self.warnings.push(message);
+        }
+    }
+
+    // Report a problem from decode, the emulator decides what to do about it
+    fn fail(&mut self, error: CpuError) {
+        self.decode_error = Some(error);
+    }
+
+    // Hand over the extension opcodes hit since the last call
+    pub fn take_platform_mismatches(&mut self) -> Vec<PlatformMismatch> {
+        std::mem::take(&mut self.platform_mismatches)
+    }
+
+    // Whether FX75 has written the flags since the last call
+    pub fn take_flags_changed(&mut self) -> bool {
+        std::mem::take(&mut self.flags_changed)
+    }
+
+    // Hand over the warnings since the last call
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    fn op_unknown(&mut self, memory: &mut Memory, display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        // Give the custom handler a go first. It's taken out for the call so it can borrow the CPU.
+        if let Some(mut handler) = self.custom_handler.take() {
+            let pc: u16 = self.pc;
+            let handled: bool = handler(self, opcode, memory, display);
+            // Keep a handler installed during the call over this one
+            self.custom_handler.get_or_insert(handler);
+            if handled {
+                return if self.pc == pc { PcAction::Continue } else { PcAction::Jumped };
             }
-            // 8XY7: Set VX to VY - VX
-            [0x8, _, _, 0x7] => {
-                let vx = nibbles[1] as usize;
-                let vy = nibbles[2] as usize;
-                if vx < 16 && vy < 16 {
-                    let (result, borrow) = self.v[vy].overflowing_sub(self.v[vx]);
-                    self.v[vx] = result;
-
-                    self.v[0xF] = (!borrow) as u8; // Set VF to 1 if no borrow, 0 if borrow
-                    
-                } else {
-                    println!("Invalid register index: {} or {}", vx, vy);
-                }
+        }
+        // An opcode from a platform that isn't enabled is worth pointing out once
+        if let Some((family, platform)) = disasm::extension_family(opcode)
+            && !self.mismatched_families.contains(&family)
+        {
+            self.mismatched_families.push(family);
+            self.platform_mismatches.push(PlatformMismatch { pc: self.pc, opcode, family, platform });
+        }
+        // Handle other opcodes
+        self.fail(CpuError::UnknownOpcode(opcode));
+        PcAction::Continue
+    }
+
+    // 00E0: Clear the display
+    fn op_00e0(&mut self, _memory: &mut Memory, display: &mut Display, _keys: &[bool; 16], _opcode: u16) -> PcAction {
+        display.clear_selected();
+        PcAction::Continue
+    }
+
+    // 00EE: Return from a subroutine
+    fn op_00ee(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], _opcode: u16) -> PcAction {
+        if let Some(address) = self.stack_top() {
+            // The stack holds the address of the 2NNN, so continue after it
+            self.pc = address.wrapping_add(2);
+            // Pop stack pointer
+            self.sp -= 1;
+            PcAction::Jumped
+        } else {
+            self.fail(CpuError::StackUnderflow);
+            PcAction::Continue
+        }
+    }
+
+    // 1NNN: Jump to location NNN
+    fn op_1nnn(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        self.pc = nnn(opcode);
+        PcAction::Jumped
+    }
+
+    // 2NNN: Call Subroutine at NNN
+    fn op_2nnn(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let address: u16 = nnn(opcode);
+        if let Some(slot) = self.stack.get_mut(self.sp as usize) {
+            *slot = self.pc;
+            self.sp += 1;
+            self.pc = address;
+            PcAction::Jumped
+        } else {
+            self.fail(CpuError::StackOverflow);
+            PcAction::Continue
+        }
+    }
+
+    // 3XNN: Skip Next Instruction if VX == NN
+    fn op_3xnn(&mut self, memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        if vx < 16 && self.v[vx] == nn(opcode) {
+            self.skip_next(memory);
+        }
+        PcAction::Continue
+    }
+
+    // 4XNN: Skip Next Instruction if VX != NN
+    fn op_4xnn(&mut self, memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        if vx < 16 && self.v[vx] != nn(opcode) {
+            self.skip_next(memory);
+        }
+        PcAction::Continue
+    }
+
+    // 5XY0: Skip Next Instruction if VX == VY
+    fn op_5xy0(&mut self, memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        let vy: usize = y(opcode);
+        if vx < 16 && vy < 16 && self.v[vx] == self.v[vy] {
+            self.skip_next(memory);
+        }
+        PcAction::Continue
+    }
+
+    // 6XNN: Set VX to NN
+    fn op_6xnn(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        if vx < 16 {
+            self.v[vx] = nn(opcode);
+        } else {
+            self.fail(CpuError::InvalidRegister(vx));
+        }
+        PcAction::Continue
+    }
+
+    // 7XNN: Add NN to VX
+    fn op_7xnn(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        if vx < 16 {
+            self.v[vx] = self.v[vx].wrapping_add(nn(opcode));
+        } else {
+            self.fail(CpuError::InvalidRegister(vx));
+        }
+        PcAction::Continue
+    }
+
+    // Arithmetic
+    // 8XY0: Set VX to VY
+    fn op_8xy0(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        let vy: usize = y(opcode);
+        if vx < 16 && vy < 16 {
+            self.v[vx] = self.v[vy];
+        } else {
+            self.fail(CpuError::InvalidRegister(if vx < 16 { vy } else { vx }));
+        }
+        PcAction::Continue
+    }
+
+    // 8XY1: Set VX to VX OR VY
+    fn op_8xy1(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        let vy: usize = y(opcode);
+        if self.quirks.vf_reset {
+            self.v[0xF] = 0;
+        }
+        if vx < 16 && vy < 16 {
+            self.v[vx] |= self.v[vy];
+        } else {
+            self.fail(CpuError::InvalidRegister(if vx < 16 { vy } else { vx }));
+        }
+        PcAction::Continue
+    }
+
+    // 8XY2: Set VX to VX AND VY
+    fn op_8xy2(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        let vy: usize = y(opcode);
+        if self.quirks.vf_reset {
+            self.v[0xF] = 0;
+        }
+        if vx < 16 && vy < 16 {
+            self.v[vx] &= self.v[vy];
+        } else {
+            self.fail(CpuError::InvalidRegister(if vx < 16 { vy } else { vx }));
+        }
+        PcAction::Continue
+    }
+
+    // 8XY3: Set VX to VX XOR VY
+    fn op_8xy3(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        let vy: usize = y(opcode);
+        if self.quirks.vf_reset {
+            self.v[0xF] = 0;
+        }
+        if vx < 16 && vy < 16 {
+            self.v[vx] ^= self.v[vy];
+        } else {
+            self.fail(CpuError::InvalidRegister(if vx < 16 { vy } else { vx }));
+        }
+        PcAction::Continue
+    }
+
+    // 8XY4: Set VX to VX + VY, VF is set to carry
+    fn op_8xy4(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        let vy: usize = y(opcode);
+        if vx < 16 && vy < 16 {
+            let (result, carry) = self.v[vx].overflowing_add(self.v[vy]);
+            self.v[vx] = result;
+            self.v[0xF] = carry as u8;
+        } else {
+            self.fail(CpuError::InvalidRegister(if vx < 16 { vy } else { vx }));
+        }
+        PcAction::Continue
+    }
+
+    // 8XY5: Set VX to VX - VY, VF is set to NOT borrow
+    fn op_8xy5(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        let vy: usize = y(opcode);
+        if vx < 16 && vy < 16 {
+            let (result, borrow) = self.v[vx].overflowing_sub(self.v[vy]);
+            self.v[vx] = result;
+
+            self.v[0xF] = (!borrow) as u8; // Set VF to 1 if no borrow, 0 if borrow
+
+        } else {
+            self.fail(CpuError::InvalidRegister(if vx < 16 { vy } else { vx }));
+        }
+        PcAction::Continue
+    }
+
+    // 8XY6: Shift VX right by 1, VF is set to the least significant bit of VX
+    fn op_8xy6(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        let vy: usize = y(opcode);
+        if vx < 16 {
+            if self.quirks.shift_uses_vy {
+                self.v[vx] = self.v[vy];
             }
-            [0x8, _, _, 0xE] => {
-                // Shift VX left by 1, VF is set to the most significant bit of VX
-                let vx: u8 = nibbles[1] as u8;
-                let vy: u8 = nibbles[2] as u8;
-                if vx < 16 {
-                    if SHIFT_SET_MODE {
-                        self.v[vx as usize] = self.v[vy as usize];
-                    }
-                    let bit: u8 = (self.v[vx as usize] & 0x80) >> 7; // Get the most significant bit
-                    self.v[vx as usize] <<= 1; // Shift left
-                    self.v[0xF] = bit; // Set VF to MSB of VX
-                } else {
-                    println!("Invalid register index: {}", vx);
-                }
+            let bit: u8 = self.v[vx] & 0x01; // Get the least significant bit
+            self.v[vx] >>= 1; // Shift right
+            self.v[0xF] = bit; // Set VF to LSB of VX
+        } else {
+            self.fail(CpuError::InvalidRegister(vx));
+        }
+        PcAction::Continue
+    }
+
+    // 8XY7: Set VX to VY - VX
+    fn op_8xy7(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        let vy: usize = y(opcode);
+        if vx < 16 && vy < 16 {
+            let (result, borrow) = self.v[vy].overflowing_sub(self.v[vx]);
+            self.v[vx] = result;
+
+            self.v[0xF] = (!borrow) as u8; // Set VF to 1 if no borrow, 0 if borrow
+
+        } else {
+            self.fail(CpuError::InvalidRegister(if vx < 16 { vy } else { vx }));
+        }
+        PcAction::Continue
+    }
+
+    // 8XYE: Shift VX left by 1, VF is set to the most significant bit of VX
+    fn op_8xye(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        let vy: usize = y(opcode);
+        if vx < 16 {
+            if self.quirks.shift_uses_vy {
+                self.v[vx] = self.v[vy];
             }
-            // 9XY0: Skip Next Instruction if VX != VY
-            [0x9, _, _, 0x0] => {
-                let vx: usize = nibbles[1] as usize;
-                let vy: usize = nibbles[2] as usize;
-                let val_vx: u8 = self.v[vx];
-                let val_vy: u8 = self.v[vy];
-                if vx < 16 && vy < 16 && val_vx != val_vy {
-                    self.pc += 2; // Skip next instruction
-                }
+            let bit: u8 = (self.v[vx] & 0x80) >> 7; // Get the most significant bit
+            self.v[vx] <<= 1; // Shift left
+            self.v[0xF] = bit; // Set VF to MSB of VX
+        } else {
+            self.fail(CpuError::InvalidRegister(vx));
+        }
+        PcAction::Continue
+    }
+
+    // 9XY0: Skip Next Instruction if VX != VY
+    fn op_9xy0(&mut self, memory: &mut Memory, display: &mut Display, keys: &[bool; 16], opcode: u16) -> PcAction {
+        if n(opcode) != 0x0 {
+            return self.op_unknown(memory, display, keys, opcode);
+        }
+        let vx: usize = x(opcode);
+        let vy: usize = y(opcode);
+        if vx < 16 && vy < 16 && self.v[vx] != self.v[vy] {
+            self.skip_next(memory);
+        }
+        PcAction::Continue
+    }
+
+    // ANNN: Set I to address NNN
+    fn op_annn(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        // Set index regier I to NNN
+        self.i = nnn(opcode);
+        PcAction::Continue
+    }
+
+    // BNNN: Jump to location NNN + V0.
+    fn op_bnnn(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let nnn: u16 = nnn(opcode);
+
+        if self.quirks.jump_uses_vx {
+            let v_x: u16 = self.v[x(opcode)] as u16;
+            self.pc = nnn + v_x;
+        }else {
+            // Original CHIP-8 behavior
+            let v0: u8 = self.v[0];
+            self.pc = nnn + (v0 as u16);
+        }
+        PcAction::Jumped
+    }
+
+    // CXNN: Random
+    fn op_cxnn(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        if vx < 16 {
+            // Generate a random number and AND it with NN
+            let random_byte: u8 = self.rng.random();
+            self.v[vx] = random_byte & nn(opcode);
+        } else {
+            self.fail(CpuError::InvalidRegister(vx));
+        }
+        PcAction::Continue
+    }
+
+    // DXYN: Draw Sprite
+    // Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision.
+    // DXY0 in high resolution draws a 16x16 sprite from the 32 bytes at I instead.
+    fn op_dxyn(&mut self, memory: &mut Memory, display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let n: usize = n(opcode);
+
+        // The starting position always wraps onto the display. Whether the rest of the sprite
+        // wraps or clips at the edges is up to draw_sprite.
+        let x: usize = self.v[x(opcode)] as usize % display.width as usize;
+        let y: usize = self.v[y(opcode)] as usize % display.height as usize;
+
+        // Set VF to 0
+        self.v[0xF] = 0;
+
+        // XO-CHIP draws into each selected plane, reading n more rows for each
+        let large: bool = n == 0 && display.is_hires();
+        let plane_length: usize = if large { 32 } else { n };
+        let length: usize = plane_length * display.selected_planes.count_ones() as usize;
+
+        // Sprite data past the end of RAM wraps around to the start or is cut off
+        let mut rows: usize = length;
+        let overflow: usize = (self.i as usize + length).saturating_sub(memory.data.len()).min(length);
+        if overflow > 0 && self.sprite_read_overflow != SpriteReadOverflow::Wrap {
+            rows = length - overflow;
+            if self.sprite_read_overflow == SpriteReadOverflow::WarnAndClamp && !self.sprite_overflow_warned {
+                self.sprite_overflow_warned = true;
+                self.warn(format!("Sprite at I={:04X} runs {} rows past the end of RAM, they were not drawn", self.i, overflow));
             }
+        }
+        let sprite: Vec<u8> = (0..rows).map(|row| memory.read(self.i as usize + row)).collect();
+        let pixel_erased: bool =
+            if large { display.draw_large_sprite(x, y, &sprite) } else { display.draw_sprite(x, y, n, &sprite) };
+        self.v[0xF] = u8::from(pixel_erased);
+        PcAction::Continue
+    }
+
+    // EX9E Skip next instruction if key with the value of Vx is pressed.
+    fn op_ex9e(&mut self, memory: &mut Memory, _display: &mut Display, keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        let key: u8 = self.v[vx];
+        if vx < 16 && key_pressed(keys, key) {
+            self.skip_next(memory);
+        }
+        PcAction::Continue
+    }
 
-            // ANNN: Set I to address NNN
-            [0xA, _, _, _] => {
-                // Set index regier I to NNN
-                self.i = ((nibbles[1] as u16) << 8) | ((nibbles[2] as u16) << 4) | nibbles[3] as u16;
+    // EXA1 Skip next instruction if key with the value of Vx is not pressed.
+    fn op_exa1(&mut self, memory: &mut Memory, _display: &mut Display, keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        let key: u8 = self.v[vx];
+        if vx < 16 && !key_pressed(keys, key) {
+            self.skip_next(memory);
+        }
+        PcAction::Continue
+    }
+
+    // F000 NNNN: Set I to the 16-bit address NNNN in the next two bytes (XO-CHIP).
+    // This is a 4-byte instruction, so PC moves past NNNN as well.
+    fn op_f000(&mut self, memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], _opcode: u16) -> PcAction {
+        let address: usize = self.pc as usize + 2;
+        self.i = (memory.read(address) as u16) << 8 | memory.read(address + 1) as u16;
+        self.pc = self.pc.wrapping_add(2);
+        PcAction::Continue
+    }
+
+    // FX07: Set Vx = delay timer value.
+    fn op_fx07(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        if vx < 16 {
+            self.v[vx] = self.delay_timer;
+        } else {
+            self.fail(CpuError::InvalidRegister(vx));
+        }
+        PcAction::Continue
+    }
+
+    // FX0A: Wait for a key press and release, store the value of the key in Vx
+    fn op_fx0a(&mut self, _memory: &mut Memory, _display: &mut Display, keys: &[bool; 16], opcode: u16) -> PcAction {
+        if self.fx0a_key_down.is_none() {
+            let key: Option<u8> = self.fx0a_pressed_key(keys);
+            self.fx0a_key_down = self.debounce_fx0a_key(key);
+        }
+        // CHIP-8 Quirk: the COSMAC VIP only goes on once the key is released
+        if let Some(key) = self.fx0a_key_down
+            && !keys[key as usize]
+        {
+            self.fx0a_keys_up = None;
+            self.fx0a_key_down = None;
+            // Set VX to the key pressed
+            let vx: usize = x(opcode);
+            if vx < 16 {
+                self.v[vx] = key;
+            } else {
+                self.fail(CpuError::InvalidRegister(vx));
             }
+            PcAction::Continue
+        }else {
+            // Repeat this instruction until a key is pressed and released
+            PcAction::Jumped
+        }
+    }
 
-            // BNNN: Jump to location NNN + V0.
-            [0xB, _, _, _] => {
-                let nnn: u16 = ((nibbles[1]  as u16) << 8) | ((nibbles[2] as u16) << 4) | nibbles[3] as u16;
-                
-                if JUMP_VX_MODE {
-                    let x: usize = nibbles[1] as usize;
-                    let v_x: u16 = self.v[x] as u16;
-                    self.pc = nnn + v_x - 2; // This adjusts for increment later    
-                }else {
-                    // Original CHIP-8 behavior
-                    let v0: u8 = self.v[0];
-                    self.pc = nnn + (v0 as u16) - 2; // This adjusts for increment later
+    // The keypad only reports held keys, so FX0A looks for a press itself: a key that goes down
+    // after being up while FX0A waits. A key still held from before FX0A started isn't read again.
+    fn fx0a_pressed_key(&mut self, keys: &[bool; 16]) -> Option<u8> {
+        let keys_up: &mut [bool; 16] = self.fx0a_keys_up.get_or_insert(keys.map(|down| !down));
+        let key: Option<usize> = (0..16).find(|&key| keys[key] && keys_up[key]);
+        for (up, &down) in keys_up.iter_mut().zip(keys) {
+            *up |= !down;
+        }
+        key.map(|key| key as u8)
+    }
+
+    // With fx0a_min_hold_frames set, only let a key through once it has been held that long
+    fn debounce_fx0a_key(&mut self, key: Option<u8>) -> Option<u8> {
+        if self.fx0a_min_hold_frames == 0 {
+            return key;
+        }
+        let Some(key) = key else {
+            self.fx0a_held = None;
+            return None;
+        };
+        match self.fx0a_held {
+            Some((held_key, held_frames)) if held_key == key => {
+                if held_frames < self.fx0a_min_hold_frames {
+                    return None;
                 }
+                self.fx0a_held = None;
+                Some(key)
             }
-            // CXNN: Random
-            [0xC, _, _, _] => {
-                let vx = nibbles[1] as usize;
-                let nn = ((nibbles[2] as u16) << 4) | nibbles[3] as u16;
-                if vx < 16 {
-                    // Generate a random number and AND it with NN
-                    let random_byte = rand::random::<u8>();
-                    self.v[vx] = random_byte & nn as u8;
-                } else {
-                    println!("Invalid register index: {}", vx);
-                }
+            // A new key restarts the count
+            _ => {
+                self.fx0a_held = Some((key, 0));
+                None
             }
-            // DXYN: Draw Sprite
-            // Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision.
-            [0xD, _, _, _] => {
-                // Draw sprite at Vx, Vy with height N
-                let vx: usize = nibbles[1] as usize;
-                let vy: usize = nibbles[2] as usize;
-                let n: usize = nibbles[3] as usize;
-
-                // Get the x and y coordinates on the screen
-                let mut x: usize = self.v[vx] as usize;
-                let mut y: usize = self.v[vy] as usize;
-
-                // Bounds check. If the sprite is drawn outside the display, we ignore it
-                if x >= display.width as usize || y >= display.height as usize {
-                    x %= display.width as usize;
-                    y %= display.height as usize;
-                }
+        }
+    }
 
-                // Set VF to 0
-                self.v[0xF] = 0;
-
-                self.v[0xF] = if display.draw_sprite(x, y, n, &memory.data[(self.i as usize)..(self.i as usize + n)]) {
-                    1
-                } else {
-                    0
-                };
-                // for row in 0..n {
-                //     if row > display.height as usize {
-                //         break;
-                //     }
-                //     // Get the nth byte of sprite data counting from the memory address in the I register
-                //     let sprite_byte: u8 = memory.data[(self.i as usize + row) % memory.data.len()];
-
-                //     for col in 0..8 {
-                //         // Check if the pixel is set at that col in the sprite byte
-                //         let pixel: bool = ((sprite_byte >> (7 - col)) & 0x01) == 1;
-                //         if col > display.width as usize {
-                //             break;
-                //         }
-                //         // Sprites are XORed onto the existing screen.
-                //         let display_x: usize = (x + col) % display.width as usize;
-                //         let display_y: usize = (y + row) % display.height as usize;
-                //         // If this causes any pixels to be erased, VF is set to 1, otherwise it is set to 0.
-                //         if display.pixels[display_y][display_x] && pixel {
-                //             self.v[0xF] = 1; // If our sprite pixel is on, and the display pixel is on the XOR will cause an overwrite
-                //         }
-                //         // XOR the pixel
-                //         display.pixels[display_y][display_x] ^= pixel;
-                //     }
-                    
-                // }
-            }
-            // EX9E Skip next instruction if key with the value of Vx is pressed.
-            [0xE, _, 0x9, 0xE] => {
-                let vx: usize = nibbles[1] as usize;
-                let key: u8 = self.v[vx];
-                if vx < 16 && keys[key as usize] {
-                    self.pc += 2; // Skip next instruction
-                }
+    // Called by the emulator at every 60 Hz frame of emulated time, counts how long FX0A's key is held
+    pub fn count_frame(&mut self) {
+        if let Some((_, held_frames)) = self.fx0a_held.as_mut() {
+            *held_frames = held_frames.saturating_add(1);
+        }
+    }
+
+    // FX15: Sets the delay timer to VX
+    fn op_fx15(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        if vx < 16 {
+            self.delay_timer = self.v[vx];
+        } else {
+            self.fail(CpuError::InvalidRegister(vx));
+        }
+        PcAction::Continue
+    }
+
+    // FX18: Sets the sound timer to VX
+    fn op_fx18(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        if vx < 16 {
+            self.sound_timer = self.v[vx];
+        } else {
+            self.fail(CpuError::InvalidRegister(vx));
+        }
+        PcAction::Continue
+    }
+
+    // FX1E: Adds VX to I
+    fn op_fx1e(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        if vx < 16 {
+            self.i = self.i.wrapping_add(self.v[vx] as u16);
+            // The Amiga interpreter flags I leaving the 4 KB address space, a few ROMs check it
+            if self.quirks.i_overflow_sets_vf && self.i > 0x0FFF {
+                self.v[0xF] = 1;
             }
-            // EXA1 Skip next instruction if key with the value of Vx is not pressed.
-            [0xE, _, 0xA, 0x1] => {
-                let vx = nibbles[1] as usize;
-                let key = self.v[vx];
-                if vx < 16 && !keys[key as usize] {
-                    self.pc += 2; // Skip next instruction
-                }
+        } else {
+            self.fail(CpuError::InvalidRegister(vx));
+        }
+        PcAction::Continue
+    }
+
+    // FX29: Load font Character
+    fn op_fx29(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        if vx < 16 {
+            // Set I to the address of the font character
+            self.i = FONT_ADDRESS + (self.v[vx] as u16) * (self.font_char_size as u16);
+        } else {
+            self.fail(CpuError::InvalidRegister(vx));
+        }
+        PcAction::Continue
+    }
+
+    // FX30: Set I to the 8x10 big font character for VX (SUPER-CHIP)
+    fn op_fx30(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        if vx < 16 {
+            self.i = FONT_BIG_ADDRESS + (self.v[vx] as u16) * 10;
+        } else {
+            self.fail(CpuError::InvalidRegister(vx));
+        }
+        PcAction::Continue
+    }
+
+    // FX75: Store V0 to VX in the flag registers, X above 7 stops at V7 (SUPER-CHIP)
+    fn op_fx75(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let count: usize = (x(opcode) + 1).min(FLAG_COUNT);
+        self.flags[..count].copy_from_slice(&self.v[..count]);
+        self.flags_changed = true;
+        PcAction::Continue
+    }
+
+    // FX85: Load V0 to VX from the flag registers, X above 7 stops at V7 (SUPER-CHIP)
+    fn op_fx85(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let count: usize = (x(opcode) + 1).min(FLAG_COUNT);
+        self.v[..count].copy_from_slice(&self.flags[..count]);
+        PcAction::Continue
+    }
+
+    // Memory wraps addresses past the end of RAM back to the start, so a bad I can't crash the emulator.
+    // The ROM is still likely broken, so say so the first time.
+    fn check_memory_wrap(&mut self, memory: &Memory, opcode: &str, len: usize) {
+        if !self.memory_wrap_warned && self.i as usize + len > memory.data.len() {
+            self.memory_wrap_warned = true;
+            self.warn(format!("{} at {:04X} with I={:04X} runs past the end of RAM and wraps to the start", opcode, self.pc, self.i));
+        }
+    }
+
+    // FX33: Binary-Coded decimal conversion
+    fn op_fx33(&mut self, memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        let val: u8 = self.v[vx];
+        if vx < 16 {
+            self.check_memory_wrap(memory, "FX33", 3);
+            // Store the hundreds digit
+            memory.write(self.i as usize, val / 100);
+            // Store the tens digit
+            memory.write(self.i as usize + 1, (val / 10) % 10);
+            // Store the units digit
+            memory.write(self.i as usize + 2, val % 10);
+        } else {
+            self.fail(CpuError::InvalidRegister(vx));
+        }
+        PcAction::Continue
+    }
+
+    // FX55: Store registers V0 to VX in memory starting at address I
+    fn op_fx55(&mut self, memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        if vx < 16 {
+            self.check_memory_wrap(memory, "FX55", vx + 1);
+            let start: u16 = self.i;
+            for i in 0..=vx {
+                memory.write(self.i as usize, self.v[i]);
+                self.i = self.i.wrapping_add(1);
             }
-            // FX07: Set Vx = delay timer value.
-            [0xF, _, 0x0, 0x7] => {
-                let vx = nibbles[1] as usize;
-                if vx < 16 {
-                    self.v[vx] = self.delay_timer.lock().unwrap().clone();
-                } else {
-                    println!("Invalid register index: {}", vx);
-                }
+            // CHIP-8 Quirk: I ends up X + 1 past the start, other platforms move it less
+            match self.quirks.load_store_increments_i {
+                LoadStoreIncrement::Unchanged => self.i = start,
+                LoadStoreIncrement::X => self.i = start.wrapping_add(vx as u16),
+                LoadStoreIncrement::XPlusOne => {}
             }
-            // FX0A: Wait for a key press, store the value of the key in Vx
-            [0xF, _, 0x0, 0xA] => {
-                let mut key: Option<u8> = None;
-                for (i, &pressed) in keys.iter().enumerate() {
-                    if pressed {
-                        key = Some(i as u8);
-                        break;
-                    }
-                }
-                if key.is_some() {
-                    // Set VX to the key pressed
-                    let vx: usize = nibbles[1] as usize;
-                    if vx < 16 {
-                        self.v[vx] = key.unwrap();
-                    } else {
-                        println!("Invalid register index: {}", vx);
-                    }
-                }else {
-                    // Repeat this instruction until a key is pressed
-                    self.pc -= 2;
-                }
+        } else {
+            self.fail(CpuError::InvalidRegister(vx));
+        }
+        PcAction::Continue
+    }
 
+    // FX65: Read registers V0 to VX from memory starting at address I
+    fn op_fx65(&mut self, memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        let vx: usize = x(opcode);
+        if vx < 16 {
+            self.check_memory_wrap(memory, "FX65", vx + 1);
+            let start: u16 = self.i;
+            for i in 0..=vx {
+                self.v[i] = memory.read(self.i as usize);
+                self.i = self.i.wrapping_add(1);
             }
-            // FX15: Sets the delay timer to VX
-            [0xF, _, 0x1, 0x5] => {
-                let vx: usize = nibbles[1] as usize;
-                if vx < 16 {
-                    let mut delay_timer_thread: std::sync::MutexGuard<'_, u8> = self.delay_timer.lock().unwrap();
-                    *delay_timer_thread = self.v[vx];
-                } else {
-                    println!("Invalid register index: {}", vx);
-                }
-            }
-            // FX18: Sets the sound timer to VX
-            [0xF, _, 0x1, 0x8] => {
-                let vx: usize = nibbles[1] as usize;
-                if vx < 16 {
-                    let mut sound_timer_thread: std::sync::MutexGuard<'_, u8> = self.sound_timer.lock().unwrap();
-                    *sound_timer_thread = self.v[vx];
-                } else {
-                    println!("Invalid register index: {}", vx);
-                }
+            // CHIP-8 Quirk: I ends up X + 1 past the start, other platforms move it less
+            match self.quirks.load_store_increments_i {
+                LoadStoreIncrement::Unchanged => self.i = start,
+                LoadStoreIncrement::X => self.i = start.wrapping_add(vx as u16),
+                LoadStoreIncrement::XPlusOne => {}
             }
-            // FX1E: Adds VX to I
-            [0xF, _, 0x1, 0xE] => {
-                let vx: usize = nibbles[1] as usize;
-                if vx < 16 {
-                    self.i += self.v[vx] as u16;
-                } else {
-                    println!("Invalid register index: {}", vx);
-                }
+        } else {
+            self.fail(CpuError::InvalidRegister(vx));
+        }
+        PcAction::Continue
+    }
+
+    // CHIP-8E extensions, only reachable when chip8e is enabled.
+    // Adds 00ED, 00F2, 0151, 0188, 5XY1, 5XY2, 5XY3, BBNN, BFNN, FX1B and FX4F.
+    // BBNN and BFNN replace BNNN for those two values of X. The port I/O opcodes (FX03, FXE3, FXE7)
+    // have no hardware to talk to and stay unknown.
+
+    // 00ED: Stop, by staying on this instruction forever
+    fn op_00ed(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], _opcode: u16) -> PcAction {
+        // Stop, by staying on this instruction forever
+        PcAction::Jumped
+    }
+
+    // 00F2: No operation
+    fn op_00f2(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], _opcode: u16) -> PcAction {
+        PcAction::Continue
+    }
+
+    // 0151: Wait until the delay timer reaches 0
+    fn op_0151(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], _opcode: u16) -> PcAction {
+        if self.delay_timer > 0 {
+            // Stay on this instruction
+            return PcAction::Jumped;
+        }
+        PcAction::Continue
+    }
+
+    // 0188: Skip next instruction
+    fn op_0188(&mut self, memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], _opcode: u16) -> PcAction {
+        self.skip_next(memory);
+        PcAction::Continue
+    }
+
+    // 5XY1: Skip next instruction if VX > VY
+    fn op_5xy1(&mut self, memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        if self.v[x(opcode)] > self.v[y(opcode)] {
+            self.skip_next(memory);
+        }
+        PcAction::Continue
+    }
+
+    // 5XY2: Store VX to VY in memory starting at address I, I is left after the last byte.
+    // With X above Y the registers go in reverse order, as XO-CHIP's range save does. XO-CHIP's
+    // 5XY2 leaves I alone, so with CHIP-8E and XO-CHIP both on this one wins and I still moves.
+    fn op_5xy2(&mut self, memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        for register in register_range(x(opcode), y(opcode)) {
+            memory.write(self.i as usize, self.v[register]);
+            self.i = self.i.wrapping_add(1);
+        }
+        PcAction::Continue
+    }
+
+    // 5XY3: Read VX to VY from memory starting at address I, I is left after the last byte.
+    // Like 5XY2, X above Y goes in reverse, and this shadows XO-CHIP's 5XY3 when both are on.
+    fn op_5xy3(&mut self, memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        for register in register_range(x(opcode), y(opcode)) {
+            self.v[register] = memory.read(self.i as usize);
+            self.i = self.i.wrapping_add(1);
+        }
+        PcAction::Continue
+    }
+
+    // BBNN: Branch back NN bytes from the next instruction
+    fn op_bbnn(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        self.pc = self.pc.wrapping_add(2).wrapping_sub(nn(opcode) as u16);
+        PcAction::Jumped
+    }
+
+    // BFNN: Branch forward NN bytes from the next instruction
+    fn op_bfnn(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        self.pc = self.pc.wrapping_add(2).wrapping_add(nn(opcode) as u16);
+        PcAction::Jumped
+    }
+
+    // FX1B: Skip VX bytes
+    fn op_fx1b(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        self.pc = self.pc.wrapping_add(2).wrapping_add(self.v[x(opcode)] as u16);
+        PcAction::Jumped
+    }
+
+    // FX4F: Set the delay timer to VX and wait until it reaches 0
+    fn op_fx4f(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        if !self.timer_wait_started {
+            self.delay_timer = self.v[x(opcode)];
+            self.timer_wait_started = true;
+        }
+        if self.delay_timer > 0 {
+            // Repeat this instruction until the timer runs out
+            return PcAction::Jumped;
+        }
+        self.timer_wait_started = false;
+        PcAction::Continue
+    }
+
+    // SUPER-CHIP extensions, only reachable when schip is enabled.
+    // Adds 00CN, 00FB, 00FC, 00FD, 00FE and 00FF.
+    // Scrolls move by pixels of the current resolution, as Octo and XO-CHIP do. The original
+    // SUPER-CHIP 1.1 moved by high resolution pixels, so half as far in low resolution.
+
+    // 00CN: Scroll the display down N pixels
+    fn op_00cn(&mut self, _memory: &mut Memory, display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        display.scroll(0, n(opcode) as isize);
+        PcAction::Continue
+    }
+
+    // 00FB: Scroll the display right 4 pixels
+    fn op_00fb(&mut self, _memory: &mut Memory, display: &mut Display, _keys: &[bool; 16], _opcode: u16) -> PcAction {
+        display.scroll(4, 0);
+        PcAction::Continue
+    }
+
+    // 00FC: Scroll the display left 4 pixels
+    fn op_00fc(&mut self, _memory: &mut Memory, display: &mut Display, _keys: &[bool; 16], _opcode: u16) -> PcAction {
+        display.scroll(-4, 0);
+        PcAction::Continue
+    }
+
+    // 00FD: Exit the program. The PC stays here and the emulator runs nothing more.
+    fn op_00fd(&mut self, _memory: &mut Memory, _display: &mut Display, _keys: &[bool; 16], _opcode: u16) -> PcAction {
+        self.halted = true;
+        PcAction::Jumped
+    }
+
+    // 00FE: Switch to the 64x32 low resolution mode and clear the display
+    fn op_00fe(&mut self, _memory: &mut Memory, display: &mut Display, _keys: &[bool; 16], _opcode: u16) -> PcAction {
+        display.set_hires(false);
+        display.clear();
+        PcAction::Continue
+    }
+
+    // 00FF: Switch to the 128x64 high resolution mode and clear the display
+    fn op_00ff(&mut self, _memory: &mut Memory, display: &mut Display, _keys: &[bool; 16], _opcode: u16) -> PcAction {
+        display.set_hires(true);
+        display.clear();
+        PcAction::Continue
+    }
+
+    // XO-CHIP extensions, only reachable when xochip is enabled.
+    // Adds FN01.
+
+    // FN01: Select the planes DXYN, 00E0 and the scrolls work on, bit 0 for plane 0 and bit 1 for plane 1
+    fn op_fn01(&mut self, _memory: &mut Memory, display: &mut Display, _keys: &[bool; 16], opcode: u16) -> PcAction {
+        display.selected_planes = x(opcode) as u8 & 0b11;
+        PcAction::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::{LORES_HEIGHT, LORES_WIDTH};
+    use crate::emulator::ROM_ADDRESS;
+
+    // A CPU with the program loaded at 0x200, and a display with nothing receiving its frames
+    fn setup(program: &[u8]) -> (CPU, Memory, Display) {
+        let mut memory: Memory = Memory::new();
+        let start: usize = ROM_ADDRESS as usize;
+        memory.data[start..start + program.len()].copy_from_slice(program);
+        let (pixel_buffer_sender, _) = tokio::sync::mpsc::channel(1);
+        let display: Display = Display::new(LORES_WIDTH, LORES_HEIGHT, pixel_buffer_sender);
+        (CPU::new(ROM_ADDRESS, Quirks::default()), memory, display)
+    }
+
+    // Run this many instructions with no keys held
+    fn run(cpu: &mut CPU, memory: &mut Memory, display: &mut Display, steps: usize) {
+        for _ in 0..steps {
+            let _ = cpu.decode(memory, display, &[false; 16]);
+        }
+    }
+
+    // The lit pixels of plane 0 as (x, y), row by row
+    fn lit(display: &Display) -> Vec<(usize, usize)> {
+        (0..display.height as usize)
+            .flat_map(|y| (0..display.width as usize).map(move |x| (x, y)))
+            .filter(|&(x, y)| display.pixels[y][x])
+            .collect()
+    }
+
+    // Run one 8XYN opcode with X == Y on V5 = value and VF = 0xAA, returning (V5, VF)
+    fn run_8xyn_aliased(opcode: u16, value: u8) -> (u8, u8) {
+        let (mut cpu, mut memory, mut display) = setup(&opcode.to_be_bytes());
+        cpu.v[0x5] = value;
+        cpu.v[0xF] = 0xAA;
+        run(&mut cpu, &mut memory, &mut display, 1);
+        (cpu.v[0x5], cpu.v[0xF])
+    }
+
+    #[test]
+    fn arithmetic_with_x_equal_to_y() {
+        assert_eq!(run_8xyn_aliased(0x8550, 0x81), (0x81, 0xAA));
+        // vf_reset is on by default
+        assert_eq!(run_8xyn_aliased(0x8551, 0x81), (0x81, 0x00));
+        assert_eq!(run_8xyn_aliased(0x8552, 0x81), (0x81, 0x00));
+        assert_eq!(run_8xyn_aliased(0x8553, 0x81), (0x00, 0x00));
+        // Doubling, with and without a carry
+        assert_eq!(run_8xyn_aliased(0x8554, 0x80), (0x00, 0x01));
+        assert_eq!(run_8xyn_aliased(0x8554, 0x40), (0x80, 0x00));
+        // Subtracting a register from itself never borrows
+        assert_eq!(run_8xyn_aliased(0x8555, 0x81), (0x00, 0x01));
+        assert_eq!(run_8xyn_aliased(0x8557, 0x81), (0x00, 0x01));
+        // shift_uses_vy copies V5 onto itself first
+        assert_eq!(run_8xyn_aliased(0x8556, 0x81), (0x40, 0x01));
+        assert_eq!(run_8xyn_aliased(0x855E, 0x81), (0x02, 0x01));
+    }
+
+    #[test]
+    fn arithmetic_on_vf_leaves_the_flag() {
+        // With X == Y == F the flag is written last, so it wins over the result
+        let (mut cpu, mut memory, mut display) = setup(&[0x8F, 0xF4, 0x8F, 0xF5]);
+        cpu.v[0xF] = 0x80;
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!(cpu.v[0xF], 0x01);
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!(cpu.v[0xF], 0x01);
+    }
+
+    #[test]
+    fn stack_errors() {
+        // 2200 calls itself, filling a stack slot each time
+        let (mut cpu, mut memory, mut display) = setup(&[0x22, 0x00]);
+        for _ in 0..16 {
+            assert_eq!(cpu.decode(&mut memory, &mut display, &[false; 16]), Ok(()));
+        }
+        assert_eq!(cpu.decode(&mut memory, &mut display, &[false; 16]), Err(CpuError::StackOverflow));
+        assert_eq!(cpu.sp, 16);
+
+        let (mut cpu, mut memory, mut display) = setup(&[0x00, 0xEE]);
+        assert_eq!(cpu.decode(&mut memory, &mut display, &[false; 16]), Err(CpuError::StackUnderflow));
+        assert_eq!(cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn f000_loads_a_16_bit_i() {
+        let (mut cpu, mut memory, mut display) = setup(&[0xF0, 0x00, 0x12, 0x34, 0x60, 0x01]);
+        cpu.xochip = true;
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!(cpu.i, 0x1234);
+        assert_eq!(cpu.pc, 0x204);
+        // The instruction after NNNN runs next
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!(cpu.v[0], 0x01);
+    }
+
+    #[test]
+    fn f000_is_unknown_without_xochip() {
+        let (mut cpu, mut memory, mut display) = setup(&[0xF0, 0x00, 0x12, 0x34]);
+        assert_eq!(cpu.decode(&mut memory, &mut display, &[false; 16]), Err(CpuError::UnknownOpcode(0xF000)));
+        assert_eq!(cpu.i, 0);
+        assert_eq!(cpu.pc, 0x202);
+        let mismatches: Vec<PlatformMismatch> = cpu.take_platform_mismatches();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].platform, "XO-CHIP");
+    }
+
+    #[test]
+    fn skips_step_over_f000_nnnn() {
+        // 3000 skips, as V0 is 0
+        let (mut cpu, mut memory, mut display) = setup(&[0x30, 0x00, 0xF0, 0x00, 0x12, 0x34]);
+        cpu.xochip = true;
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!(cpu.pc, 0x206);
+
+        let (mut cpu, mut memory, mut display) = setup(&[0x30, 0x00, 0xF0, 0x00, 0x12, 0x34]);
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn fx0a_hold_counts_emulator_frames() {
+        let (mut cpu, mut memory, mut display) = setup(&[0xF3, 0x0A]);
+        cpu.fx0a_min_hold_frames = 2;
+        let mut held: [bool; 16] = [false; 16];
+        held[0x5] = true;
+        let _ = cpu.decode(&mut memory, &mut display, &[false; 16]);
+        // However many instructions run, the key isn't taken until two frames have passed
+        for _ in 0..2 {
+            for _ in 0..10 {
+                let _ = cpu.decode(&mut memory, &mut display, &held);
+                assert_eq!(cpu.fx0a_key_down, None);
             }
-            
-            // FX29: Load font Character
-            [0xF, _, 0x2, 0x9] => {
-                let vx: usize = nibbles[1] as usize;
-                if vx < 16 {
-                    // Set I to the address of the font character
-                    self.i = FONT_ADDRESS as u16 + (self.v[vx] as u16);
-                } else {
-                    println!("Invalid register index: {}", vx);
-                }
+            cpu.count_frame();
+        }
+        let _ = cpu.decode(&mut memory, &mut display, &held);
+        assert_eq!(cpu.fx0a_key_down, Some(0x5));
+        let _ = cpu.decode(&mut memory, &mut display, &[false; 16]);
+        assert_eq!((cpu.v[0x3], cpu.pc), (0x5, 0x202));
+    }
+
+    #[test]
+    fn beeping_follows_sound_timer_min() {
+        let (mut cpu, _, _) = setup(&[]);
+        cpu.sound_timer = 1;
+        assert!(cpu.is_beeping());
+        cpu.quirks = Quirks::chip8();
+        assert!(!cpu.is_beeping());
+        cpu.sound_timer = 2;
+        assert!(cpu.view().beeping);
+        cpu.sound_timer = 0;
+        cpu.quirks.sound_timer_min = 0;
+        assert!(!cpu.is_beeping());
+    }
+
+    #[test]
+    fn decode_match_agrees_on_f000() {
+        let (mut cpu, mut memory, mut display) = setup(&[0xF0, 0x00, 0x12, 0x34]);
+        assert!(cpu.decode_match(&mut memory, &mut display, &[false; 16]).is_err());
+        let (mut cpu, mut memory, mut display) = setup(&[0xF0, 0x00, 0x12, 0x34]);
+        cpu.xochip = true;
+        assert_eq!(cpu.decode_match(&mut memory, &mut display, &[false; 16]), Ok(()));
+        assert_eq!((cpu.i, cpu.pc), (0x1234, 0x204));
+    }
+
+    #[test]
+    fn shifts_follow_shift_uses_vy() {
+        // 8016 and 801E with V0 = 0x81 and V1 = 0x02, giving (V0, VF)
+        for (shift_uses_vy, right, left) in [(true, (0x01, 0x00), (0x04, 0x00)), (false, (0x40, 0x01), (0x02, 0x01))] {
+            for (opcode, expected) in [(0x8016u16, right), (0x801E, left)] {
+                let (mut cpu, mut memory, mut display) = setup(&opcode.to_be_bytes());
+                cpu.quirks = Quirks { shift_uses_vy, ..Quirks::default() };
+                cpu.v[0x0] = 0x81;
+                cpu.v[0x1] = 0x02;
+                run(&mut cpu, &mut memory, &mut display, 1);
+                assert_eq!((cpu.v[0x0], cpu.v[0xF]), expected, "{:04X} with shift_uses_vy {}", opcode, shift_uses_vy);
+                assert_eq!(cpu.v[0x1], 0x02);
             }
-            // FX33: Binary-Coded decimal conversion
-            [0xF, _, 0x3, 0x3] => {
-                let vx: usize = nibbles[1] as usize;
-                let val: u8 = self.v[vx];
-                if vx < 16 {
-                    // Store the hundreds digit
-                    memory.data[self.i as usize] = val / 100;
-                    // Store the tens digit
-                    memory.data[self.i as usize + 1] = (val / 10) % 10;
-                    // Store the units digit
-                    memory.data[self.i as usize + 2] = val % 10;
-                } else {
-                    println!("Invalid register index: {}", vx);
-                }
+        }
+    }
+
+    #[test]
+    fn bnnn_jumps_past_the_end_of_ram() {
+        // BFFF with V0 = 0xFF, or VF = 0xFF with jump_uses_vx
+        for jump_uses_vx in [false, true] {
+            let (mut cpu, mut memory, mut display) = setup(&[0xBF, 0xFF]);
+            cpu.quirks = Quirks { jump_uses_vx, ..Quirks::default() };
+            cpu.v[0x0] = if jump_uses_vx { 0x01 } else { 0xFF };
+            cpu.v[0xF] = if jump_uses_vx { 0xFF } else { 0x01 };
+            run(&mut cpu, &mut memory, &mut display, 1);
+            assert_eq!(cpu.pc, 0x10FE, "jump_uses_vx {}", jump_uses_vx);
+            // Fetching there wraps around RAM instead of panicking
+            assert_eq!(cpu.fetch(&memory), 0x0000);
+            run(&mut cpu, &mut memory, &mut display, 1);
+        }
+
+        let (mut cpu, mut memory, mut display) = setup(&[0xB3, 0x00]);
+        cpu.quirks.jump_uses_vx = true;
+        cpu.v[0x0] = 0x10;
+        cpu.v[0x3] = 0x20;
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!(cpu.pc, 0x320);
+    }
+
+    #[test]
+    fn load_store_near_the_top_of_ram_wraps() {
+        // FF55 with I = 0xFFA writes V0 to V5 at the top of RAM and V6 to VF from address 0
+        let (mut cpu, mut memory, mut display) = setup(&[0xFF, 0x55, 0xF0, 0x33]);
+        for register in 0..16 {
+            cpu.v[register] = 0x10 + register as u8;
+        }
+        cpu.i = 0xFFA;
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!(memory.data[0xFFA..], [0x10, 0x11, 0x12, 0x13, 0x14, 0x15]);
+        assert_eq!(memory.data[..10], [0x16, 0x17, 0x18, 0x19, 0x1A, 0x1B, 0x1C, 0x1D, 0x1E, 0x1F]);
+        assert_eq!(cpu.i, 0x100A);
+        assert_eq!(cpu.take_warnings().len(), 1);
+
+        // F033 with I = 0xFFF puts the hundreds at the top and the rest at 0 and 1, warning only once
+        cpu.v[0x0] = 234;
+        cpu.i = 0xFFF;
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!((memory.data[0xFFF], memory.data[0x0], memory.data[0x1]), (2, 3, 4));
+        assert!(cpu.take_warnings().is_empty());
+
+        // In range, nothing wraps or warns
+        let (mut cpu, mut memory, mut display) = setup(&[0xF0, 0x33]);
+        cpu.v[0x0] = 234;
+        cpu.i = 0xFFD;
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!(memory.data[0xFFD..], [2, 3, 4]);
+        assert_eq!(memory.data[0x0], 0);
+        assert!(cpu.take_warnings().is_empty());
+    }
+
+    #[test]
+    fn fx0a_finishes_on_release() {
+        // F30A, then 6101 to show the PC only moved on once
+        let (mut cpu, mut memory, mut display) = setup(&[0xF3, 0x0A, 0x61, 0x01]);
+        let mut held: [bool; 16] = [false; 16];
+        held[0x7] = true;
+        let _ = cpu.decode(&mut memory, &mut display, &[false; 16]);
+        assert_eq!(cpu.pc, 0x200);
+        // Holding the key keeps waiting
+        for _ in 0..3 {
+            let _ = cpu.decode(&mut memory, &mut display, &held);
+            assert_eq!((cpu.v[0x3], cpu.pc), (0x0, 0x200));
+        }
+        let _ = cpu.decode(&mut memory, &mut display, &[false; 16]);
+        assert_eq!((cpu.v[0x3], cpu.pc), (0x7, 0x202));
+        let _ = cpu.decode(&mut memory, &mut display, &[false; 16]);
+        assert_eq!((cpu.v[0x1], cpu.pc), (0x1, 0x204));
+    }
+
+    #[test]
+    fn fx0a_ignores_a_key_held_before_it_started() {
+        let (mut cpu, mut memory, mut display) = setup(&[0xF3, 0x0A]);
+        let mut held: [bool; 16] = [false; 16];
+        held[0x7] = true;
+        for _ in 0..3 {
+            let _ = cpu.decode(&mut memory, &mut display, &held);
+        }
+        let _ = cpu.decode(&mut memory, &mut display, &[false; 16]);
+        assert_eq!(cpu.pc, 0x200);
+        // Pressed again after it was let go, it counts
+        let _ = cpu.decode(&mut memory, &mut display, &held);
+        let _ = cpu.decode(&mut memory, &mut display, &[false; 16]);
+        assert_eq!((cpu.v[0x3], cpu.pc), (0x7, 0x202));
+    }
+
+    #[test]
+    fn sprites_past_the_right_edge_wrap_or_clip() {
+        // An 8 pixel wide sprite at (60, 0), half of it past the right edge
+        for (wrap_x, expected) in [(true, vec![0, 1, 2, 3, 60, 61, 62, 63]), (false, vec![60, 61, 62, 63])] {
+            let (mut cpu, mut memory, mut display) = setup(&[0xD0, 0x11]);
+            memory.data[0x300] = 0xFF;
+            cpu.i = 0x300;
+            cpu.v[0x0] = 60;
+            display.wrap_x = wrap_x;
+            run(&mut cpu, &mut memory, &mut display, 1);
+            let lit: Vec<usize> = (0..LORES_WIDTH as usize).filter(|&x| display.pixels[0][x]).collect();
+            assert_eq!(lit, expected, "wrap_x {}", wrap_x);
+            assert_eq!(cpu.v[0xF], 0);
+        }
+    }
+
+    #[test]
+    fn logic_ops_follow_vf_reset() {
+        // 8011, 8012 and 8013 with V0 = 0x0C, V1 = 0x0A and VF = 0xAA, giving V0
+        for (opcode, result) in [(0x8011u16, 0x0E), (0x8012, 0x08), (0x8013, 0x06)] {
+            for (vf_reset, vf) in [(true, 0x00), (false, 0xAA)] {
+                let (mut cpu, mut memory, mut display) = setup(&opcode.to_be_bytes());
+                cpu.quirks = Quirks { vf_reset, ..Quirks::default() };
+                cpu.v[0x0] = 0x0C;
+                cpu.v[0x1] = 0x0A;
+                cpu.v[0xF] = 0xAA;
+                run(&mut cpu, &mut memory, &mut display, 1);
+                assert_eq!((cpu.v[0x0], cpu.v[0xF]), (result, vf), "{:04X} with vf_reset {}", opcode, vf_reset);
             }
-            // FX55: Store registers V0 to VX in memory starting at address I
-            [0xF, _, 0x5, 0x5] => {
-                let vx: usize = nibbles[1] as usize;
-                if vx < 16 {
-                    for i in 0..=vx {
-                        memory.data[(self.i) as usize] = self.v[i];
-                        self.i += 1;
-                    }
-                    // CHIP-8 Quirk: We do not reset I to its original value after operation
-                } else {
-                    println!("Invalid register index: {}", vx);
-                }
+        }
+    }
+
+    #[test]
+    fn chip8e_opcodes() {
+        let chip8e = |program: &[u8]| {
+            let (mut cpu, memory, display) = setup(program);
+            cpu.chip8e = true;
+            (cpu, memory, display)
+        };
+
+        // 00ED stops on itself, 00F2 does nothing
+        let (mut cpu, mut memory, mut display) = chip8e(&[0x00, 0xF2, 0x00, 0xED]);
+        run(&mut cpu, &mut memory, &mut display, 5);
+        assert_eq!(cpu.pc, 0x202);
+
+        // 0151 waits for the delay timer, 0188 skips the next instruction
+        let (mut cpu, mut memory, mut display) = chip8e(&[0x01, 0x51, 0x01, 0x88, 0x60, 0x01, 0x61, 0x01]);
+        cpu.delay_timer = 2;
+        run(&mut cpu, &mut memory, &mut display, 3);
+        assert_eq!(cpu.pc, 0x200);
+        cpu.tick_timers();
+        cpu.tick_timers();
+        run(&mut cpu, &mut memory, &mut display, 3);
+        assert_eq!((cpu.v[0x0], cpu.v[0x1], cpu.pc), (0, 1, 0x208));
+
+        // 5011 skips when V0 > V1, and not when they are equal
+        let (mut cpu, mut memory, mut display) = chip8e(&[0x50, 0x11, 0x00, 0x00, 0x50, 0x11]);
+        cpu.v[0x0] = 2;
+        cpu.v[0x1] = 1;
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!(cpu.pc, 0x204);
+        cpu.v[0x1] = 2;
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!(cpu.pc, 0x206);
+
+        // 5132 stores V1 to V3 at I and 5463 reads them back into V4 to V6, each leaving I past the end
+        let (mut cpu, mut memory, mut display) = chip8e(&[0x51, 0x32, 0x54, 0x63]);
+        cpu.v[0x1..=0x3].copy_from_slice(&[0x11, 0x22, 0x33]);
+        cpu.i = 0x300;
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!((memory.data[0x300..0x303].to_vec(), cpu.i), (vec![0x11, 0x22, 0x33], 0x303));
+        cpu.i = 0x300;
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!((cpu.v[0x4..=0x6].to_vec(), cpu.i), (vec![0x11, 0x22, 0x33], 0x303));
+
+        // BF02 branches forward over 2 bytes, BB06 branches back to the start
+        let (mut cpu, mut memory, mut display) = chip8e(&[0xBF, 0x02, 0x00, 0x00, 0xBB, 0x06]);
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!(cpu.pc, 0x204);
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!(cpu.pc, 0x200);
+
+        // F01B skips V0 bytes
+        let (mut cpu, mut memory, mut display) = chip8e(&[0xF0, 0x1B]);
+        cpu.v[0x0] = 4;
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!(cpu.pc, 0x206);
+
+        // F04F sets the delay timer to V0 and waits for it to run out
+        let (mut cpu, mut memory, mut display) = chip8e(&[0xF0, 0x4F]);
+        cpu.v[0x0] = 2;
+        run(&mut cpu, &mut memory, &mut display, 3);
+        assert_eq!((cpu.delay_timer, cpu.pc), (2, 0x200));
+        cpu.tick_timers();
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!((cpu.delay_timer, cpu.pc), (1, 0x200));
+        cpu.tick_timers();
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!(cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn jumps_to_low_addresses() {
+        // 1000 jumps to 0, where nothing needs subtracting from the target
+        let (mut cpu, mut memory, mut display) = setup(&[0x10, 0x00]);
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!(cpu.pc, 0x000);
+
+        // B000 with V0 = 0 as well
+        let (mut cpu, mut memory, mut display) = setup(&[0xB0, 0x00]);
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!(cpu.pc, 0x000);
+
+        // 2000 calls address 0, where 00EE returns to just after the call
+        let (mut cpu, mut memory, mut display) = setup(&[0x20, 0x00]);
+        memory.data[0x000..0x002].copy_from_slice(&[0x00, 0xEE]);
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!((cpu.pc, cpu.sp, cpu.stack[0]), (0x000, 1, 0x200));
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!((cpu.pc, cpu.sp), (0x202, 0));
+
+        // A skip at 0x000 lands on 0x004
+        let (mut cpu, mut memory, mut display) = setup(&[0x10, 0x00]);
+        memory.data[0x000..0x002].copy_from_slice(&[0x30, 0x00]);
+        run(&mut cpu, &mut memory, &mut display, 2);
+        assert_eq!(cpu.pc, 0x004);
+    }
+
+    #[test]
+    fn sprite_origins_wrap_onto_the_display() {
+        // A single pixel sprite at (V0, V1), with clipping on so only the origin can wrap
+        for ((vx, vy), expected) in [((10, 5), (10, 5)), ((70, 40), (6, 8)), ((64, 32), (0, 0)), ((255, 255), (63, 31))] {
+            let (mut cpu, mut memory, mut display) = setup(&[0xD0, 0x11]);
+            memory.data[0x300] = 0x80;
+            cpu.i = 0x300;
+            (cpu.v[0x0], cpu.v[0x1]) = (vx, vy);
+            (display.wrap_x, display.wrap_y) = (false, false);
+            run(&mut cpu, &mut memory, &mut display, 1);
+            assert_eq!(lit(&display), [expected], "drawn at ({}, {})", vx, vy);
+        }
+    }
+
+    #[test]
+    fn fx29_uses_the_font_stride() {
+        for (font_char_size, digit, expected) in [(5, 0x3, FONT_ADDRESS + 15), (6, 0x3, FONT_ADDRESS + 18), (6, 0xF, FONT_ADDRESS + 90)] {
+            let (mut cpu, mut memory, mut display) = setup(&[0xF0, 0x29]);
+            cpu.font_char_size = font_char_size;
+            cpu.v[0x0] = digit;
+            run(&mut cpu, &mut memory, &mut display, 1);
+            assert_eq!(cpu.i, expected, "digit {:X} with {} byte characters", digit, font_char_size);
+        }
+    }
+
+    #[test]
+    fn sprites_read_past_the_end_of_ram() {
+        // A 4 row sprite at I = 0xFFE, two rows at the top of RAM and two wrapped to the start
+        for (mode, rows, warnings) in [
+            (SpriteReadOverflow::Wrap, 4, 0),
+            (SpriteReadOverflow::Clamp, 2, 0),
+            (SpriteReadOverflow::WarnAndClamp, 2, 1),
+        ] {
+            let (mut cpu, mut memory, mut display) = setup(&[0xD0, 0x04, 0x00, 0xE0, 0xD0, 0x04]);
+            for address in [0xFFE, 0xFFF, 0x000, 0x001] {
+                memory.data[address] = 0x80;
             }
-            // FX65: Read registers V0 to VX from memory starting at address I
-            [0xF, _, 0x6, 0x5] => {
-                let vx: usize = nibbles[1] as usize;
-                if vx < 16 {
-                    for i in 0..=vx {
-                        self.v[i] = memory.data[(self.i) as usize];
-                        self.i += 1;
-                    }
-                    // CHIP-8 Quirk: We do not reset I to its original value after operation
-                } else {
-                    println!("Invalid register index: {}", vx);
-                }
+            cpu.i = 0xFFE;
+            cpu.sprite_read_overflow = mode;
+            run(&mut cpu, &mut memory, &mut display, 1);
+            let lit: usize = (0..LORES_HEIGHT as usize).filter(|&y| display.pixels[y][0]).count();
+            assert_eq!(lit, rows, "{:?}", mode);
+            // Only the first draw warns
+            run(&mut cpu, &mut memory, &mut display, 2);
+            assert_eq!(cpu.take_warnings().len(), warnings, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn scrolls_move_by_pixels_of_the_current_resolution() {
+        // 00C3 scrolls down 3, 00FB right 4 and 00FC left 4. The second pixel starts near an edge
+        // and is scrolled off it.
+        for (hires, start, edge) in [(false, (10, 10), (62, 30)), (true, (100, 50), (126, 62))] {
+            let (mut cpu, mut memory, mut display) = setup(&[0x00, 0xC3, 0x00, 0xFB, 0x00, 0xFC, 0x00, 0xFC]);
+            cpu.schip = true;
+            display.set_hires(hires);
+            display.pixels[start.1][start.0] = true;
+            display.pixels[edge.1][edge.0] = true;
+            run(&mut cpu, &mut memory, &mut display, 1);
+            assert_eq!(lit(&display), [(start.0, start.1 + 3)], "hires {}", hires);
+            display.pixels[edge.1][edge.0] = true;
+            run(&mut cpu, &mut memory, &mut display, 1);
+            assert_eq!(lit(&display), [(start.0 + 4, start.1 + 3)], "hires {}", hires);
+            run(&mut cpu, &mut memory, &mut display, 2);
+            assert_eq!(lit(&display), [(start.0 - 4, start.1 + 3)], "hires {}", hires);
+        }
+    }
+
+    #[test]
+    fn dxy0_draws_16x16_in_high_resolution() {
+        // A300 D010 D010 with a sprite lighting the first and last column of each of its 16 rows
+        for hires in [false, true] {
+            let (mut cpu, mut memory, mut display) = setup(&[0xA3, 0x00, 0xD0, 0x10, 0xD0, 0x10]);
+            cpu.schip = true;
+            display.set_hires(hires);
+            for row in 0..16 {
+                memory.data[0x300 + row * 2] = 0x80;
+                memory.data[0x301 + row * 2] = 0x01;
             }
-            _ => {
-                // Handle other opcodes
-                println!("Unknown opcode: {:04X}", opcode);
+            run(&mut cpu, &mut memory, &mut display, 2);
+            let expected: Vec<(usize, usize)> =
+                if hires { (0..16).flat_map(|y| [(0, y), (15, y)]).collect() } else { Vec::new() };
+            assert_eq!(lit(&display), expected, "hires {}", hires);
+            assert_eq!(cpu.v[0xF], 0, "hires {}", hires);
+            // Drawing it again erases it
+            run(&mut cpu, &mut memory, &mut display, 1);
+            assert_eq!(lit(&display), [], "hires {}", hires);
+            assert_eq!(cpu.v[0xF], u8::from(hires), "hires {}", hires);
+        }
+    }
+
+    #[test]
+    fn fx1e_flags_i_leaving_4_kb() {
+        // F01E with V0 = 0x10 and VF = 0xAA, giving (I, VF)
+        for (i, i_overflow_sets_vf, expected) in [
+            (0x0FE0, false, (0x0FF0, 0xAA)),
+            (0x0FE0, true, (0x0FF0, 0xAA)),
+            (0x0FF8, false, (0x1008, 0xAA)),
+            (0x0FF8, true, (0x1008, 0x01)),
+            // Past 0xFFFF I wraps instead of panicking
+            (0xFFF8, false, (0x0008, 0xAA)),
+        ] {
+            let (mut cpu, mut memory, mut display) = setup(&[0xF0, 0x1E]);
+            cpu.quirks.i_overflow_sets_vf = i_overflow_sets_vf;
+            cpu.i = i;
+            cpu.v[0x0] = 0x10;
+            cpu.v[0xF] = 0xAA;
+            run(&mut cpu, &mut memory, &mut display, 1);
+            assert_eq!((cpu.i, cpu.v[0xF]), expected, "I={:04X} with i_overflow_sets_vf {}", i, i_overflow_sets_vf);
+        }
+    }
+
+    #[test]
+    fn load_store_leaves_i_per_mode() {
+        // F255 and F265 with I = 0x300, so X = 2
+        for (mode, expected) in [
+            (LoadStoreIncrement::Unchanged, 0x300),
+            (LoadStoreIncrement::X, 0x302),
+            (LoadStoreIncrement::XPlusOne, 0x303),
+        ] {
+            for opcode in [0xF255u16, 0xF265] {
+                let (mut cpu, mut memory, mut display) = setup(&opcode.to_be_bytes());
+                cpu.quirks.load_store_increments_i = mode;
+                cpu.i = 0x300;
+                run(&mut cpu, &mut memory, &mut display, 1);
+                assert_eq!(cpu.i, expected, "{:04X} with {:?}", opcode, mode);
             }
-            
         }
-        // Increment the program counter
-        self.pc += 2;
+
+        // FF65 reads all 16 registers whatever the mode
+        let (mut cpu, mut memory, mut display) = setup(&[0xFF, 0x65]);
+        cpu.quirks.load_store_increments_i = LoadStoreIncrement::X;
+        memory.data[0x300..0x310].copy_from_slice(&[0x42; 16]);
+        cpu.i = 0x300;
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!((cpu.v, cpu.i), ([0x42; 16], 0x30F));
     }
-}
 
+    #[test]
+    fn unknown_opcodes_are_errors() {
+        // 5XY1 is only known with CHIP-8E, 00FF only with SUPER-CHIP
+        for opcode in [0x5121u16, 0x00FF, 0xE1FF, 0xF1FF] {
+            let [high, low]: [u8; 2] = opcode.to_be_bytes();
+            let (mut cpu, mut memory, mut display) = setup(&[high, low, 0x60, 0x01]);
+            assert_eq!(cpu.decode(&mut memory, &mut display, &[false; 16]), Err(CpuError::UnknownOpcode(opcode)));
+            assert_eq!(cpu.pc, 0x202);
+            // The next instruction is fine again
+            assert_eq!(cpu.decode(&mut memory, &mut display, &[false; 16]), Ok(()));
+        }
+        assert_eq!(CpuError::UnknownOpcode(0x5121).to_string(), "Unknown opcode 5121");
+    }
+
+    #[test]
+    fn chip8e_skips_step_over_f000_nnnn() {
+        // 0188 always skips, 5011 skips as V0 > V1. Each is followed by F000 NNNN.
+        for skip in [[0x01, 0x88], [0x50, 0x11]] {
+            let (mut cpu, mut memory, mut display) = setup(&[skip[0], skip[1], 0xF0, 0x00, 0x12, 0x34]);
+            cpu.chip8e = true;
+            cpu.xochip = true;
+            cpu.v[0x0] = 1;
+            run(&mut cpu, &mut memory, &mut display, 1);
+            assert_eq!(cpu.pc, 0x206, "{:02X}{:02X}", skip[0], skip[1]);
+        }
+    }
+
+    #[test]
+    fn chip8e_ranges_run_backwards() {
+        // 5312 stores V3, V2, V1 in that order, 5313 reads them back the same way
+        let (mut cpu, mut memory, mut display) = setup(&[0x53, 0x12, 0x53, 0x13]);
+        cpu.chip8e = true;
+        cpu.v[0x1..=0x3].copy_from_slice(&[0x11, 0x22, 0x33]);
+        cpu.i = 0x300;
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!((memory.data[0x300..0x303].to_vec(), cpu.i), (vec![0x33, 0x22, 0x11], 0x303));
+        cpu.v = [0; 16];
+        cpu.i = 0x300;
+        run(&mut cpu, &mut memory, &mut display, 1);
+        assert_eq!(cpu.v[0x1..=0x3], [0x11, 0x22, 0x33]);
+    }
+}
@@ -0,0 +1,60 @@
+use std::fmt::Write;
+use std::panic::PanicHookInfo;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::config::config_dir;
+use crate::cpu::CpuSnapshot;
+use crate::emulator::Emulator;
+
+// The report from the most recent crash, overwritten each time
+pub fn crash_report_path() -> PathBuf {
+    config_dir().join("crash_report.txt")
+}
+
+// Install a panic hook that keeps the panic message and location for the crash report.
+// The previous hook still runs, so the panic is printed as usual.
+pub fn capture_panic_message() -> Arc<Mutex<Option<String>>> {
+    let message: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+    let hook_message: Arc<Mutex<Option<String>>> = Arc::clone(&message);
+    let previous_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info: &PanicHookInfo<'_>| {
+        if let Ok(mut hook_message) = hook_message.lock() {
+            *hook_message = Some(info.to_string());
+        }
+        previous_hook(info);
+    }));
+    message
+}
+
+// Write what the emulator was doing when it panicked
+pub fn write_crash_report(message: &str, emulator: &Emulator) -> std::io::Result<PathBuf> {
+    let mut report: String = String::new();
+    let _ = writeln!(report, "{}", message);
+    let _ = writeln!(report);
+    let _ = writeln!(report, "Cycles: {}", emulator.cycles);
+
+    let cpu: CpuSnapshot = emulator.cpu.snapshot();
+    let _ = writeln!(report, "PC: {:04X}  I: {:04X}  SP: {}", cpu.pc, cpu.i, cpu.sp);
+    for (index, value) in cpu.v.iter().enumerate() {
+        let _ = write!(report, "V{:X}: {:02X}  ", index, value);
+    }
+    let _ = writeln!(report);
+    let _ = write!(report, "Stack:");
+    for address in cpu.stack.iter().take(cpu.sp as usize) {
+        let _ = write!(report, " {:04X}", address);
+    }
+    let _ = writeln!(report);
+
+    // Oldest first, the last line is the instruction that was running
+    let _ = writeln!(report);
+    let _ = writeln!(report, "Recent opcodes:");
+    for step in emulator.recent_opcodes() {
+        let _ = writeln!(report, "{:04X}  {:04X}", step.pc, step.opcode);
+    }
+
+    let path: PathBuf = crash_report_path();
+    std::fs::create_dir_all(config_dir())?;
+    std::fs::write(&path, report)?;
+    Ok(path)
+}
@@ -0,0 +1,301 @@
+use crate::emulator::ROM_ADDRESS;
+use crate::memory::MAX_RAM_SIZE;
+use crate::opcode_filter::OpcodePattern;
+use crate::quirks::Quirks;
+
+// One line of a ROM listing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListingLine {
+    Code { address: u16, opcode: u16 },
+    Data { address: u16, byte: u8 },
+}
+
+// Split a ROM into code and data.
+// With follow_control_flow, only instructions reachable from the entry point are code and every
+// other byte is data (usually sprites). Without it, the whole ROM is read as 2-byte opcodes.
+pub fn listing(rom: &[u8], follow_control_flow: bool) -> Vec<ListingLine> {
+    let rom: &[u8] = loadable(rom);
+    let instruction_starts: Vec<bool> = if follow_control_flow {
+        find_code(rom)
+    } else {
+        (0..rom.len()).map(|offset| offset % 2 == 0).collect()
+    };
+
+    let mut lines: Vec<ListingLine> = Vec::new();
+    let mut offset: usize = 0;
+    while offset < rom.len() {
+        let address: u16 = ROM_ADDRESS + offset as u16;
+        if instruction_starts[offset] && offset + 1 < rom.len() {
+            let opcode: u16 = u16::from_be_bytes([rom[offset], rom[offset + 1]]);
+            lines.push(ListingLine::Code { address, opcode });
+            offset += 2;
+        } else {
+            lines.push(ListingLine::Data { address, byte: rom[offset] });
+            offset += 1;
+        }
+    }
+    lines
+}
+
+// Decode every 2-byte opcode of a ROM into an address and its assembly, e.g. (0x200, "MOV V0, 0x1F").
+// Opcodes without a mnemonic come out as "DW 0xXXXX", and an odd last byte as "DB 0xXX".
+pub fn disassemble(rom: &[u8]) -> Vec<(u16, String)> {
+    loadable(rom)
+        .chunks(2)
+        .enumerate()
+        .map(|(index, bytes)| {
+            let address: u16 = ROM_ADDRESS + (index * 2) as u16;
+            let text: String = match *bytes {
+                [high, low] => {
+                    let opcode: u16 = u16::from_be_bytes([high, low]);
+                    mnemonic(opcode).unwrap_or_else(|| format!("DW 0x{:04X}", opcode))
+                }
+                _ => format!("DB 0x{:02X}", bytes[0]),
+            };
+            (address, text)
+        })
+        .collect()
+}
+
+// The part of a ROM that fits in the largest RAM, so every byte has a 16-bit address
+fn loadable(rom: &[u8]) -> &[u8] {
+    &rom[..rom.len().min(MAX_RAM_SIZE - ROM_ADDRESS as usize)]
+}
+
+// Assembly for an opcode as the default settings run it, None for opcodes without a mnemonic
+pub fn mnemonic(opcode: u16) -> Option<String> {
+    mnemonic_for(opcode, false, &Quirks::default())
+}
+
+// Assembly for an opcode on a platform. The CHIP-8E instructions only decode with chip8e, where
+// 00ED, 00F2, 0151, 0188, BBNN and BFNN take the place of SYS and BNNN, and jump_uses_vx changes
+// how BNNN reads. F000 is followed by its 2-byte address, which the listing shows as data.
+pub fn mnemonic_for(opcode: u16, chip8e: bool, quirks: &Quirks) -> Option<String> {
+    let x: u16 = (opcode >> 8) & 0xF;
+    let y: u16 = (opcode >> 4) & 0xF;
+    let n: u16 = opcode & 0xF;
+    let nn: u16 = opcode & 0xFF;
+    let nnn: u16 = opcode & 0xFFF;
+    let text: String = match (opcode >> 12, x, y, n) {
+        _ if chip8e && opcode == 0x00ED => "STOP".to_string(),
+        _ if chip8e && opcode == 0x00F2 => "NOP".to_string(),
+        _ if chip8e && opcode == 0x0151 => "WAIT DT".to_string(),
+        _ if chip8e && opcode == 0x0188 => "SKIP".to_string(),
+        (0xB, 0xB, _, _) if chip8e => format!("JB 0x{:02X}", nn),
+        (0xB, 0xF, _, _) if chip8e => format!("JF 0x{:02X}", nn),
+        (0x0, 0x0, 0xE, 0x0) => "CLS".to_string(),
+        (0x0, 0x0, 0xE, 0xE) => "RET".to_string(),
+        (0x0, 0x0, 0xC, _) => format!("SCD {}", n),
+        (0x0, 0x0, 0xF, 0xB) => "SCR".to_string(),
+        (0x0, 0x0, 0xF, 0xC) => "SCL".to_string(),
+        (0x0, 0x0, 0xF, 0xD) => "EXIT".to_string(),
+        (0x0, 0x0, 0xF, 0xE) => "LOW".to_string(),
+        (0x0, 0x0, 0xF, 0xF) => "HIGH".to_string(),
+        (0x0, _, _, _) => format!("SYS 0x{:03X}", nnn),
+        (0x1, _, _, _) => format!("JP 0x{:03X}", nnn),
+        (0x2, _, _, _) => format!("CALL 0x{:03X}", nnn),
+        (0x3, _, _, _) => format!("SE V{:X}, 0x{:02X}", x, nn),
+        (0x4, _, _, _) => format!("SNE V{:X}, 0x{:02X}", x, nn),
+        (0x5, _, _, 0x0) => format!("SE V{:X}, V{:X}", x, y),
+        (0x5, _, _, 0x1) if chip8e => format!("SGT V{:X}, V{:X}", x, y),
+        (0x5, _, _, 0x2) if chip8e => format!("MOV [I], V{:X}-V{:X}", x, y),
+        (0x5, _, _, 0x3) if chip8e => format!("MOV V{:X}-V{:X}, [I]", x, y),
+        (0x6, _, _, _) => format!("MOV V{:X}, 0x{:02X}", x, nn),
+        (0x7, _, _, _) => format!("ADD V{:X}, 0x{:02X}", x, nn),
+        (0x8, _, _, 0x0) => format!("MOV V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x1) => format!("OR V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x2) => format!("AND V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x3) => format!("XOR V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x4) => format!("ADD V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x5) => format!("SUB V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x6) => format!("SHR V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x7) => format!("SUBN V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0xE) => format!("SHL V{:X}, V{:X}", x, y),
+        (0x9, _, _, 0x0) => format!("SNE V{:X}, V{:X}", x, y),
+        (0xA, _, _, _) => format!("MOV I, 0x{:03X}", nnn),
+        (0xB, _, _, _) if quirks.jump_uses_vx => format!("JP V{:X}, 0x{:03X}", x, nnn),
+        (0xB, _, _, _) => format!("JP V0, 0x{:03X}", nnn),
+        (0xC, _, _, _) => format!("RND V{:X}, 0x{:02X}", x, nn),
+        (0xD, _, _, _) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        (0xE, _, 0x9, 0xE) => format!("SKP V{:X}", x),
+        (0xE, _, 0xA, 0x1) => format!("SKNP V{:X}", x),
+        (0xF, 0x0, 0x0, 0x0) => "MOV I, LONG".to_string(),
+        (0xF, _, 0x0, 0x1) => format!("PLANE {}", x),
+        (0xF, _, 0x0, 0x7) => format!("MOV V{:X}, DT", x),
+        (0xF, _, 0x0, 0xA) => format!("WAITKEY V{:X}", x),
+        (0xF, _, 0x1, 0x5) => format!("MOV DT, V{:X}", x),
+        (0xF, _, 0x1, 0x8) => format!("MOV ST, V{:X}", x),
+        (0xF, _, 0x1, 0xB) if chip8e => format!("SKIP V{:X}", x),
+        (0xF, _, 0x1, 0xE) => format!("ADD I, V{:X}", x),
+        (0xF, _, 0x2, 0x9) => format!("FONT V{:X}", x),
+        (0xF, _, 0x3, 0x0) => format!("HFONT V{:X}", x),
+        (0xF, _, 0x3, 0x3) => format!("BCD V{:X}", x),
+        (0xF, _, 0x4, 0xF) if chip8e => format!("WAIT DT, V{:X}", x),
+        (0xF, _, 0x5, 0x5) => format!("MOV [I], V0-V{:X}", x),
+        (0xF, _, 0x6, 0x5) => format!("MOV V0-V{:X}, [I]", x),
+        (0xF, _, 0x7, 0x5) => format!("MOV R, V0-V{:X}", x),
+        (0xF, _, 0x8, 0x5) => format!("MOV V0-V{:X}, R", x),
+        _ => return None,
+    };
+    Some(text)
+}
+
+// Mark the ROM offsets where a reachable instruction starts, following jumps, calls and skips from the entry point.
+// BNNN jumps to a computed address, so the analysis can't follow it and stops there.
+pub fn find_code(rom: &[u8]) -> Vec<bool> {
+    let rom: &[u8] = loadable(rom);
+    let mut instruction_starts: Vec<bool> = vec![false; rom.len()];
+    let mut pending: Vec<u16> = vec![ROM_ADDRESS];
+
+    while let Some(address) = pending.pop() {
+        let Some(offset) = (address as usize).checked_sub(ROM_ADDRESS as usize) else {
+            continue;
+        };
+        if offset + 1 >= rom.len() || instruction_starts[offset] {
+            continue;
+        }
+        instruction_starts[offset] = true;
+
+        let opcode: u16 = u16::from_be_bytes([rom[offset], rom[offset + 1]]);
+        // Past the end of RAM wraps below the ROM, where the offset check above drops it
+        let next: u16 = address.wrapping_add(2);
+        let nnn: u16 = opcode & 0x0FFF;
+        match opcode >> 12 {
+            // Return, the caller continues after its 2NNN
+            _ if opcode == 0x00EE => {}
+            // Jump
+            0x1 => pending.push(nnn),
+            // Call, then continue after it
+            0x2 => {
+                pending.push(nnn);
+                pending.push(next);
+            }
+            // Skips go on to either of the next two instructions
+            0x3 | 0x4 | 0x5 | 0x9 => {
+                pending.push(next);
+                pending.push(next.wrapping_add(instruction_length(rom, next)));
+            }
+            0xE if matches!(opcode & 0xFF, 0x9E | 0xA1) => {
+                pending.push(next);
+                pending.push(next.wrapping_add(instruction_length(rom, next)));
+            }
+            // Jump to a computed address
+            0xB => {}
+            // F000 NNNN is 4 bytes long
+            _ if opcode == 0xF000 => pending.push(next.wrapping_add(2)),
+            _ => pending.push(next),
+        }
+    }
+    instruction_starts
+}
+
+// Bytes taken by the instruction at address, 4 for F000 NNNN and 2 for everything else
+fn instruction_length(rom: &[u8], address: u16) -> u16 {
+    let bytes: Option<&[u8]> = address
+        .checked_sub(ROM_ADDRESS)
+        .and_then(|offset| rom.get(offset as usize..offset as usize + 2));
+    match bytes {
+        Some([0xF0, 0x00]) => 4,
+        _ => 2,
+    }
+}
+
+// The CHIP-8 instruction set, one pattern per instruction
+pub const OPCODE_FAMILIES: [&str; 35] = [
+    "00E0", "00EE", "0NNN", "1NNN", "2NNN", "3XNN", "4XNN", "5XY0", "6XNN", "7XNN",
+    "8XY0", "8XY1", "8XY2", "8XY3", "8XY4", "8XY5", "8XY6", "8XY7", "8XYE", "9XY0",
+    "ANNN", "BNNN", "CXNN", "DXYN", "EX9E", "EXA1", "FX07", "FX0A", "FX15", "FX18",
+    "FX1E", "FX29", "FX33", "FX55", "FX65",
+];
+
+// Extension instructions the emulator runs and the setting that turns each on. The mismatch
+// message tells the user to switch that platform on, so anything unimplemented stays out.
+pub const EXTENSION_FAMILIES: [(&str, &str); 22] = [
+    ("00ED", "CHIP-8E"), ("00F2", "CHIP-8E"), ("0151", "CHIP-8E"), ("0188", "CHIP-8E"),
+    ("5XY1", "CHIP-8E"), ("5XY2", "CHIP-8E"), ("5XY3", "CHIP-8E"),
+    ("BBNN", "CHIP-8E"), ("BFNN", "CHIP-8E"), ("FX1B", "CHIP-8E"), ("FX4F", "CHIP-8E"),
+    ("00CN", "SUPER-CHIP"), ("00FB", "SUPER-CHIP"), ("00FC", "SUPER-CHIP"), ("00FD", "SUPER-CHIP"),
+    ("00FE", "SUPER-CHIP"), ("00FF", "SUPER-CHIP"), ("FX30", "SUPER-CHIP"), ("FX75", "SUPER-CHIP"),
+    ("FX85", "SUPER-CHIP"), ("F000", "XO-CHIP"), ("FN01", "XO-CHIP"),
+];
+
+// The extension instruction an opcode belongs to and its platform, or None for anything else
+pub fn extension_family(opcode: u16) -> Option<(&'static str, &'static str)> {
+    EXTENSION_FAMILIES.iter().copied().find(|(family, _)| {
+        OpcodePattern::parse(family).is_ok_and(|pattern| pattern.matches(opcode))
+    })
+}
+
+// The instruction an opcode belongs to, or None for opcodes outside the CHIP-8 set
+pub fn opcode_family(opcode: u16) -> Option<&'static str> {
+    OPCODE_FAMILIES.iter().copied().find(|family| {
+        OpcodePattern::parse(family).is_ok_and(|pattern| pattern.matches(opcode))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_code_skips_over_f000_nnnn() {
+        // 3000 skips F000 1234 as a whole, 1234 is never an instruction
+        let rom: [u8; 10] = [0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0x61, 0x01, 0x12, 0x08];
+        let starts: Vec<bool> = find_code(&rom);
+        assert!(starts[0] && starts[2] && starts[6] && starts[8]);
+        assert!(!starts[4]);
+    }
+
+    #[test]
+    fn listings_stop_at_the_end_of_ram() {
+        // A ROM bigger than RAM, made of skips so find_code walks to the very last address
+        let rom: Vec<u8> = [0x30, 0x00].repeat(MAX_RAM_SIZE / 2);
+        let starts: Vec<bool> = find_code(&rom);
+        assert_eq!(starts.len(), MAX_RAM_SIZE - ROM_ADDRESS as usize);
+        assert!(starts.iter().step_by(2).all(|&start| start));
+
+        let lines: Vec<ListingLine> = listing(&rom, true);
+        assert_eq!(lines.last(), Some(&ListingLine::Code { address: 0xFFFE, opcode: 0x3000 }));
+        assert_eq!(disassemble(&rom).last().map(|(address, _)| *address), Some(0xFFFE));
+    }
+
+    #[test]
+    fn extension_families_are_only_ones_that_run() {
+        assert_eq!(extension_family(0x5122), Some(("5XY2", "CHIP-8E")));
+        assert_eq!(extension_family(0xF201), Some(("FN01", "XO-CHIP")));
+        // XO-CHIP's scroll up, audio pattern and pitch aren't implemented
+        assert_eq!(extension_family(0x00D4), None);
+        assert_eq!(extension_family(0xF002), None);
+        assert_eq!(extension_family(0xF13A), None);
+    }
+
+    #[test]
+    fn mnemonics_follow_the_platform() {
+        assert_eq!(mnemonic(0xF000).as_deref(), Some("MOV I, LONG"));
+        assert_eq!(mnemonic(0xF201).as_deref(), Some("PLANE 2"));
+        assert_eq!(mnemonic(0xB123).as_deref(), Some("JP V0, 0x123"));
+        let superchip: Quirks = Quirks::superchip();
+        assert_eq!(mnemonic_for(0xB123, false, &superchip).as_deref(), Some("JP V1, 0x123"));
+
+        // SYS and BNNN unless CHIP-8E is on
+        assert_eq!(mnemonic(0x00ED).as_deref(), Some("SYS 0x0ED"));
+        assert_eq!(mnemonic(0xBB04).as_deref(), Some("JP V0, 0xB04"));
+        assert_eq!(mnemonic(0x5121), None);
+        let chip8e: [(u16, &str); 11] = [
+            (0x00ED, "STOP"),
+            (0x00F2, "NOP"),
+            (0x0151, "WAIT DT"),
+            (0x0188, "SKIP"),
+            (0x5121, "SGT V1, V2"),
+            (0x5122, "MOV [I], V1-V2"),
+            (0x5123, "MOV V1-V2, [I]"),
+            (0xBB04, "JB 0x04"),
+            (0xBF04, "JF 0x04"),
+            (0xF31B, "SKIP V3"),
+            (0xF34F, "WAIT DT, V3"),
+        ];
+        for (opcode, text) in chip8e {
+            assert_eq!(mnemonic_for(opcode, true, &Quirks::default()).as_deref(), Some(text), "{:04X}", opcode);
+        }
+    }
+}
@@ -1,12 +1,204 @@
-const WIDTH: usize = 64;
-const HEIGHT: usize = 32;
-use crate::PixelBuffer;
+use std::error::Error;
+use std::path::Path;
 
-pub struct Display {
+// CHIP-8's resolution, and SUPER-CHIP's low resolution mode
+pub const LORES_WIDTH: u32 = 64;
+pub const LORES_HEIGHT: u32 = 32;
+
+// SUPER-CHIP's high resolution mode, the largest there is. Pixel arrays are always this size and
+// only the top left width x height of them is in use.
+pub const MAX_WIDTH: usize = 128;
+pub const MAX_HEIGHT: usize = 64;
+
+// One bitplane, indexed [y][x]
+pub type Plane = [[bool; MAX_WIDTH]; MAX_HEIGHT];
+
+pub struct PixelBuffer {
     pub width: u32,
     pub height: u32,
-    pub pixels: [[bool; WIDTH as usize]; HEIGHT as usize],
+    pub pixels: Plane,
+    pub second_plane: Plane,
+}
+
+impl Default for PixelBuffer {
+    fn default() -> Self {
+        PixelBuffer {
+            width: LORES_WIDTH,
+            height: LORES_HEIGHT,
+            pixels: [[false; MAX_WIDTH]; MAX_HEIGHT],
+            second_plane: [[false; MAX_WIDTH]; MAX_HEIGHT],
+        }
+    }
+}
+
+impl PixelBuffer {
+    // This frame with the pixels of the one before it kept lit, so a sprite that a ROM erases and
+    // redraws between frames is never caught half drawn. A resolution change leaves this frame as it is.
+    pub fn blend(&self, previous: &PixelBuffer) -> PixelBuffer {
+        let mut blended: PixelBuffer = PixelBuffer { ..*self };
+        if (previous.width, previous.height) != (self.width, self.height) {
+            return blended;
+        }
+        for y in 0..self.height as usize {
+            for x in 0..self.width as usize {
+                blended.pixels[y][x] |= previous.pixels[y][x];
+                blended.second_plane[y][x] |= previous.second_plane[y][x];
+            }
+        }
+        blended
+    }
+
+    // The frame as an SVG with one unit per pixel: a background rect, then a rect per lit pixel in its palette color.
+    // The width and height attributes give a default size, the viewBox lets it scale to any other.
+    pub fn to_svg(&self, palette: &Palette) -> String {
+        let hex = |[red, green, blue]: [u8; 3]| format!("#{:02X}{:02X}{:02X}", red, green, blue);
+        let (width, height): (usize, usize) = (self.width as usize, self.height as usize);
+        let mut svg: String = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\" shape-rendering=\"crispEdges\">\n",
+            width * SVG_PIXEL_SIZE,
+            height * SVG_PIXEL_SIZE,
+            width,
+            height
+        );
+        svg.push_str(&format!("<rect width=\"{}\" height=\"{}\" fill=\"{}\"/>\n", width, height, hex(palette.color(&[]))));
+        for y in 0..height {
+            for x in 0..width {
+                let plane_bits: [bool; 2] = [self.pixels[y][x], self.second_plane[y][x]];
+                if plane_bits.contains(&true) {
+                    let fill: String = hex(palette.color(&plane_bits));
+                    svg.push_str(&format!("<rect x=\"{}\" y=\"{}\" width=\"1\" height=\"1\" fill=\"{}\"/>\n", x, y, fill));
+                }
+            }
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+// Default size of an exported SVG pixel
+const SVG_PIXEL_SIZE: usize = 10;
+
+// Colors for every combination of plane bits, indexed with plane 0 in the lowest bit.
+// CHIP-8 has one plane (background, foreground), XO-CHIP has two (4 colors), and more
+// planes only need a longer color list.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Palette {
+    planes: u32,
+    colors: Vec<[u8; 3]>, // RGB
+}
+
+impl Palette {
+    pub fn new(planes: u32, colors: Vec<[u8; 3]>) -> Result<Self, String> {
+        let expected: usize = 1 << planes;
+        if colors.len() != expected {
+            return Err(format!("{} planes need {} colors, got {}", planes, expected, colors.len()));
+        }
+        Ok(Palette { planes, colors })
+    }
+
+    pub fn chip8() -> Self {
+        Palette { planes: 1, colors: vec![[10, 10, 10], [255, 255, 255]] }
+    }
+
+    pub fn xochip() -> Self {
+        Palette { planes: 2, colors: vec![[10, 10, 10], [255, 255, 255], [170, 170, 170], [85, 85, 85]] }
+    }
+
+    pub fn planes(&self) -> u32 {
+        self.planes
+    }
+
+    // Colors in palette index order
+    pub fn colors(&self) -> &[[u8; 3]] {
+        &self.colors
+    }
+
+    pub fn set_color(&mut self, index: usize, color: [u8; 3]) {
+        if let Some(slot) = self.colors.get_mut(index) {
+            *slot = color;
+        }
+    }
+
+    // Palettes people already know, by name
+    pub fn well_known() -> Vec<(&'static str, Palette)> {
+        vec![
+            ("Classic", Palette::chip8()),
+            ("Octo", Palette { planes: 1, colors: vec![[0x99, 0x66, 0x00], [0xFF, 0xCC, 0x00]] }),
+            ("Green phosphor", Palette { planes: 1, colors: vec![[0x0A, 0x1A, 0x0A], [0x33, 0xFF, 0x66]] }),
+            ("LCD", Palette { planes: 1, colors: vec![[0x9B, 0xBC, 0x0F], [0x0F, 0x38, 0x0F]] }),
+            ("XO-CHIP", Palette::xochip()),
+            (
+                "Octo XO-CHIP",
+                Palette { planes: 2, colors: vec![[0x99, 0x66, 0x00], [0xFF, 0xCC, 0x00], [0xFF, 0x66, 0x00], [0x66, 0x22, 0x00]] },
+            ),
+        ]
+    }
+
+    // One RRGGBB hex color per line in palette index order, the same as Lospec's .hex files
+    pub fn to_text(&self) -> String {
+        self.colors
+            .iter()
+            .map(|[red, green, blue]| format!("{:02X}{:02X}{:02X}\n", red, green, blue))
+            .collect()
+    }
+
+    // Read the to_text format. A leading # on a color and blank lines are allowed,
+    // and the number of colors decides the number of planes.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let colors: Vec<[u8; 3]> = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let hex: &str = line.strip_prefix('#').unwrap_or(line);
+                let rgb: u32 = u32::from_str_radix(hex, 16)
+                    .ok()
+                    .filter(|_| hex.len() == 6)
+                    .ok_or_else(|| format!("'{}' is not an RRGGBB color", line))?;
+                Ok([(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8])
+            })
+            .collect::<Result<Vec<[u8; 3]>, String>>()?;
+        if !colors.len().is_power_of_two() || colors.len() < 2 {
+            return Err(format!("A palette needs 2, 4, 8... colors, got {}", colors.len()));
+        }
+        Palette::new(colors.len().trailing_zeros(), colors)
+    }
+
+    pub fn to_file(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        std::fs::write(path, self.to_text())?;
+        Ok(())
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(Palette::parse(&std::fs::read_to_string(path)?)?)
+    }
+
+    // The color for a pixel, from the bit it has set in each plane
+    pub fn color(&self, plane_bits: &[bool]) -> [u8; 3] {
+        let index: usize = plane_bits
+            .iter()
+            .take(self.planes as usize)
+            .enumerate()
+            .fold(0, |index, (plane, &bit)| index | ((bit as usize) << plane));
+        self.colors[index]
+    }
+}
+
+pub struct Display {
+    pub width: u32, // The live resolution, see set_hires
+    pub height: u32,
+    pub pixels: Plane, // Plane 0, the only one before XO-CHIP
+    pub second_plane: Plane, // XO-CHIP's plane 1
+    pub selected_planes: u8, // Bit N selects plane N for drawing, clearing and scrolling, see FN01
     pub pixel_buffer_sender: tokio::sync::mpsc::Sender<PixelBuffer>,
+    // With double buffering, pixels is the back buffer and the GUI's copy is the front buffer.
+    // Draws and clears only change pixels, and vblank() sends a finished frame at most once per
+    // 60 Hz frame. A sprite drawn mid-frame is not visible until then.
+    pub double_buffered: bool,
+    // Whether sprites that run off the right or bottom edge wrap to the other side or are clipped
+    pub wrap_x: bool,
+    pub wrap_y: bool,
+    dirty: bool, // pixels changed since the last frame sent at vblank
 }
 
 impl Display {
@@ -18,45 +210,311 @@ impl Display {
         Display {
             width,
             height,
-            pixels: [[false; WIDTH as usize]; HEIGHT as usize],
+            pixels: [[false; MAX_WIDTH]; MAX_HEIGHT],
+            second_plane: [[false; MAX_WIDTH]; MAX_HEIGHT],
+            selected_planes: 1,
             pixel_buffer_sender,
+            double_buffered: false,
+            wrap_x: true,
+            wrap_y: true,
+            dirty: false,
         }
     }
+    // Clear every plane
     pub fn clear(&mut self) {
-        for row in self.pixels.iter_mut() {
-            for pixel in row.iter_mut() {
-                *pixel = false;
+        for plane in [&mut self.pixels, &mut self.second_plane] {
+            *plane = [[false; MAX_WIDTH]; MAX_HEIGHT];
+        }
+        self.dirty = true;
+    }
+
+    // Clear the selected planes only, for 00E0
+    pub fn clear_selected(&mut self) {
+        for plane in 0..2 {
+            if let Some(plane) = self.selected_plane_mut(plane) {
+                *plane = [[false; MAX_WIDTH]; MAX_HEIGHT];
             }
         }
+        self.dirty = true;
+    }
+
+    // Plane number `plane`, if it is selected
+    fn selected_plane_mut(&mut self, plane: usize) -> Option<&mut Plane> {
+        if self.selected_planes & (1 << plane) == 0 {
+            return None;
+        }
+        match plane {
+            0 => Some(&mut self.pixels),
+            1 => Some(&mut self.second_plane),
+            _ => None,
+        }
+    }
+
+    // Switch between SUPER-CHIP's 128x64 high resolution mode and the 64x32 low resolution one.
+    // The pixels are left alone, 00FE and 00FF clear them as well.
+    pub fn set_hires(&mut self, hires: bool) {
+        (self.width, self.height) = if hires {
+            (MAX_WIDTH as u32, MAX_HEIGHT as u32)
+        } else {
+            (LORES_WIDTH, LORES_HEIGHT)
+        };
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.width == MAX_WIDTH as u32
     }
 
+    // Draw an n row sprite into each selected plane. With both planes selected, sprite holds the
+    // rows for plane 0 followed by the rows for plane 1. Returns whether any plane had a pixel erased.
     pub fn draw_sprite(&mut self, x: usize, y: usize, n: usize, sprite: &[u8]) -> bool {
-        let mut pixel_erased = false;
-        for row in 0..n {
-            if row > self.height as usize {
-                break;
+        self.draw_sprite_planes(x, y, n.max(1), 1, sprite)
+    }
+
+    // Draw a 16x16 sprite (DXY0 in high resolution), two bytes per row and 32 bytes per plane
+    pub fn draw_large_sprite(&mut self, x: usize, y: usize, sprite: &[u8]) -> bool {
+        self.draw_sprite_planes(x, y, 32, 2, sprite)
+    }
+
+    fn draw_sprite_planes(&mut self, x: usize, y: usize, plane_len: usize, row_bytes: usize, sprite: &[u8]) -> bool {
+        let mut pixel_erased: bool = false;
+        let mut rows: std::slice::Chunks<'_, u8> = sprite.chunks(plane_len);
+        for plane in 0..2 {
+            if self.selected_planes & (1 << plane) == 0 {
+                continue;
             }
-            // Get the nth byte of sprite data counting from the memory address in the I register
-            let sprite_byte: u8 = sprite[row];
+            let plane_rows: &[u8] = rows.next().unwrap_or(&[]);
+            pixel_erased |= self.draw_sprite_on_plane(plane, x, y, row_bytes, plane_rows);
+        }
+        self.show_changes();
+        pixel_erased
+    }
 
-            for col in 0..8 {
+    fn draw_sprite_on_plane(&mut self, plane: usize, x: usize, y: usize, row_bytes: usize, sprite: &[u8]) -> bool {
+        let (width, height): (usize, usize) = (self.width as usize, self.height as usize);
+        let (wrap_x, wrap_y): (bool, bool) = (self.wrap_x, self.wrap_y);
+        let Some(pixels) = self.selected_plane_mut(plane) else {
+            return false;
+        };
+        let mut pixel_erased = false;
+        for (byte_index, &sprite_byte) in sprite.iter().enumerate() {
+            // sprite_byte is the nth byte of sprite data counting from the memory address in the I register.
+            // Wide sprites have several bytes side by side on each row.
+            let row: usize = byte_index / row_bytes;
+            let first_col: usize = (byte_index % row_bytes) * 8;
+
+            for bit in 0..8 {
+                let col: usize = first_col + bit;
                 // Check if the pixel is set at that col in the sprite byte
-                let pixel: bool = ((sprite_byte >> (7 - col)) & 0x01) == 1;
-                if col > self.width as usize {
-                    break;
+                let pixel: bool = ((sprite_byte >> (7 - bit)) & 0x01) == 1;
+                // Sprites are XORed onto the existing screen. x and y are already on screen, so only
+                // the part past the right or bottom edge wraps or is clipped.
+                if (!wrap_x && x + col >= width) || (!wrap_y && y + row >= height) {
+                    continue;
                 }
-                // Sprites are XORed onto the existing screen.
-                let display_x: usize = (x + col) % self.width as usize;
-                let display_y: usize = (y + row) % self.height as usize;
+                let display_x: usize = (x + col) % width;
+                let display_y: usize = (y + row) % height;
                 // If this causes any pixels to be erased, VF is set to 1, otherwise it is set to 0.
-                if self.pixels[display_y][display_x] && pixel {
+                if pixels[display_y][display_x] && pixel {
                     pixel_erased = true;
                 }
                 // XOR the pixel
-                self.pixels[display_y][display_x] ^= pixel;
+                pixels[display_y][display_x] ^= pixel;
             }
         }
-        self.pixel_buffer_sender.blocking_send(PixelBuffer { pixels: self.pixels }).unwrap();
         pixel_erased
     }
+
+    // Move the selected planes dx pixels right and dy pixels down, negative to go the other way.
+    // Pixels moved off an edge are lost and the uncovered ones are cleared.
+    pub fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height): (isize, isize) = (self.width as isize, self.height as isize);
+        for plane in 0..2 {
+            let Some(pixels) = self.selected_plane_mut(plane) else {
+                continue;
+            };
+            let old: Plane = *pixels;
+            for y in 0..height {
+                for x in 0..width {
+                    let (from_x, from_y): (isize, isize) = (x - dx, y - dy);
+                    let inside: bool = (0..width).contains(&from_x) && (0..height).contains(&from_y);
+                    pixels[y as usize][x as usize] = inside && old[from_y as usize][from_x as usize];
+                }
+            }
+        }
+        self.show_changes();
+    }
+
+    // Send the frame now, or mark it for the next vblank when double buffered
+    fn show_changes(&mut self) {
+        if self.double_buffered {
+            self.dirty = true;
+        } else {
+            self.send_frame();
+        }
+    }
+
+    // The end of a 60 Hz frame. With double buffering, swap the finished frame to the GUI.
+    pub fn vblank(&mut self) {
+        if self.dirty {
+            self.dirty = false;
+            self.send_frame();
+        }
+    }
+
+    // Push the current pixels to the GUI. Once the window has closed nothing is listening, and the
+    // frame is dropped while the emulator thread winds down.
+    pub fn send_frame(&self) {
+        let _ = self.pixel_buffer_sender.blocking_send(PixelBuffer {
+            width: self.width,
+            height: self.height,
+            pixels: self.pixels,
+            second_plane: self.second_plane,
+        });
+    }
+
+    // Render the display as ASCII art for terminal output.
+    // The frame starts with ANSI codes that move the cursor home and clear the screen, so printing
+    // one frame after another redraws in place instead of scrolling.
+    pub fn to_ascii(&self) -> String {
+        let mut frame: String = String::from("\x1B[H\x1B[2J");
+        // Only the live resolution is printed
+        for row in self.pixels.iter().take(self.height as usize) {
+            for &pixel in row.iter().take(self.width as usize) {
+                frame.push(if pixel { '#' } else { '.' });
+            }
+            frame.push('\n');
+        }
+        frame
+    }
+
+    // Plane 0 at the live resolution as a grayscale image, white for a lit pixel and black for an unlit
+    // one, for saving as a PNG
+    pub fn to_image(&self) -> image::GrayImage {
+        image::GrayImage::from_fn(self.width, self.height, |x, y| {
+            image::Luma([if self.pixels[y as usize][x as usize] { 255 } else { 0 }])
+        })
+    }
+
+    // Pack the display into a raw monochrome frame for piping to other tools.
+    // Layout: width and height as little endian u16s, then each row top to bottom with
+    // 8 pixels per byte, leftmost pixel in the most significant bit, 1 = lit.
+    // Every frame at a given resolution is the same size (4 + width / 8 * height bytes).
+    pub fn to_raw_frame(&self) -> Vec<u8> {
+        let bytes_per_row: usize = (self.width as usize).div_ceil(8);
+        let mut frame: Vec<u8> = Vec::with_capacity(4 + bytes_per_row * self.height as usize);
+        frame.extend_from_slice(&(self.width as u16).to_le_bytes());
+        frame.extend_from_slice(&(self.height as u16).to_le_bytes());
+        for row in self.pixels.iter().take(self.height as usize) {
+            for chunk in row[..self.width as usize].chunks(8) {
+                let byte: u8 = chunk
+                    .iter()
+                    .enumerate()
+                    .fold(0, |byte, (bit, &pixel)| byte | ((pixel as u8) << (7 - bit)));
+                frame.push(byte);
+            }
+        }
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 64x32 display with nothing receiving its frames
+    fn display() -> Display {
+        let (pixel_buffer_sender, _) = tokio::sync::mpsc::channel(1);
+        Display::new(LORES_WIDTH, LORES_HEIGHT, pixel_buffer_sender)
+    }
+
+    // The lit pixels of plane 0 as (x, y), row by row
+    fn lit(display: &Display) -> Vec<(usize, usize)> {
+        (0..display.height as usize)
+            .flat_map(|y| (0..display.width as usize).map(move |x| (x, y)))
+            .filter(|&(x, y)| display.pixels[y][x])
+            .collect()
+    }
+
+    #[test]
+    fn blending_keeps_the_previous_frame_lit() {
+        let mut previous: PixelBuffer = PixelBuffer::default();
+        previous.pixels[1][2] = true;
+        previous.second_plane[3][4] = true;
+        let mut current: PixelBuffer = PixelBuffer::default();
+        current.pixels[5][6] = true;
+        let blended: PixelBuffer = current.blend(&previous);
+        assert!(blended.pixels[1][2] && blended.second_plane[3][4] && blended.pixels[5][6]);
+        // After a resolution change the old frame is from another screen and is left out
+        current.width = MAX_WIDTH as u32;
+        current.height = MAX_HEIGHT as u32;
+        let blended: PixelBuffer = current.blend(&previous);
+        assert!(!blended.pixels[1][2] && !blended.second_plane[3][4] && blended.pixels[5][6]);
+    }
+
+    #[test]
+    fn clipped_pixels_never_collide() {
+        // A 2x2 block at the bottom right corner only has its top left pixel on screen
+        let mut display: Display = display();
+        (display.wrap_x, display.wrap_y) = (false, false);
+        assert!(!display.draw_sprite(63, 31, 2, &[0xC0, 0xC0]));
+        assert_eq!(lit(&display), [(63, 31)]);
+        // Drawing it again erases that pixel, and the clipped ones don't set VF on their own
+        assert!(display.draw_sprite(63, 31, 2, &[0xC0, 0xC0]));
+        assert_eq!(lit(&display), []);
+        display.pixels[0][0] = true;
+        assert!(!display.draw_sprite(63, 31, 2, &[0xC0, 0xC0]));
+        assert_eq!(lit(&display), [(0, 0), (63, 31)]);
+    }
+
+    #[test]
+    fn clearing_keeps_unselected_planes() {
+        let mut display: Display = display();
+        display.pixels[1][2] = true;
+        display.second_plane[3][4] = true;
+        display.selected_planes = 2;
+        display.clear_selected();
+        assert_eq!(lit(&display), [(2, 1)]);
+        assert!(!display.second_plane[3][4]);
+
+        display.second_plane[3][4] = true;
+        display.selected_planes = 1;
+        display.clear_selected();
+        assert_eq!(lit(&display), []);
+        assert!(display.second_plane[3][4]);
+
+        // With no plane selected nothing is cleared, clear() always clears both
+        display.pixels[1][2] = true;
+        display.selected_planes = 0;
+        display.clear_selected();
+        assert_eq!(lit(&display), [(2, 1)]);
+        display.clear();
+        assert_eq!(lit(&display), []);
+        assert!(!display.second_plane[3][4]);
+    }
+
+    #[test]
+    fn each_axis_wraps_on_its_own() {
+        // A 2x2 block at the bottom right corner, crossing both edges
+        for (wrap_x, wrap_y, expected) in [
+            (true, true, vec![(0, 0), (63, 0), (0, 31), (63, 31)]),
+            (true, false, vec![(0, 31), (63, 31)]),
+            (false, true, vec![(63, 0), (63, 31)]),
+            (false, false, vec![(63, 31)]),
+        ] {
+            let mut display: Display = display();
+            (display.wrap_x, display.wrap_y) = (wrap_x, wrap_y);
+            display.draw_sprite(63, 31, 2, &[0xC0, 0xC0]);
+            assert_eq!(lit(&display), expected, "wrap_x {} wrap_y {}", wrap_x, wrap_y);
+        }
+    }
+
+    #[test]
+    fn frames_sent_after_the_gui_closes_are_dropped() {
+        let (pixel_buffer_sender, pixel_buffer_receiver) = tokio::sync::mpsc::channel(1);
+        let mut display: Display = Display::new(LORES_WIDTH, LORES_HEIGHT, pixel_buffer_sender);
+        drop(pixel_buffer_receiver);
+        display.send_frame();
+        assert!(!display.draw_sprite(0, 0, 1, &[0x80]));
+        assert_eq!(lit(&display), [(0, 0)]);
+    }
 }
@@ -1,11 +1,186 @@
-use crate::PixelBuffer;
-use crate::{cpu::CPU, display::Display, memory::Memory};
+use crate::display::{PixelBuffer, LORES_HEIGHT, LORES_WIDTH, MAX_HEIGHT, MAX_WIDTH};
+use crate::opcode_filter::OpcodeFilter;
+use crate::quirks::Quirks;
+use crate::replay::ReplayBundle;
+use crate::save_slots::SaveSlotManager;
+use crate::state::EmulatorState;
+use crate::trace::{TraceComparator, TraceEntry, TraceMismatch};
+use crate::{cpu::{CpuSnapshot, CpuView, CustomOpcodeHandler, PlatformMismatch, SpriteReadOverflow, CPU, FLAG_COUNT}, display::Display, memory::Memory};
+use std::collections::{HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 
 pub struct EmulatorData {
-    pub file_content: mpsc::Receiver<Vec<u8>>,
     pub font_file_content: mpsc::Receiver<Vec<u8>>,
-    pub keys: mpsc::Receiver<[bool; 16]>,
+    pub keys: Arc<AtomicU16>, // Keypad state written by the GUI, bit N is set while key N is held
+    pub commands: mpsc::Receiver<EmulatorCommand>,
+    pub events: mpsc::Sender<EmulatorEvent>,
+    pub cpu_state: mpsc::Sender<CpuView>, // Sent once per 60 Hz frame while running, and on a step or pause
+    pub opcode_counts: mpsc::Sender<OpcodeCounts>, // Times each opcode has run, sent while SetSendOpcodeCounts is on
+    pub recent_opcodes: mpsc::Sender<Vec<TraceEntry>>, // The last instructions run, sent while SetSendRecentOpcodes is on
+    pub cycles: Arc<AtomicU64>, // Copy of Emulator::cycles for the GUI
+    pub cpu_hz: u32, // Starting clock speed, see Emulator::set_clock_hz
+}
+
+// (opcode, times run) pairs
+pub type OpcodeCounts = Vec<(u16, u64)>;
+
+// Pack a keypad state into a bitmask, bit N for key N
+pub fn keys_to_bitmask(keys: &[bool; 16]) -> u16 {
+    keys.iter()
+        .enumerate()
+        .filter(|&(_, &pressed)| pressed)
+        .fold(0, |bitmask, (key, _)| bitmask | (1 << key))
+}
+
+pub fn keys_from_bitmask(bitmask: u16) -> [bool; 16] {
+    std::array::from_fn(|key| bitmask & (1 << key) != 0)
+}
+
+// A memory byte or V register that pauses the emulator when an instruction changes it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Watch {
+    Memory(u16),
+    Register(usize),
+}
+
+impl std::fmt::Display for Watch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Watch::Memory(address) => write!(f, "memory {:04X}", address),
+            Watch::Register(register) => write!(f, "V{:X}", register),
+        }
+    }
+}
+
+// Settings the GUI can change while the emulator is running
+pub enum EmulatorCommand {
+    LoadRom(Vec<u8>), // Cold reset and load, answered with RomLoaded or RomLoadFailed
+    SetClearDisplayOnReset(bool),
+    SetAllowRomTruncation(bool),
+    SetChip8E(bool),
+    SetSchip(bool),
+    SetXoChip(bool),
+    SetFx0aMinHoldFrames(u32),
+    SetFontCharSize(u8), // Stride between font characters for FX29
+    SetSpriteReadOverflow(SpriteReadOverflow),
+    SetTimerHz(u32),
+    ColdReset, // Reload the ROM into cleared RAM
+    WarmReset, // Restart the CPU at 0x200 with RAM as it is
+    SaveSlot(usize),
+    LoadSlot(usize),
+    SetPaused(bool),
+    SetReferenceTrace(Option<Vec<TraceEntry>>), // None stops comparing
+    SetOpcodeFilter(OpcodeFilter),
+    SetSendOpcodeCounts(bool),
+    SetSendRecentOpcodes(bool),
+    SetPauseAfterDraw(bool),
+    RunToNextDraw,
+    Step, // Run one instruction while paused, see Emulator::step
+    AddBreakpoint(u16),
+    RemoveBreakpoint(u16),
+    ClearBreakpoints,
+    AddWatch(Watch),
+    RemoveWatch(Watch),
+    ClearWatches,
+    SetSpinIdle(bool),
+    SetClockHz(u32), // Instructions per second, 0 for unlimited
+    SetRewindCapacity(usize), // Frames of rewind kept, 0 turns rewind off
+    Rewind, // Go back one frame, see Emulator::rewind_one
+    SetDoubleBuffered(bool), // Only send whole frames at vblank, see Display::double_buffered
+    SetQuirks(Quirks), // Takes effect from the next instruction
+    SetRamSize(usize), // The ROM has to be loaded again afterwards
+    SetWatchdogThreshold(Option<u64>), // None turns the watchdog off
+    SaveReplay(PathBuf), // Write a ReplayBundle of the run since the ROM was loaded
+    StartReplay(Box<ReplayBundle>), // Load the bundle's ROM and settings and play its inputs instead of the GUI's
+    PlayInputScript(Vec<[bool; 16]>), // Keypad states from input_script::parse, see Emulator::play_input_script
+}
+
+// Notifications the emulator sends back to the GUI
+pub enum EmulatorEvent {
+    RomLoaded { size: usize },
+    RomLoadFailed { message: String },
+    RomTruncated { truncated: usize },
+    SlotSaved(usize),
+    SlotLoaded(usize),
+    SlotError { slot: usize, message: String },
+    TraceMismatch(TraceMismatch), // The emulator pauses on the mismatching step
+    TraceMatched { steps: usize },
+    OpcodeDenied { pc: u16, opcode: u16 }, // The emulator pauses before running it
+    FirstDraw { cycle: u64 }, // The first DXYN or 00E0 since the ROM was loaded ran on this cycle
+    Log { level: LogLevel, message: String }, // Messages that are only interesting in the log
+    PausedAfterDraw { pc: u16 }, // The draw at pc ran with pause_after_draw or run_to_next_draw
+    BreakpointHit { pc: u16 }, // The emulator pauses before running the instruction at pc
+    WatchTriggered { pc: u16, watch: Watch, old: u8, new: u8 }, // The instruction at pc changed a watch, the emulator pauses
+    PossibleSoftLock { instructions: u64 }, // This many instructions ran without drawing or reading keys
+    ReplaySaved(PathBuf),
+    PlatformMismatch(PlatformMismatch), // Once per extension instruction family per ROM load
+    ReplayFinished, // The keypad is back to the GUI
+    InputScriptFinished, // The keypad is back to the GUI
+    ProgramEnded { pc: u16 }, // The ROM ran 00FD at pc, nothing more runs until a reset
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogLevel {
+    Info,
+    Warning,
+    Error,
+}
+
+impl EmulatorEvent {
+    pub fn level(&self) -> LogLevel {
+        match self {
+            EmulatorEvent::Log { level, .. } => *level,
+            EmulatorEvent::SlotError { .. } | EmulatorEvent::TraceMismatch(_) | EmulatorEvent::RomLoadFailed { .. } => {
+                LogLevel::Error
+            }
+            EmulatorEvent::RomTruncated { .. }
+            | EmulatorEvent::OpcodeDenied { .. }
+            | EmulatorEvent::PossibleSoftLock { .. }
+            | EmulatorEvent::PlatformMismatch(_) => LogLevel::Warning,
+            _ => LogLevel::Info,
+        }
+    }
+}
+
+impl std::fmt::Display for EmulatorEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmulatorEvent::RomLoaded { size } => write!(f, "ROM loaded, {} bytes", size),
+            EmulatorEvent::RomLoadFailed { message } => write!(f, "Could not load ROM: {}", message),
+            EmulatorEvent::RomTruncated { truncated } => {
+                write!(f, "ROM too large for memory, {} bytes were truncated", truncated)
+            }
+            EmulatorEvent::SlotSaved(slot) => write!(f, "Saved state to slot {}", slot),
+            EmulatorEvent::SlotLoaded(slot) => write!(f, "Loaded state from slot {}", slot),
+            EmulatorEvent::SlotError { slot, message } => write!(f, "Save slot {}: {}", slot, message),
+            EmulatorEvent::TraceMismatch(mismatch) => write!(f, "{}", mismatch),
+            EmulatorEvent::TraceMatched { steps } => write!(f, "All {} steps matched the reference trace", steps),
+            EmulatorEvent::OpcodeDenied { pc, opcode } => write!(f, "Paused on opcode {:04X} at {:04X}, it is not allowed", opcode, pc),
+            EmulatorEvent::FirstDraw { cycle } => write!(f, "First draw at cycle {}", cycle),
+            EmulatorEvent::Log { message, .. } => write!(f, "{}", message),
+            EmulatorEvent::PausedAfterDraw { pc } => write!(f, "Paused after the draw at {:04X}", pc),
+            EmulatorEvent::BreakpointHit { pc } => write!(f, "Paused at the breakpoint at {:04X}", pc),
+            EmulatorEvent::WatchTriggered { pc, watch, old, new } => {
+                write!(f, "Paused after {:04X} changed {} from {:02X} to {:02X}", pc, watch, old, new)
+            }
+            EmulatorEvent::PossibleSoftLock { instructions } => {
+                write!(f, "{} instructions without drawing or reading keys, the ROM may be stuck", instructions)
+            }
+            EmulatorEvent::PlatformMismatch(mismatch) => write!(
+                f,
+                "{:04X} at {:04X} is the {} instruction {}, which the current settings don't run",
+                mismatch.opcode, mismatch.pc, mismatch.platform, mismatch.family
+            ),
+            EmulatorEvent::ReplaySaved(path) => write!(f, "Saved replay to {}", path.display()),
+            EmulatorEvent::ReplayFinished => write!(f, "Replay finished"),
+            EmulatorEvent::InputScriptFinished => write!(f, "Input script finished"),
+            EmulatorEvent::ProgramEnded { pc } => write!(f, "Program ended at {:04X}", pc),
+        }
+    }
 }
 
 pub struct Emulator {
@@ -15,21 +190,86 @@ pub struct Emulator {
     pub keys: [bool; 16], // Keypad state
     pub emulator_data: EmulatorData,
     is_rom_loaded: bool,
+    initial_state: Option<CpuSnapshot>, // Applied to the CPU after every ROM load
+    pub clear_display_on_reset: bool, // When false, the last frame stays on the display after a reset
+    pub allow_rom_truncation: bool, // When true, oversized ROMs are cut to fit instead of rejected
+    pub cycles: u64, // Instructions executed since the ROM was loaded
+    pub frames: u64, // 60 Hz frames of emulated time since the ROM was loaded, see advance_clock
+    timer_phase: u32, // Progress towards the next timer tick, in timer_hz steps out of the instruction rate
+    frame_phase: u32, // Progress towards the next frame, in 60 Hz steps out of the instruction rate
+    save_slots: Option<SaveSlotManager>, // Slots for the loaded ROM
+    paused: bool, // When true, cycle() handles commands and ROM loads but runs no instructions and the timers stop
+    trace_comparator: Option<TraceComparator>, // Reference trace the run is checked against
+    recent_opcodes: VecDeque<TraceEntry>, // The last RECENT_OPCODES instructions, oldest first
+    pub opcode_filter: OpcodeFilter, // Checked before each instruction runs
+    opcode_counts: Vec<u64>, // Times each opcode has run since the ROM was loaded, indexed by opcode
+    send_opcode_counts: bool,
+    opcode_counts_sent_at: Instant,
+    send_recent_opcodes: bool,
+    recent_opcodes_sent_at: Instant,
+    cpu_state_sent_at: Instant,
+    has_drawn: bool, // A DXYN or 00E0 has run since the ROM was loaded
+    pub pause_after_draw: bool, // Pause after every DXYN, to watch sprites go down one at a time
+    running_to_draw: bool, // Pause after the next DXYN or 00E0, see run_to_next_draw
+    stepping: bool, // Run one instruction on the next cycle even though paused, see step
+    breakpoints: HashSet<u16>, // Addresses to pause at before running the instruction there
+    breakpoint_hit: Option<u16>, // The breakpoint just paused at, which lets the instruction there run on resume
+    watches: HashSet<Watch>, // Checked around every instruction, see watch_values
+    pub spin_idle: bool, // Sleep instead of decoding a jump to itself, see cycle()
+    pub persist_flags: bool, // Keep the SUPER-CHIP flag registers in the ROM's save directory between runs
+    pub watchdog_threshold: Option<u64>, // Warn after this many instructions without drawing or reading keys
+    rom: Vec<u8>, // The ROM as loaded, for replays
+    font: Vec<u8>, // The font as last set, written back to RAM after every cold reset
+    rng_seed: u64, // CXNN seed for this run, picked when the ROM is loaded
+    input_log: Vec<(u64, u16)>, // (cycle, keypad bitmask) for each keypad change since the ROM was loaded
+    replay_inputs: Option<VecDeque<(u64, u16)>>, // Keypad changes still to play, these replace the GUI's keys
+    input_script: Option<VecDeque<[bool; 16]>>, // Keypad states still to play, one per frame, these replace the GUI's keys
+    instructions_since_activity: u64, // Instructions since the last draw or key read, for the watchdog
+    next_vblank: Instant, // When the double buffered display next sends a frame
+    display_waiting: bool, // A DXYN ran with the display_wait quirk on, nothing runs until the next frame
+    clock_hz: u32, // Instructions per second, 0 runs them as fast as the host can
+    rewind_capacity: usize, // Most states kept in rewind_states, 0 when rewind is off
+    rewind_states: VecDeque<EmulatorState>, // One state per 60 Hz frame of running, oldest first
+    next_rewind_state: Instant, // When the next state is due
+    next_instruction: Instant, // When the next instruction is due at clock_hz
 }
 
-const SCREEN_WIDTH: u32 = 64;
-const SCREEN_HEIGHT: u32 = 32;
-
 pub const FONT_ADDRESS: u16 = 0x050; // Address where fonts are stored in memory
+pub const FONT_BIG_ADDRESS: u16 = 0x0A0; // The SUPER-CHIP 8x10 font for FX30, right after the 80-byte small font
 pub const ROM_ADDRESS: u16 = 0x200; // Address where ROM is loaded in memory
 
-const _CPU_FREQUENCY: u64 = 500; // CPU frequency in Hz
+// Instructions kept for crash reports and the GUI's opcode timeline
+const RECENT_OPCODES: usize = 256;
+
+// How often opcode counts go to the GUI
+const OPCODE_COUNTS_INTERVAL: Duration = Duration::from_millis(250);
+
+// How often recent opcodes go to the GUI, often enough for the timeline to scroll smoothly
+const RECENT_OPCODES_INTERVAL: Duration = Duration::from_millis(100);
+
+// How long a spin_idle sleep lasts, one 60 Hz frame
+const SPIN_IDLE_SLEEP: Duration = Duration::from_millis(1000 / 60);
+
+// Time between double buffered frames
+const VBLANK_INTERVAL: Duration = Duration::from_millis(1000 / 60);
+
+pub const DEFAULT_CPU_HZ: u32 = 500; // CPU frequency in Hz
+
+// How far the clock may fall behind before it stops catching up, so a stall isn't followed by a burst
+const MAX_CLOCK_LAG: Duration = Duration::from_millis(50);
+
+// How long cycle() sleeps when there's nothing to run and the clock is limited
+const IDLE_SLEEP: Duration = Duration::from_millis(1);
 
 impl Emulator {
-    pub fn new(emulator_data: EmulatorData, pixel_buffer_sender: mpsc::Sender<PixelBuffer>) -> Self {
+    pub fn new(emulator_data: EmulatorData, pixel_buffer_sender: mpsc::Sender<PixelBuffer>, quirks: Quirks) -> Self {
         let memory: Memory = Memory::new();
-        let display: Display = Display::new(SCREEN_WIDTH, SCREEN_HEIGHT, pixel_buffer_sender);
-        let cpu: CPU = CPU::new(ROM_ADDRESS as u16);
+        let mut display: Display = Display::new(LORES_WIDTH, LORES_HEIGHT, pixel_buffer_sender);
+        // The CPU applies the rest of the quirks itself
+        display.wrap_x = quirks.wrap_x;
+        display.wrap_y = quirks.wrap_y;
+        let cpu: CPU = CPU::new(ROM_ADDRESS, quirks);
+        let clock_hz: u32 = emulator_data.cpu_hz;
 
         Emulator {
             cpu,
@@ -38,43 +278,497 @@ impl Emulator {
             keys: [false; 16],
             emulator_data,
             is_rom_loaded: false,
+            initial_state: None,
+            clear_display_on_reset: true,
+            allow_rom_truncation: false,
+            cycles: 0,
+            frames: 0,
+            timer_phase: 0,
+            frame_phase: 0,
+            save_slots: None,
+            paused: false,
+            trace_comparator: None,
+            recent_opcodes: VecDeque::with_capacity(RECENT_OPCODES),
+            opcode_filter: OpcodeFilter::AllowAll,
+            opcode_counts: vec![0; 0x10000],
+            send_opcode_counts: false,
+            opcode_counts_sent_at: Instant::now(),
+            send_recent_opcodes: false,
+            recent_opcodes_sent_at: Instant::now(),
+            cpu_state_sent_at: Instant::now(),
+            has_drawn: false,
+            pause_after_draw: false,
+            running_to_draw: false,
+            stepping: false,
+            breakpoints: HashSet::new(),
+            breakpoint_hit: None,
+            watches: HashSet::new(),
+            spin_idle: false,
+            persist_flags: false,
+            watchdog_threshold: None,
+            instructions_since_activity: 0,
+            next_vblank: Instant::now(),
+            display_waiting: false,
+            rom: Vec::new(),
+            font: Vec::new(),
+            rng_seed: 0,
+            input_log: Vec::new(),
+            replay_inputs: None,
+            input_script: None,
+            clock_hz,
+            next_instruction: Instant::now(),
+            rewind_capacity: 0,
+            rewind_states: VecDeque::new(),
+            next_rewind_state: Instant::now(),
         }
     }
 
+    // Cold reset: a new CPU, cleared RAM apart from the font, display and keys, and no ROM. load_rom
+    // does this before loading. Settings (quirks, CHIP-8E, timer rate and the rest) are kept.
     pub fn reset(&mut self) {
-        self.cpu = CPU::new(ROM_ADDRESS as u16);
+        self.reset_cpu();
         self.memory.clear();
+        self.write_font();
+        self.display.set_hires(false);
+        self.display.selected_planes = 1;
+        // States from before the reset may not even fit the RAM any more
+        self.rewind_states.clear();
+        if self.clear_display_on_reset {
+            self.display.clear();
+        }
+        self.is_rom_loaded = false;
+    }
+
+    // Warm reset: the CPU (registers, PC, stack and timers), display and keys start over, but RAM keeps
+    // its contents, including anything the ROM wrote over itself. Settings are kept.
+    pub fn warm_reset(&mut self) {
+        self.reset_cpu();
+        self.cpu.seed_rng(self.rng_seed);
+        if let Some(initial_state) = self.initial_state {
+            self.cpu.restore(&initial_state);
+        }
+        self.display.set_hires(false);
+        self.display.selected_planes = 1;
         self.display.clear();
+        self.display.send_frame();
+    }
+
+    // Cold reset, then load the same ROM again
+    pub fn cold_reset(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.is_rom_loaded {
+            return Err(Box::from("No ROM loaded"));
+        }
+        let rom: Vec<u8> = self.rom.clone();
+        self.load_rom(&rom)
+    }
+
+    // The parts of a reset both kinds share
+    fn reset_cpu(&mut self) {
+        let chip8e: bool = self.cpu.chip8e;
+        let schip: bool = self.cpu.schip;
+        let xochip: bool = self.cpu.xochip;
+        let quirks: Quirks = self.cpu.quirks;
+        let sprite_read_overflow: SpriteReadOverflow = self.cpu.sprite_read_overflow;
+        let timer_hz: u32 = self.cpu.timer_hz();
+        let fx0a_min_hold_frames: u32 = self.cpu.fx0a_min_hold_frames;
+        let font_char_size: u8 = self.cpu.font_char_size;
+        let flags: [u8; FLAG_COUNT] = self.cpu.flags;
+        let custom_handler: Option<Box<CustomOpcodeHandler>> = self.cpu.custom_handler.take();
+        self.cpu = CPU::new(ROM_ADDRESS, quirks);
+        self.cpu.custom_handler = custom_handler;
+        self.cpu.chip8e = chip8e;
+        self.cpu.schip = schip;
+        self.cpu.xochip = xochip;
+        self.cpu.sprite_read_overflow = sprite_read_overflow;
+        self.cpu.fx0a_min_hold_frames = fx0a_min_hold_frames;
+        self.cpu.font_char_size = font_char_size;
+        self.cpu.flags = flags;
+        self.cpu.set_timer_hz(timer_hz);
         self.keys = [false; 16];
-        self.is_rom_loaded = false;
+        self.cycles = 0;
+        self.frames = 0;
+        self.timer_phase = 0;
+        self.frame_phase = 0;
+        self.emulator_data.cycles.store(0, Ordering::Relaxed);
+        self.recent_opcodes.clear();
+        self.opcode_counts.fill(0);
+        self.has_drawn = false;
+        self.display_waiting = false;
+        self.instructions_since_activity = 0;
+        if let Some(trace_comparator) = self.trace_comparator.as_mut() {
+            trace_comparator.restart();
+        }
     }
 
-    pub fn set_font(&mut self, font: [u8; 80]) {
-        for (i, &byte) in font.iter().enumerate() {
+    // Fonts with a larger font_char_size are longer than 80 bytes and cover the big font. Anything that would
+    // run into the ROM is dropped, and a font too short for 16 characters leaves the rest of them blank.
+    // Both are logged.
+    pub fn set_font(&mut self, font: &[u8]) {
+        let available: usize = (ROM_ADDRESS - FONT_ADDRESS) as usize;
+        let needed: usize = 16 * self.cpu.font_char_size as usize;
+        if font.len() > available {
+            let message: String = format!("Font is {} bytes, only the first {} fit below the ROM", font.len(), available);
+            self.log(LogLevel::Warning, message);
+        } else if font.len() < needed {
+            let message: String = format!(
+                "Font is {} bytes, 16 characters of {} bytes need {}. The missing characters are blank.",
+                font.len(),
+                self.cpu.font_char_size,
+                needed
+            );
+            self.log(LogLevel::Warning, message);
+        }
+        self.font = font.iter().take(available).copied().collect();
+        self.write_font();
+    }
+
+    // Fill everything from FONT_ADDRESS up to the ROM, so a shorter font doesn't leave a longer one's tail behind.
+    // The big font goes in first, a small font longer than 80 bytes writes over it.
+    fn write_font(&mut self) {
+        for address in FONT_ADDRESS..ROM_ADDRESS {
+            self.memory.data[address as usize] = 0;
+        }
+        for (i, byte) in Emulator::get_default_big_font().into_iter().enumerate() {
+            self.memory.data[FONT_BIG_ADDRESS as usize + i] = byte;
+        }
+        for (i, &byte) in self.font.iter().enumerate() {
             self.memory.data[FONT_ADDRESS as usize + i] = byte;
         }
     }
 
     pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
         self.is_rom_loaded = false;
-        if rom.len() + ROM_ADDRESS as usize > self.memory.data.len() {
-            // Return an error
-            println!("ROM size exceeds available memory");
-
-            return Err(Box::from("ROM size exceeds available memory"));
+        let mut rom: &[u8] = rom;
+        let available: usize = self.memory.data.len() - ROM_ADDRESS as usize;
+        if rom.len() > available {
+            if !self.allow_rom_truncation {
+                return Err(Box::from("ROM size exceeds available memory"));
+            }
+            // Load as much as fits and warn about the rest
+            let truncated: usize = rom.len() - available;
+            let _ = self.emulator_data.events.try_send(EmulatorEvent::RomTruncated { truncated });
+            rom = &rom[..available];
         }
 
         self.reset();
+        // Every load gets a fresh seed, start_replay puts the recorded one back
+        self.rng_seed = rand::random();
+        self.cpu.seed_rng(self.rng_seed);
+        self.rom = rom.to_vec();
+        self.input_log.clear();
+        self.replay_inputs = None;
 
         for (i, &byte) in rom.iter().enumerate() {
             self.memory.data[ROM_ADDRESS as usize + i] = byte; // Load ROM starting at 0x200
         }
-        println!("ROM loaded successfully, size: {} bytes", rom.len());
+        if let Some(initial_state) = self.initial_state {
+            self.cpu.restore(&initial_state);
+        }
+        let _ = self.emulator_data.events.try_send(EmulatorEvent::RomLoaded { size: rom.len() });
+        let save_slots: SaveSlotManager = SaveSlotManager::new(rom);
+        // Flags belong to a ROM, another ROM starts with its own or none
+        self.cpu.flags = if self.persist_flags { save_slots.load_flags().unwrap_or_default() } else { [0; FLAG_COUNT] };
+        self.save_slots = Some(save_slots);
+        self.is_rom_loaded = true;
+        Ok(())
+    }
+
+    // Everything needed to run the current ROM again with the same inputs, or None before a ROM is loaded
+    pub fn replay_bundle(&self) -> Option<ReplayBundle> {
+        if !self.is_rom_loaded {
+            return None;
+        }
+        Some(ReplayBundle {
+            rom: self.rom.clone(),
+            seed: self.rng_seed,
+            chip8e: self.cpu.chip8e,
+            schip: self.cpu.schip,
+            xochip: self.cpu.xochip,
+            quirks: self.cpu.quirks,
+            clock_hz: self.clock_hz,
+            timer_hz: self.cpu.timer_hz(),
+            inputs: self.input_log.clone(),
+        })
+    }
+
+    // Load the bundle's ROM with its settings and seed, then play its inputs from cycle 0
+    pub fn start_replay(&mut self, bundle: ReplayBundle) -> Result<(), Box<dyn std::error::Error>> {
+        self.cpu.chip8e = bundle.chip8e;
+        self.cpu.schip = bundle.schip;
+        self.cpu.xochip = bundle.xochip;
+        self.cpu.quirks = bundle.quirks;
+        self.display.wrap_x = bundle.quirks.wrap_x;
+        self.display.wrap_y = bundle.quirks.wrap_y;
+        self.cpu.set_timer_hz(bundle.timer_hz);
+        self.set_clock_hz(bundle.clock_hz);
+        self.load_rom(&bundle.rom)?;
+        self.rng_seed = bundle.seed;
+        self.cpu.seed_rng(bundle.seed);
+        self.replay_inputs = Some(bundle.inputs.into());
+        self.input_script = None;
+        self.log(LogLevel::Info, "Replay started".to_string());
+        Ok(())
+    }
+
+    // Hold each keypad state for one 60 Hz frame of emulated time in place of the GUI's keys, so a
+    // script plays the same at any clock speed and waits while the emulator is paused. The keys
+    // go back to the GUI after the last frame.
+    pub fn play_input_script(&mut self, frames: Vec<[bool; 16]>) {
+        self.input_script = Some(frames.into());
+    }
+
+    // Swap in RAM of a different size, 4 KB for CHIP-8 or up to 64 KB for XO-CHIP.
+    // This resets the machine, so the ROM has to be loaded again.
+    pub fn set_ram_size(&mut self, size: usize) -> Result<(), Box<dyn std::error::Error>> {
+        self.memory = Memory::with_size(size)?;
+        self.reset();
+        self.save_slots = None;
+        Ok(())
+    }
+
+    pub fn save_state(&self) -> EmulatorState {
+        EmulatorState {
+            cpu: self.cpu.snapshot(),
+            delay_timer: self.cpu.delay_timer,
+            sound_timer: self.cpu.sound_timer,
+            memory: self.memory.data.to_vec(),
+            display_width: self.display.width,
+            display_height: self.display.height,
+            pixels: self
+                .display
+                .pixels
+                .iter()
+                .take(self.display.height as usize)
+                .flat_map(|row| row[..self.display.width as usize].iter().copied())
+                .collect(),
+            selected_planes: self.display.selected_planes,
+            second_plane: self
+                .display
+                .second_plane
+                .iter()
+                .take(self.display.height as usize)
+                .flat_map(|row| row[..self.display.width as usize].iter().copied())
+                .collect(),
+        }
+    }
+
+    pub fn load_state(&mut self, state: &EmulatorState) -> Result<(), Box<dyn std::error::Error>> {
+        if state.memory.len() != self.memory.data.len() {
+            return Err(Box::from("Save state memory size does not match"));
+        }
+        let hires: bool = (state.display_width, state.display_height) == (MAX_WIDTH as u32, MAX_HEIGHT as u32);
+        if !hires && (state.display_width, state.display_height) != (LORES_WIDTH, LORES_HEIGHT) {
+            return Err(Box::from("Save state display size is not supported"));
+        }
+
+        self.cpu.restore(&state.cpu);
+        self.cpu.delay_timer = state.delay_timer;
+        self.cpu.sound_timer = state.sound_timer;
+        self.memory.data.copy_from_slice(&state.memory);
+        self.display.set_hires(hires);
+        self.display.clear();
+        for (row, pixels) in self.display.pixels.iter_mut().zip(state.pixels.chunks(state.display_width as usize)) {
+            row[..pixels.len()].copy_from_slice(pixels);
+        }
+        for (row, pixels) in self.display.second_plane.iter_mut().zip(state.second_plane.chunks(state.display_width as usize)) {
+            row[..pixels.len()].copy_from_slice(pixels);
+        }
+        self.display.selected_planes = state.selected_planes;
+        self.display.send_frame();
         self.is_rom_loaded = true;
         Ok(())
     }
 
-    
+    fn save_slot(&mut self, slot: usize) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(save_slots) = self.save_slots.as_ref() else {
+            return Err(Box::from("No ROM loaded"));
+        };
+        save_slots.save(slot, &self.save_state(), self.cycles)
+    }
+
+    fn load_slot(&mut self, slot: usize) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(save_slots) = self.save_slots.as_ref() else {
+            return Err(Box::from("No ROM loaded"));
+        };
+        let state: EmulatorState = save_slots.load(slot)?;
+        self.load_state(&state)
+    }
+
+    // Start the CPU from this state instead of all zeros.
+    // The state is applied after each ROM load, before the first cycle runs.
+    pub fn set_initial_state(&mut self, state: CpuSnapshot) -> Result<(), Box<dyn std::error::Error>> {
+        let memory_size: usize = self.memory.data.len();
+        // The PC must leave room to fetch a full opcode
+        if state.pc as usize + 1 >= memory_size {
+            return Err(Box::from(format!("PC {:04X} is outside of memory", state.pc)));
+        }
+        if state.i as usize >= memory_size {
+            return Err(Box::from(format!("I {:04X} is outside of memory", state.i)));
+        }
+        if state.sp as usize > state.stack.len() {
+            return Err(Box::from(format!("Stack pointer {} exceeds the stack size", state.sp)));
+        }
+        if let Some(address) = state.stack[..state.sp as usize].iter().find(|&&address| address as usize + 1 >= memory_size) {
+            return Err(Box::from(format!("Stack address {:04X} is outside of memory", address)));
+        }
+
+        self.initial_state = Some(state);
+        Ok(())
+    }
+
+    pub fn clear_initial_state(&mut self) {
+        self.initial_state = None;
+    }
+
+    // (opcode, times run) for every opcode that has run since the ROM was loaded
+    pub fn nonzero_opcode_counts(&self) -> OpcodeCounts {
+        self.opcode_counts
+            .iter()
+            .enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(opcode, &count)| (opcode as u16, count))
+            .collect()
+    }
+
+    // The last instructions run, oldest first
+    pub fn recent_opcodes(&self) -> impl Iterator<Item = &TraceEntry> {
+        self.recent_opcodes.iter()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Freeze or resume both instructions and the timers
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        // cycle() only sends the CPU state once a frame, send the one it stopped on
+        if paused {
+            let _ = self.emulator_data.cpu_state.try_send(self.cpu.view());
+        }
+    }
+
+    // Set how many instructions run per second. 0 means unlimited, cycle() then never sleeps.
+    // The timers follow emulated time, at 0 that passes as if the clock were DEFAULT_CPU_HZ.
+    pub fn set_clock_hz(&mut self, hz: u32) {
+        self.clock_hz = hz;
+        self.next_instruction = Instant::now();
+    }
+
+    pub fn clock_hz(&self) -> u32 {
+        self.clock_hz
+    }
+
+    // Keep a state for every 60 Hz frame of running, up to capacity of them, so rewind_one can step
+    // back through them. Each state holds all of RAM and the display. 0 turns rewind off.
+    pub fn enable_rewind(&mut self, capacity: usize) {
+        self.rewind_capacity = capacity;
+        self.rewind_states.clear();
+        self.rewind_states.shrink_to_fit();
+    }
+
+    // Go back to the last state kept for rewinding, timers included. Returns false when there are none left.
+    pub fn rewind_one(&mut self) -> bool {
+        let Some(state) = self.rewind_states.pop_back() else {
+            return false;
+        };
+        if let Err(err) = self.load_state(&state) {
+            self.log(LogLevel::Warning, format!("Could not rewind: {}", err));
+            return false;
+        }
+        // The next state is a whole frame away, so running on doesn't put this one straight back
+        self.next_rewind_state = Instant::now() + VBLANK_INTERVAL;
+        true
+    }
+
+    fn record_rewind_state(&mut self) {
+        if self.rewind_capacity == 0 || Instant::now() < self.next_rewind_state {
+            return;
+        }
+        self.next_rewind_state = Instant::now() + VBLANK_INTERVAL;
+        if self.rewind_states.len() == self.rewind_capacity {
+            self.rewind_states.pop_front();
+        }
+        self.rewind_states.push_back(self.save_state());
+    }
+
+    // Sleep until the next instruction is due at clock_hz
+    fn wait_for_clock(&mut self) {
+        if self.clock_hz == 0 {
+            return;
+        }
+        let now: Instant = Instant::now();
+        if self.next_instruction > now {
+            std::thread::sleep(self.next_instruction - now);
+        } else if now - self.next_instruction > MAX_CLOCK_LAG {
+            self.next_instruction = now;
+        }
+        self.next_instruction += Duration::from_secs_f64(1.0 / self.clock_hz as f64);
+    }
+
+    // Resume and pause again right after the next instruction that changes the display
+    pub fn run_to_next_draw(&mut self) {
+        self.running_to_draw = true;
+        self.set_paused(false);
+    }
+
+    // Run a single instruction on the next cycle and stay paused. Does nothing unless paused.
+    pub fn step(&mut self) {
+        self.stepping = self.paused;
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) {
+        self.breakpoints.remove(&address);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn breakpoints(&self) -> &HashSet<u16> {
+        &self.breakpoints
+    }
+
+    pub fn add_memory_watch(&mut self, address: u16) {
+        self.watches.insert(Watch::Memory(address));
+    }
+
+    // Watch V0 to VF, anything higher is ignored
+    pub fn add_register_watch(&mut self, register: usize) {
+        if register < 16 {
+            self.watches.insert(Watch::Register(register));
+        }
+    }
+
+    pub fn remove_watch(&mut self, watch: Watch) {
+        self.watches.remove(&watch);
+    }
+
+    pub fn clear_watches(&mut self) {
+        self.watches.clear();
+    }
+
+    pub fn watches(&self) -> &HashSet<Watch> {
+        &self.watches
+    }
+
+    // The current value of every watch, taken before an instruction to compare with after it
+    fn watch_values(&self) -> Vec<(Watch, u8)> {
+        self.watches
+            .iter()
+            .map(|&watch| {
+                let value: u8 = match watch {
+                    Watch::Memory(address) => self.memory.read(address as usize),
+                    Watch::Register(register) => self.cpu.v[register],
+                };
+                (watch, value)
+            })
+            .collect()
+    }
 
     pub fn get_default_font() -> [u8; 80] {
         [
@@ -97,22 +791,429 @@ impl Emulator {
         ]
     }
 
+    // The SUPER-CHIP 8x10 digits, with A-F as Octo draws them
+    pub fn get_default_big_font() -> [u8; 160] {
+        [
+            0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+            0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+            0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+            0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+            0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+            0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+            0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+            0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+        ]
+    }
+
+    pub fn handle_command(&mut self, command: EmulatorCommand) {
+        match command {
+            EmulatorCommand::PlayInputScript(frames) => self.play_input_script(frames),
+            EmulatorCommand::LoadRom(rom) => {
+                if let Err(err) = self.load_rom(&rom) {
+                    let _ = self.emulator_data.events.try_send(EmulatorEvent::RomLoadFailed { message: err.to_string() });
+                }
+            }
+            EmulatorCommand::SetClearDisplayOnReset(clear) => self.clear_display_on_reset = clear,
+            EmulatorCommand::SetAllowRomTruncation(allow) => self.allow_rom_truncation = allow,
+            EmulatorCommand::SetChip8E(enabled) => self.cpu.chip8e = enabled,
+            EmulatorCommand::SetSchip(enabled) => self.cpu.schip = enabled,
+            EmulatorCommand::SetXoChip(enabled) => self.cpu.xochip = enabled,
+            EmulatorCommand::SetFx0aMinHoldFrames(frames) => self.cpu.fx0a_min_hold_frames = frames,
+            EmulatorCommand::SetFontCharSize(size) => self.cpu.font_char_size = size,
+            EmulatorCommand::SetSpriteReadOverflow(overflow) => self.cpu.sprite_read_overflow = overflow,
+            EmulatorCommand::SetTimerHz(hz) => self.cpu.set_timer_hz(hz),
+            EmulatorCommand::ColdReset => {
+                if let Err(err) = self.cold_reset() {
+                    self.log(LogLevel::Error, format!("Could not reset: {}", err));
+                }
+            }
+            EmulatorCommand::WarmReset => {
+                if self.is_rom_loaded {
+                    self.warm_reset();
+                    self.log(LogLevel::Info, "Warm reset".to_string());
+                }
+            }
+            EmulatorCommand::SaveSlot(slot) => {
+                let event: EmulatorEvent = match self.save_slot(slot) {
+                    Ok(()) => EmulatorEvent::SlotSaved(slot),
+                    Err(err) => EmulatorEvent::SlotError { slot, message: err.to_string() },
+                };
+                let _ = self.emulator_data.events.try_send(event);
+            }
+            EmulatorCommand::LoadSlot(slot) => {
+                let event: EmulatorEvent = match self.load_slot(slot) {
+                    Ok(()) => EmulatorEvent::SlotLoaded(slot),
+                    Err(err) => EmulatorEvent::SlotError { slot, message: err.to_string() },
+                };
+                let _ = self.emulator_data.events.try_send(event);
+            }
+            EmulatorCommand::SaveReplay(path) => match self.replay_bundle() {
+                Some(bundle) => match bundle.to_file(&path) {
+                    Ok(()) => {
+                        let _ = self.emulator_data.events.try_send(EmulatorEvent::ReplaySaved(path));
+                    }
+                    Err(err) => self.log(LogLevel::Error, format!("Could not save replay: {}", err)),
+                },
+                None => self.log(LogLevel::Error, "Could not save replay: no ROM loaded".to_string()),
+            },
+            EmulatorCommand::StartReplay(bundle) => {
+                if let Err(err) = self.start_replay(*bundle) {
+                    self.log(LogLevel::Error, format!("Could not start replay: {}", err));
+                }
+            }
+            EmulatorCommand::SetPaused(paused) => {
+                // A plain pause or resume cancels a pending run_to_next_draw
+                self.running_to_draw = false;
+                self.set_paused(paused);
+            }
+            EmulatorCommand::SetReferenceTrace(reference) => {
+                // Compare from the start of the ROM
+                self.trace_comparator = reference.map(TraceComparator::new);
+            }
+            EmulatorCommand::SetOpcodeFilter(opcode_filter) => self.opcode_filter = opcode_filter,
+            EmulatorCommand::SetSendOpcodeCounts(send) => self.send_opcode_counts = send,
+            EmulatorCommand::SetSendRecentOpcodes(send) => self.send_recent_opcodes = send,
+            EmulatorCommand::SetPauseAfterDraw(pause) => self.pause_after_draw = pause,
+            EmulatorCommand::RunToNextDraw => self.run_to_next_draw(),
+            EmulatorCommand::Step => self.step(),
+            EmulatorCommand::AddBreakpoint(address) => self.add_breakpoint(address),
+            EmulatorCommand::RemoveBreakpoint(address) => self.remove_breakpoint(address),
+            EmulatorCommand::ClearBreakpoints => self.clear_breakpoints(),
+            EmulatorCommand::AddWatch(Watch::Memory(address)) => self.add_memory_watch(address),
+            EmulatorCommand::AddWatch(Watch::Register(register)) => self.add_register_watch(register),
+            EmulatorCommand::RemoveWatch(watch) => self.remove_watch(watch),
+            EmulatorCommand::ClearWatches => self.clear_watches(),
+            EmulatorCommand::SetSpinIdle(idle) => self.spin_idle = idle,
+            EmulatorCommand::SetClockHz(hz) => self.set_clock_hz(hz),
+            EmulatorCommand::SetRewindCapacity(capacity) => self.enable_rewind(capacity),
+            EmulatorCommand::Rewind => {
+                self.rewind_one();
+            }
+            EmulatorCommand::SetQuirks(quirks) => {
+                self.cpu.quirks = quirks;
+                self.display.wrap_x = quirks.wrap_x;
+                self.display.wrap_y = quirks.wrap_y;
+            }
+            EmulatorCommand::SetDoubleBuffered(double_buffered) => {
+                self.display.double_buffered = double_buffered;
+                // Don't leave the last draws stuck in the back buffer
+                self.display.vblank();
+            }
+            EmulatorCommand::SetWatchdogThreshold(threshold) => {
+                self.watchdog_threshold = threshold;
+                self.instructions_since_activity = 0;
+            }
+            EmulatorCommand::SetRamSize(size) => {
+                if let Err(err) = self.set_ram_size(size) {
+                    self.log(LogLevel::Error, format!("Could not change RAM size: {}", err));
+                }
+            }
+        }
+    }
+
+    fn log(&self, level: LogLevel, message: String) {
+        let _ = self.emulator_data.events.try_send(EmulatorEvent::Log { level, message });
+    }
+
+    // Advisory soft-lock check. Drawing or reading the keypad (EX9E, EXA1, FX0A) restarts the
+    // count, and the warning goes out once each time the count passes the threshold.
+    fn check_watchdog(&mut self, opcode: u16, is_draw: bool) {
+        let Some(threshold) = self.watchdog_threshold else {
+            return;
+        };
+        let reads_keys: bool = matches!(opcode & 0xF0FF, 0xE09E | 0xE0A1 | 0xF00A);
+        if is_draw || reads_keys {
+            self.instructions_since_activity = 0;
+            return;
+        }
+        self.instructions_since_activity += 1;
+        if self.instructions_since_activity == threshold {
+            let instructions: u64 = self.instructions_since_activity;
+            let _ = self.emulator_data.events.try_send(EmulatorEvent::PossibleSoftLock { instructions });
+        }
+    }
+
+    // Check the instruction about to run against the reference trace.
+    // Returns false, after pausing and reporting, when it differs.
+    fn check_trace(&mut self) -> bool {
+        let Some(trace_comparator) = self.trace_comparator.as_mut() else {
+            return true;
+        };
+        let step: TraceEntry = TraceEntry { pc: self.cpu.pc, opcode: self.cpu.fetch(&self.memory) };
+        if let Err(mismatch) = trace_comparator.check(step, &self.cpu.snapshot()) {
+            self.trace_comparator = None;
+            self.set_paused(true);
+            let _ = self.emulator_data.events.try_send(EmulatorEvent::TraceMismatch(mismatch));
+            return false;
+        }
+        if trace_comparator.is_finished() {
+            let steps: usize = trace_comparator.steps();
+            self.trace_comparator = None;
+            let _ = self.emulator_data.events.try_send(EmulatorEvent::TraceMatched { steps });
+        }
+        true
+    }
+
+    // Instructions per second of emulated time. Unlimited runs keep the default pace so the timers
+    // and frames still line up with the instructions.
+    fn instruction_rate(&self) -> u32 {
+        if self.clock_hz == 0 { DEFAULT_CPU_HZ } else { self.clock_hz }
+    }
+
+    // Move emulated time on by one instruction slot. The timers tick timer_hz times and the frame
+    // count 60 times per instruction_rate slots, spread evenly, so a run is the same at any host speed.
+    fn advance_clock(&mut self) {
+        let rate: u32 = self.instruction_rate();
+        self.timer_phase += self.cpu.timer_hz();
+        while self.timer_phase >= rate {
+            self.timer_phase -= rate;
+            self.cpu.tick_timers();
+        }
+        self.frame_phase += 60;
+        while self.frame_phase >= rate {
+            self.frame_phase -= rate;
+            self.frames += 1;
+            self.display_waiting = false;
+            self.cpu.count_frame();
+            if let Some(input_script) = self.input_script.as_mut() {
+                input_script.pop_front();
+                if input_script.is_empty() {
+                    self.input_script = None;
+                    let _ = self.emulator_data.events.try_send(EmulatorEvent::InputScriptFinished);
+                }
+            }
+        }
+    }
+
     pub fn cycle(&mut self) {
-        if let Ok(rom_content) = self.emulator_data.file_content.try_recv() {
-            let _ = self.load_rom(rom_content.as_slice());
+        while let Ok(command) = self.emulator_data.commands.try_recv() {
+            self.handle_command(command);
         }
 
         if let Ok(font_content) = self.emulator_data.font_file_content.try_recv() {
-            self.set_font(font_content.as_slice().try_into().unwrap());
+            self.set_font(&font_content);
+        }
+
+        // The GUI stores the keypad once per frame, so a key reaches the CPU on the first
+        // cycle after the frame that saw it and no state is ever dropped
+        let mut bitmask: u16 = self.emulator_data.keys.load(Ordering::Relaxed);
+        if let Some(keys) = self.input_script.as_ref().and_then(|input_script| input_script.front()) {
+            bitmask = keys_to_bitmask(keys);
+        }
+        // A replay holds each recorded keypad state until the next change comes due
+        if let Some(replay_inputs) = self.replay_inputs.as_mut() {
+            bitmask = keys_to_bitmask(&self.keys);
+            while let Some(&(cycle, keys)) = replay_inputs.front()
+                && cycle <= self.cycles
+            {
+                bitmask = keys;
+                replay_inputs.pop_front();
+            }
+            if replay_inputs.is_empty() {
+                self.replay_inputs = None;
+                let _ = self.emulator_data.events.try_send(EmulatorEvent::ReplayFinished);
+            }
+        }
+        if self.is_rom_loaded && bitmask != keys_to_bitmask(&self.keys) {
+            self.input_log.push((self.cycles, bitmask));
         }
+        self.keys = keys_from_bitmask(bitmask);
+
+        // Runs while paused too, so a draw just before a pause still shows up
+        if self.display.double_buffered && Instant::now() >= self.next_vblank {
+            self.display.vblank();
+            self.next_vblank = Instant::now() + VBLANK_INTERVAL;
+        }
+
+        let stepping: bool = std::mem::take(&mut self.stepping) && self.paused;
+        if self.is_rom_loaded && (!self.paused || stepping) && !self.cpu.halted {
+            // A step runs at once and isn't a frame of running for rewind
+            if !stepping {
+                self.wait_for_clock();
+                self.record_rewind_state();
+            }
+            // After a DXYN with the display_wait quirk, the instruction slots up to the next frame
+            // pass without running anything. A step still runs.
+            if self.display_waiting && !stepping {
+                self.advance_clock();
+                return;
+            }
+            if !self.check_trace() {
+                return;
+            }
+
+            // Pause before the instruction at a breakpoint. Resuming runs it, so execution gets past
+            // the breakpoint instead of pausing there again. A step always runs the instruction.
+            let pc: u16 = self.cpu.pc;
+            if !stepping && self.breakpoint_hit != Some(pc) && self.breakpoints.contains(&pc) {
+                self.breakpoint_hit = Some(pc);
+                self.set_paused(true);
+                let _ = self.emulator_data.events.try_send(EmulatorEvent::BreakpointHit { pc });
+                return;
+            }
+            self.breakpoint_hit = None;
+
+            let step: TraceEntry = TraceEntry { pc: self.cpu.pc, opcode: self.cpu.fetch(&self.memory) };
+            if !self.opcode_filter.allows(step.opcode) {
+                self.set_paused(true);
+                let _ = self.emulator_data.events.try_send(EmulatorEvent::OpcodeDenied { pc: step.pc, opcode: step.opcode });
+                return;
+            }
+
+            // A 1NNN that jumps to itself never exits, CHIP-8 has no interrupts. With spin_idle on, the
+            // jump isn't decoded again and the thread sleeps a frame instead. A frame of emulated time
+            // passes so the timers keep running, and every call still takes commands, keys and ROM
+            // loads, so a reset, ROM load or save state load wakes it. Off by default as it stops the
+            // cycle count.
+            if self.spin_idle && step.opcode & 0xF000 == 0x1000 && step.opcode & 0x0FFF == step.pc {
+                std::thread::sleep(SPIN_IDLE_SLEEP);
+                let frame: u64 = self.frames;
+                while self.frames == frame {
+                    self.advance_clock();
+                }
+                return;
+            }
+
+            if self.recent_opcodes.len() == RECENT_OPCODES {
+                self.recent_opcodes.pop_front();
+            }
+            self.recent_opcodes.push_back(step);
+            self.opcode_counts[step.opcode as usize] += 1;
+
+            let watched: Vec<(Watch, u8)> = self.watch_values();
+            // A bad instruction is skipped and logged, the ROM keeps running
+            if let Err(err) = self.cpu.decode(&mut self.memory, &mut self.display, &self.keys) {
+                self.log(LogLevel::Warning, format!("{} at {:04X}", err, step.pc));
+            }
+            self.cycles += 1;
+            self.emulator_data.cycles.store(self.cycles, Ordering::Relaxed);
+            let is_sprite: bool = step.opcode & 0xF000 == 0xD000;
+            let is_draw: bool = is_sprite || step.opcode == 0x00E0;
+            // Set before the clock moves, so a frame that starts in this instruction's slot ends the wait
+            self.display_waiting = is_sprite && self.cpu.quirks.display_wait;
+            self.advance_clock();
+            for ((watch, old), (_, new)) in watched.into_iter().zip(self.watch_values()) {
+                if old != new {
+                    self.set_paused(true);
+                    let _ = self.emulator_data.events.try_send(EmulatorEvent::WatchTriggered { pc: step.pc, watch, old, new });
+                }
+            }
+            for warning in self.cpu.take_warnings() {
+                self.log(LogLevel::Warning, warning);
+            }
+            if self.cpu.take_flags_changed() && self.persist_flags
+                && let Some(save_slots) = self.save_slots.as_ref()
+                && let Err(err) = save_slots.save_flags(&self.cpu.flags)
+            {
+                self.log(LogLevel::Error, format!("Could not save the flag registers: {}", err));
+            }
+            for mismatch in self.cpu.take_platform_mismatches() {
+                let _ = self.emulator_data.events.try_send(EmulatorEvent::PlatformMismatch(mismatch));
+            }
+            if (self.pause_after_draw && is_sprite) || (self.running_to_draw && is_draw) {
+                self.running_to_draw = false;
+                self.set_paused(true);
+                let _ = self.emulator_data.events.try_send(EmulatorEvent::PausedAfterDraw { pc: step.pc });
+            }
+            if self.cpu.halted {
+                let _ = self.emulator_data.events.try_send(EmulatorEvent::ProgramEnded { pc: step.pc });
+            }
+            self.check_watchdog(step.opcode, is_draw);
+            // Tells a ROM that is slow to start apart from one that never draws
+            if !self.has_drawn && is_draw {
+                self.has_drawn = true;
+                let _ = self.emulator_data.events.try_send(EmulatorEvent::FirstDraw { cycle: self.cycles });
+            }
+            // If the GUI hasn't taken the last state yet, this one is dropped
+            if stepping || self.cpu_state_sent_at.elapsed() >= VBLANK_INTERVAL {
+                self.cpu_state_sent_at = Instant::now();
+                let _ = self.emulator_data.cpu_state.try_send(self.cpu.view());
+            }
+            // Show the step's effect even if double buffering would hold it until the next vblank
+            if stepping {
+                self.display.send_frame();
+            }
 
-        if self.is_rom_loaded {
-            if let Ok(keys) = self.emulator_data.keys.try_recv() {
-                self.keys = keys;
+            if self.send_opcode_counts && self.opcode_counts_sent_at.elapsed() >= OPCODE_COUNTS_INTERVAL {
+                self.opcode_counts_sent_at = Instant::now();
+                let _ = self.emulator_data.opcode_counts.try_send(self.nonzero_opcode_counts());
             }
+            if self.send_recent_opcodes && self.recent_opcodes_sent_at.elapsed() >= RECENT_OPCODES_INTERVAL {
+                self.recent_opcodes_sent_at = Instant::now();
+                let _ = self.emulator_data.recent_opcodes.try_send(self.recent_opcodes().copied().collect());
+            }
+        } else if self.clock_hz != 0 {
+            // Nothing to run, don't spin a core waiting for commands
+            std::thread::sleep(IDLE_SLEEP);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An emulator with the default font and the ROM loaded, after the GUI has gone: every
+    // receiver is dropped, as when the window closes with the emulator thread still running
+    fn emulator_after_window_closed(rom: &[u8]) -> Emulator {
+        let (_, font_file_content) = mpsc::channel(1);
+        let (_, commands) = mpsc::channel(1);
+        let (events, _) = mpsc::channel(1);
+        let (cpu_state, _) = mpsc::channel(1);
+        let (opcode_counts, _) = mpsc::channel(1);
+        let (recent_opcodes, _) = mpsc::channel(1);
+        let (pixel_buffer_sender, _) = mpsc::channel(1);
+        let mut emulator: Emulator = Emulator::new(
+            EmulatorData {
+                font_file_content,
+                keys: Arc::new(AtomicU16::new(0)),
+                commands,
+                events,
+                cpu_state,
+                opcode_counts,
+                recent_opcodes,
+                cycles: Arc::new(AtomicU64::new(0)),
+                cpu_hz: 0,
+            },
+            pixel_buffer_sender,
+            Quirks::default(),
+        );
+        emulator.set_font(&Emulator::get_default_font());
+        emulator.load_rom(rom).unwrap();
+        emulator
+    }
+
+    #[test]
+    fn load_state_after_the_window_closes() {
+        // V0 = 1, draw glyph 0, loop
+        let mut emulator: Emulator = emulator_after_window_closed(&[0x60, 0x01, 0xD0, 0x05, 0x12, 0x04]);
+        let state: EmulatorState = emulator.save_state();
+        for _ in 0..10 {
+            emulator.cycle();
+        }
+        emulator.load_state(&state).unwrap();
+        assert_eq!(emulator.cpu.snapshot(), state.cpu);
+    }
 
-            self.cpu
-                .decode(&mut self.memory, &mut self.display, &self.keys);
+    #[test]
+    fn warm_reset_after_the_window_closes() {
+        // V0 = 1, draw glyph 0, loop
+        let mut emulator: Emulator = emulator_after_window_closed(&[0x60, 0x01, 0xD0, 0x05, 0x12, 0x04]);
+        for _ in 0..10 {
+            emulator.cycle();
         }
+        emulator.warm_reset();
+        assert_eq!((emulator.cpu.pc, emulator.cpu.v[0x0]), (ROM_ADDRESS, 0));
+        assert!(emulator.display.pixels.iter().flatten().all(|&lit| !lit));
+        // RAM is kept
+        assert_eq!(emulator.memory.data[0x200..0x206], [0x60, 0x01, 0xD0, 0x05, 0x12, 0x04]);
     }
 }
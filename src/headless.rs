@@ -0,0 +1,200 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU16, AtomicU64};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc;
+
+use crate::cpu::CpuView;
+use crate::display::{Display, PixelBuffer};
+use crate::emulator::{Emulator, EmulatorData, EmulatorEvent};
+use crate::input_script;
+use crate::quirks::Quirks;
+use crate::trace::TraceEntry;
+
+// Events kept until take_events, later ones are dropped
+const EVENT_CAPACITY: usize = 256;
+
+// Runs a ROM without a window, for CI and regression tests. cycle() is called directly and as fast
+// as it goes, and the display and CPU are read straight off the emulator afterwards. The delay and
+// sound timers count emulated time, so a run comes out the same on any machine.
+pub struct HeadlessEmulator {
+    pub emulator: Emulator,
+    pixel_buffer_receiver: mpsc::Receiver<PixelBuffer>,
+    event_receiver: mpsc::Receiver<EmulatorEvent>,
+}
+
+// Why run_until stopped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    CycleLimit,
+    TimeLimit,
+    ProgramEnded, // The ROM ran 00FD
+}
+
+impl HeadlessEmulator {
+    // An emulator with the default font, settings and quirks and the ROM loaded
+    pub fn new(rom: &[u8]) -> Result<Self, Box<dyn Error>> {
+        // The GUI's ends of these are dropped, nothing is sent or read on them
+        let (_font_file_content_sender, font_file_content) = mpsc::channel::<Vec<u8>>(1);
+        let (_command_sender, commands) = mpsc::channel(1);
+        let (cpu_state, _cpu_state_receiver) = mpsc::channel::<CpuView>(1);
+        let (opcode_counts, _opcode_counts_receiver) = mpsc::channel::<Vec<(u16, u64)>>(1);
+        let (recent_opcodes, _recent_opcodes_receiver) = mpsc::channel::<Vec<TraceEntry>>(1);
+        let (events, event_receiver) = mpsc::channel::<EmulatorEvent>(EVENT_CAPACITY);
+        let (pixel_buffer_sender, pixel_buffer_receiver) = mpsc::channel::<PixelBuffer>(1);
+
+        let mut emulator: Emulator = Emulator::new(
+            EmulatorData {
+                font_file_content,
+                keys: Arc::new(AtomicU16::new(0)),
+                commands,
+                events,
+                cpu_state,
+                opcode_counts,
+                recent_opcodes,
+                cycles: Arc::new(AtomicU64::new(0)),
+                cpu_hz: 0, // Unlimited
+            },
+            pixel_buffer_sender,
+            Quirks::default(),
+        );
+        emulator.set_font(&Emulator::get_default_font());
+        emulator.load_rom(rom)?;
+
+        Ok(HeadlessEmulator {
+            emulator,
+            pixel_buffer_receiver,
+            event_receiver,
+        })
+    }
+
+    // Press keys as an input script says, see input_script::parse. Frame N of the script is held
+    // during the emulator's Nth frame from now, see Emulator::play_input_script.
+    pub fn with_input_script(mut self, script: &str) -> Result<Self, Box<dyn Error>> {
+        self.emulator.play_input_script(input_script::parse(script)?);
+        Ok(self)
+    }
+
+    // Call cycle() this many times. A paused or halted emulator uses up the calls without running.
+    pub fn run(&mut self, cycles: u64) {
+        for _ in 0..cycles {
+            self.cycle();
+        }
+    }
+
+    // Call cycle() up to this many times, stopping early once max_time has passed or the ROM exits.
+    // The limits guard automated runs against ROMs that never halt.
+    pub fn run_until(&mut self, cycles: u64, max_time: Option<Duration>) -> StopReason {
+        self.run_until_each_frame(cycles, max_time, |_| {})
+    }
+
+    // run_until, showing on_frame the display at the end of every 60 Hz frame of emulated time
+    pub fn run_until_each_frame(&mut self, cycles: u64, max_time: Option<Duration>, mut on_frame: impl FnMut(&Display)) -> StopReason {
+        let started: Instant = Instant::now();
+        for _ in 0..cycles {
+            if self.emulator.cpu.halted {
+                return StopReason::ProgramEnded;
+            }
+            if max_time.is_some_and(|max_time| started.elapsed() >= max_time) {
+                return StopReason::TimeLimit;
+            }
+            let frame: u64 = self.emulator.frames;
+            self.cycle();
+            if self.emulator.frames != frame {
+                on_frame(&self.emulator.display);
+            }
+        }
+        if self.emulator.cpu.halted { StopReason::ProgramEnded } else { StopReason::CycleLimit }
+    }
+
+    // Run until this many more 60 Hz frames of emulated time have passed, or the emulator pauses or halts
+    pub fn run_frames(&mut self, frames: u64) {
+        let end: u64 = self.emulator.frames + frames;
+        while self.emulator.frames < end && !self.emulator.is_paused() && !self.emulator.cpu.halted {
+            self.cycle();
+        }
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.emulator.frames
+    }
+
+    fn cycle(&mut self) {
+        self.emulator.cycle();
+        // Drawing blocks until the frame is taken
+        while self.pixel_buffer_receiver.try_recv().is_ok() {}
+    }
+
+    pub fn display(&self) -> &Display {
+        &self.emulator.display
+    }
+
+    pub fn cpu(&self) -> CpuView {
+        self.emulator.cpu.view()
+    }
+
+    // Plane 0 at the live resolution, one string per row with '#' for a lit pixel and '.' for an
+    // unlit one, to compare against an expected grid
+    pub fn screen(&self) -> Vec<String> {
+        let display: &Display = &self.emulator.display;
+        display.pixels[..display.height as usize]
+            .iter()
+            .map(|row| row[..display.width as usize].iter().map(|&lit| if lit { '#' } else { '.' }).collect())
+            .collect()
+    }
+
+    // The events sent since the last call
+    pub fn take_events(&mut self) -> Vec<EmulatorEvent> {
+        std::iter::from_fn(|| self.event_receiver.try_recv().ok()).collect()
+    }
+}
+
+// Run the ROM headless and check the display against a reference PNG at each (frame, path), where
+// frame N is the display once N frames of emulated time have passed. Reference pixels brighter than
+// mid gray are lit. The first mismatch panics with the number of differing pixels and writes a diff
+// image to the temp directory: red where a pixel should be lit, blue where it shouldn't, gray where
+// both are lit. For pinning how a ROM looks in tests, the frames have to be in order.
+pub fn assert_frames_match(rom: &[u8], expected: &[(u64, &Path)]) {
+    let mut headless: HeadlessEmulator = HeadlessEmulator::new(rom).unwrap();
+    for &(frame, png_path) in expected {
+        assert!(frame >= headless.frames(), "Frame {} comes after frame {}, list the frames in order", frame, headless.frames());
+        headless.run_frames(frame - headless.frames());
+
+        let reference: image::GrayImage = match image::open(png_path) {
+            Ok(reference) => reference.to_luma8(),
+            Err(err) => panic!("Could not read {}: {}", png_path.display(), err),
+        };
+        let actual: image::GrayImage = headless.display().to_image();
+        assert_eq!(
+            reference.dimensions(),
+            actual.dimensions(),
+            "Frame {} is a different size from {}",
+            frame,
+            png_path.display()
+        );
+
+        let mut differing: usize = 0;
+        let diff: image::RgbImage = image::RgbImage::from_fn(actual.width(), actual.height(), |x, y| {
+            let expected_lit: bool = reference.get_pixel(x, y)[0] > 127;
+            let actual_lit: bool = actual.get_pixel(x, y)[0] > 127;
+            differing += (expected_lit != actual_lit) as usize;
+            match (expected_lit, actual_lit) {
+                (true, true) => image::Rgb([128, 128, 128]),
+                (true, false) => image::Rgb([255, 0, 0]),
+                (false, true) => image::Rgb([0, 0, 255]),
+                (false, false) => image::Rgb([0, 0, 0]),
+            }
+        });
+        if differing > 0 {
+            let name: String = png_path.file_stem().map_or("frame".into(), |stem| stem.to_string_lossy().into_owned());
+            let diff_path: PathBuf = std::env::temp_dir().join(format!("{}-frame-{}-diff.png", name, frame));
+            let saved: String = match diff.save(&diff_path) {
+                Ok(()) => format!("diff written to {}", diff_path.display()),
+                Err(err) => format!("the diff could not be written: {}", err),
+            };
+            panic!("Frame {}: {} pixels differ from {}, {}", frame, differing, png_path.display(), saved);
+        }
+    }
+}
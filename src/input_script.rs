@@ -0,0 +1,144 @@
+use std::fmt;
+
+// The last frame a script can reach, an hour at 60 Hz. Every frame up to it is stored, so a typo'd
+// frame number fails here instead of allocating gigabytes.
+pub const MAX_FRAME: usize = 60 * 60 * 60;
+
+// A human-writable script of key presses, turned into the key state for every frame.
+//
+//     # Comments start with a hash
+//     frame 10: press 5    # key 5 goes down on frame 10 and stays down
+//     frame 13: release 5
+//     wait 60              # move 60 frames forward
+//     press A              # without a frame, the action happens on the current frame
+//     release A
+//
+// Keys are the hex digits 0-F. `frame N` jumps to an absolute frame and can't go backwards, and
+// no frame can be past MAX_FRAME.
+pub fn parse(script: &str) -> Result<Vec<[bool; 16]>, ScriptError> {
+    let mut frames: Vec<[bool; 16]> = Vec::new();
+    let mut keys: [bool; 16] = [false; 16];
+    let mut frame: usize = 0;
+
+    for (index, line) in script.lines().enumerate() {
+        let line_number: usize = index + 1;
+        let error = |message: String| ScriptError { line: line_number, message };
+
+        let line: &str = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        // Split off a "frame N:" prefix
+        let mut action: &str = line;
+        let mut target_frame: usize = frame;
+        if let Some(rest) = line.strip_prefix("frame") {
+            let (number, rest) = rest
+                .split_once(':')
+                .ok_or_else(|| error("Expected ':' after the frame number".to_string()))?;
+            target_frame = parse_number(number.trim()).map_err(&error)?;
+            if target_frame < frame {
+                return Err(error(format!("Frame {} is before the current frame {}", target_frame, frame)));
+            }
+            action = rest.trim();
+        }
+        if target_frame > MAX_FRAME {
+            return Err(error(format!("Frame {} is past the last frame {}", target_frame, MAX_FRAME)));
+        }
+
+        let mut words = action.split_whitespace();
+        let command: &str = words.next().ok_or_else(|| error("Missing command".to_string()))?;
+        let argument: &str = words
+            .next()
+            .ok_or_else(|| error(format!("'{}' needs an argument", command)))?;
+        if let Some(extra) = words.next() {
+            return Err(error(format!("Unexpected '{}'", extra)));
+        }
+
+        match command {
+            "press" | "release" => {
+                let key: usize = parse_key(argument).map_err(&error)?;
+                advance(&mut frames, keys, target_frame);
+                frame = target_frame;
+                keys[key] = command == "press";
+            }
+            "wait" => {
+                if target_frame != frame {
+                    return Err(error("'wait' can't have a frame prefix".to_string()));
+                }
+                let wait: usize = parse_number(argument).map_err(&error)?;
+                frame = frame
+                    .checked_add(wait)
+                    .filter(|&frame| frame <= MAX_FRAME)
+                    .ok_or_else(|| error(format!("Waiting {} frames goes past the last frame {}", wait, MAX_FRAME)))?;
+            }
+            _ => return Err(error(format!("Unknown command '{}'", command))),
+        }
+    }
+
+    // Include the final frame so the last press or release takes effect
+    advance(&mut frames, keys, frame + 1);
+    Ok(frames)
+}
+
+// Repeat the current key state up to (not including) the given frame
+fn advance(frames: &mut Vec<[bool; 16]>, keys: [bool; 16], frame: usize) {
+    if frames.len() < frame {
+        frames.resize(frame, keys);
+    }
+}
+
+fn parse_number(text: &str) -> Result<usize, String> {
+    text.parse::<usize>().map_err(|_| format!("'{}' is not a number", text))
+}
+
+fn parse_key(text: &str) -> Result<usize, String> {
+    match usize::from_str_radix(text, 16) {
+        Ok(key) if key < 16 && text.len() == 1 => Ok(key),
+        _ => Err(format!("'{}' is not a key, expected 0-F", text)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_keys_between_frames() {
+        let frames: Vec<[bool; 16]> = parse("frame 2: press 5 # down\nframe 4: release 5\nwait 2\npress A\n").unwrap();
+        assert_eq!(frames.len(), 7);
+        let held: Vec<bool> = frames.iter().map(|keys| keys[0x5]).collect();
+        assert_eq!(held, [false, false, true, true, false, false, false]);
+        assert!(frames[6][0xA] && !frames[5][0xA]);
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        assert_eq!(parse("press 5\nfoo 3\n").unwrap_err().line, 2);
+        assert_eq!(parse("frame 3: press 1\nframe 1: press 2").unwrap_err().line, 2);
+        assert_eq!(parse("press G").unwrap_err().line, 1);
+    }
+
+    #[test]
+    fn frames_past_the_limit_are_rejected() {
+        let error: ScriptError = parse("press 1\nwait 10\nwait 18446744073709551615").unwrap_err();
+        assert_eq!(error.line, 3);
+        assert_eq!(parse(&format!("frame {}: press 1", MAX_FRAME + 1)).unwrap_err().line, 1);
+        assert_eq!(parse(&format!("wait {}\nwait 1", MAX_FRAME)).unwrap_err().line, 2);
+        assert_eq!(parse(&format!("frame {}: press 1", MAX_FRAME)).unwrap().len(), MAX_FRAME + 1);
+    }
+}
@@ -0,0 +1,15 @@
+pub mod config;
+pub mod cpu;
+pub mod crash;
+pub mod display;
+pub mod disasm;
+pub mod emulator;
+pub mod headless;
+pub mod input_script;
+pub mod memory;
+pub mod opcode_filter;
+pub mod quirks;
+pub mod replay;
+pub mod save_slots;
+pub mod state;
+pub mod trace;
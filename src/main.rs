@@ -1,47 +1,328 @@
-mod cpu;
-mod display;
-mod emulator;
-mod memory;
-
-use std::{error::Error, path::PathBuf, thread};
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::collections::{HashSet, VecDeque};
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::io::Write;
+use std::time::{Duration, Instant};
+use std::{error::Error, path::{Path, PathBuf}, thread};
 
 use eframe::egui::{self};
 use tokio::sync::mpsc;
 
-use crate::emulator::Emulator;
+use pico_rs_8::config;
+use pico_rs_8::crash;
+use pico_rs_8::cpu::{CpuView, SpriteReadOverflow, DEFAULT_TIMER_HZ};
+use pico_rs_8::disasm::{self, ListingLine};
+use pico_rs_8::display::{Palette, PixelBuffer, Plane, MAX_WIDTH};
+use pico_rs_8::emulator::{self, Emulator, EmulatorCommand, EmulatorEvent, LogLevel, OpcodeCounts, DEFAULT_CPU_HZ};
+use pico_rs_8::headless::{HeadlessEmulator, StopReason};
+use pico_rs_8::input_script;
+use pico_rs_8::memory::{DEFAULT_RAM_SIZE, MAX_RAM_SIZE};
+use pico_rs_8::opcode_filter::{self, OpcodeFilter, OpcodePattern};
+use pico_rs_8::quirks::{LoadStoreIncrement, Quirks};
+use pico_rs_8::replay::ReplayBundle;
+use pico_rs_8::trace::{self, TraceEntry};
+use pico_rs_8::save_slots::{self, SaveSlotManager, SlotMetadata, SLOT_COUNT};
+use pico_rs_8::state::{EmulatorState, StateDiff};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            std::process::exit(2);
+        }
+    };
+    // Fail before opening a window rather than start with no ROM
+    if let Some(rom_path) = args.rom.as_ref()
+        && !rom_path.is_file()
+    {
+        eprintln!("ROM not found: {}", rom_path.display());
+        std::process::exit(1);
+    }
+
+    if args.headless {
+        let Some(rom_path) = args.rom else {
+            eprintln!("--headless needs a ROM\n{}", USAGE);
+            std::process::exit(2);
+        };
+        if let Err(err) = run_headless(rom_path, args.cycles, args.max_time, args.ascii, args.raw_stream) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     let options = eframe::NativeOptions::default();
 
     eframe::run_native(
         "Rust Chip8 Emulator",
         options,
         Box::new(move |_cc| {
-            Ok(Box::new(Pico8Emulator::new()))
+            let mut app: Pico8Emulator = Pico8Emulator::new();
+            if let Some(rom_path) = args.rom {
+                app.rom_selected(rom_path);
+            }
+            Ok(Box::new(app))
         }),
     )?;
     Ok(())
 }
 
-const WIDTH: usize = 64;
-const HEIGHT: usize = 32;
+const USAGE: &str = "Usage: pico-rs-8 [--headless] [--cycles N] [--max-seconds S] [--ascii | --raw-stream] [ROM]";
+
+// Cycles --headless runs when --cycles isn't given
+const DEFAULT_HEADLESS_CYCLES: u64 = 10_000;
+
+// Least time between --ascii frames, a terminal can't keep up with every one
+const ASCII_FRAME_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Args {
+    rom: Option<PathBuf>, // Loaded and started right away
+    headless: bool, // Run the ROM without a window and print the screen
+    cycles: u64, // How long --headless runs for
+    max_time: Option<Duration>, // Stops --headless sooner if it runs this long
+    ascii: bool, // Redraw the screen in the terminal as --headless runs
+    raw_stream: bool, // Write every --headless frame to stdout, see Display::to_raw_frame
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed: Args = Args { rom: None, headless: false, cycles: DEFAULT_HEADLESS_CYCLES, max_time: None, ascii: false, raw_stream: false };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--headless" => parsed.headless = true,
+            "--ascii" => parsed.ascii = true,
+            "--raw-stream" => parsed.raw_stream = true,
+            "--cycles" => {
+                let value: String = args.next().ok_or("--cycles needs a number")?;
+                parsed.cycles = value.parse().map_err(|_| format!("'{}' is not a number of cycles", value))?;
+            }
+            "--max-seconds" => {
+                let value: String = args.next().ok_or("--max-seconds needs a number")?;
+                let seconds: Option<Duration> = value.parse().ok().and_then(|seconds| Duration::try_from_secs_f64(seconds).ok());
+                parsed.max_time = Some(seconds.ok_or_else(|| format!("'{}' is not a number of seconds", value))?);
+            }
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _ if parsed.rom.is_some() => return Err(format!("Only one ROM can be given, got {} too", arg)),
+            _ => parsed.rom = Some(PathBuf::from(arg)),
+        }
+    }
+    if (parsed.ascii || parsed.raw_stream) && !parsed.headless {
+        return Err("--ascii and --raw-stream only work with --headless".to_string());
+    }
+    if parsed.ascii && parsed.raw_stream {
+        return Err("--ascii and --raw-stream both write to stdout, pick one".to_string());
+    }
+    Ok(parsed)
+}
+
+// Run a ROM for a number of cycles, or until max_time passes or it exits, and print the final
+// screen, the registers and why it stopped. With ascii the screen is also redrawn as it runs. With
+// raw_stream every frame goes to stdout instead, and the rest is printed to stderr.
+fn run_headless(rom_path: PathBuf, cycles: u64, max_time: Option<Duration>, ascii: bool, raw_stream: bool) -> Result<(), String> {
+    let rom: Vec<u8> = std::fs::read(&rom_path).map_err(|err| format!("Could not read {}: {}", rom_path.display(), err))?;
+    // Drawing sends frames with blocking_send, which can't run on a tokio runtime thread
+    let result: thread::Result<Result<String, String>> = thread::spawn(move || {
+        let mut headless: HeadlessEmulator = HeadlessEmulator::new(&rom).map_err(|err| err.to_string())?;
+        let mut last_ascii_frame: Option<Instant> = None;
+        let mut stream: std::io::BufWriter<std::io::StdoutLock> = std::io::BufWriter::new(std::io::stdout().lock());
+        let mut stream_error: Option<std::io::Error> = None;
+        let reason: StopReason = headless.run_until_each_frame(cycles, max_time, |display| {
+            if ascii && last_ascii_frame.is_none_or(|shown| shown.elapsed() >= ASCII_FRAME_INTERVAL) {
+                last_ascii_frame = Some(Instant::now());
+                print!("{}", display.to_ascii());
+                let _ = std::io::stdout().flush();
+            }
+            // After a failed write, such as the reader going away, the rest of the frames are dropped
+            if raw_stream && stream_error.is_none() {
+                stream_error = stream.write_all(&display.to_raw_frame()).err();
+            }
+        });
+        if let Some(err) = stream_error.or_else(|| stream.flush().err()) {
+            return Err(format!("Could not write the frame stream: {}", err));
+        }
+        drop(stream);
+        let stopped: String = match reason {
+            StopReason::CycleLimit => format!("the {} cycle limit was reached", cycles),
+            StopReason::TimeLimit => format!("the {} second time limit was reached", max_time.unwrap_or_default().as_secs_f64()),
+            StopReason::ProgramEnded => {
+                let pc: Option<u16> = headless.take_events().into_iter().find_map(|event| match event {
+                    EmulatorEvent::ProgramEnded { pc } => Some(pc),
+                    _ => None,
+                });
+                pc.map_or("the program ended".to_string(), |pc| format!("the program ended with 00FD at {:04X}", pc))
+            }
+        };
+
+        let mut report: String = String::new();
+        for row in headless.screen() {
+            report.push_str(&format!("{}\n", row));
+        }
+        let cpu: CpuView = headless.cpu();
+        report.push_str(&format!("PC={:04X} I={:04X} SP={}\n", cpu.pc, cpu.i, cpu.sp));
+        report.push_str(&format!("V={}\n", cpu.v.iter().map(|value| format!("{:02X}", value)).collect::<Vec<String>>().join(" ")));
+        report.push_str(&format!("Stopped after {} cycles: {}", headless.emulator.cycles, stopped));
+        Ok(report)
+    })
+    .join();
+    let report: String = result.map_err(|_| "The emulator crashed".to_string())??;
+    if raw_stream {
+        eprintln!("{}", report);
+    } else {
+        println!("{}", report);
+    }
+    Ok(())
+}
+
+// Screen pixels per CHIP-8 pixel in the planes window
+const PLANE_VIEW_SCALE: f32 = 3.0;
+
+// How long a changed register stays highlighted in the register panel
+const HIGHLIGHT_FADE_SECONDS: f64 = 1.0;
+// Slots in register_changed_at after V0 to VF
+const REGISTER_I: usize = 16;
+const REGISTER_PC: usize = 17;
+const REGISTER_SP: usize = 18;
+const REGISTER_DT: usize = 19;
+const REGISTER_ST: usize = 20;
+
+// Lines kept in the log panel
+const LOG_CAPACITY: usize = 500;
+
+// Commands the emulator hasn't taken yet. restart_emulator sends every setting and breakpoint at once.
+const COMMAND_CAPACITY: usize = 256;
+
+// Settings key for the register panel number format
+const REGISTER_FORMAT_SETTING: &str = "register_format";
+
+// Settings key for how keyboard keys map to the keypad
+const KEY_MAPPING_SETTING: &str = "key_mapping";
+
+// Settings key for the keypad bindings that differ from KeyMap::default
+const KEY_MAP_SETTING: &str = "key_map";
+
+// Settings key prefix for the display palette, one palette per plane count, e.g. palette_1
+const PALETTE_SETTING: &str = "palette";
+
+// The COSMAC VIP keypad layout, row by row
+const KEYPAD_LAYOUT: [[usize; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+
+// Resumes after a pause from "Pause after each draw"
+const RESUME_KEY: egui::Key = egui::Key::Space;
+
+// Starts a new lap, see Pico8Emulator::lap_start
+const LAP_KEY: egui::Key = egui::Key::L;
+
+// Holding this key overlays a coordinate grid on the display
+const GRID_KEY: egui::Key = egui::Key::G;
+
+// Holding this key steps the emulator back one frame per GUI frame
+const REWIND_KEY: egui::Key = egui::Key::Backspace;
+
+// 10 seconds at 60 Hz
+const DEFAULT_REWIND_FRAMES: usize = 600;
+
+// F1 to F10 select save slots 0 to 9
+const SLOT_KEYS: [egui::Key; SLOT_COUNT] = [
+    egui::Key::F1,
+    egui::Key::F2,
+    egui::Key::F3,
+    egui::Key::F4,
+    egui::Key::F5,
+    egui::Key::F6,
+    egui::Key::F7,
+    egui::Key::F8,
+    egui::Key::F9,
+    egui::Key::F10,
+];
 
 struct Pico8Emulator {
     selected_file: Option<String>,
     selected_font_file: Option<String>,
     requested_quit: bool,
     pixels: PixelBuffer,
+    previous_pixels: PixelBuffer, // The frame before pixels, for clean captures
     frame_buffer_receiver: mpsc::Receiver<PixelBuffer>,
-    file_content_sender: mpsc::Sender<Vec<u8>>,
     font_file_content_sender: mpsc::Sender<Vec<u8>>,
-    keys_sender: mpsc::Sender<[bool; 16]>,
+    keys: Arc<AtomicU16>, // Shared with the emulator, see EmulatorData::keys
+    cycles: Arc<AtomicU64>, // Shared with the emulator, see EmulatorData::cycles
+    lap_start: u64, // Cycle count when the current lap was marked
+    command_sender: mpsc::Sender<EmulatorCommand>,
+    event_receiver: mpsc::Receiver<EmulatorEvent>,
+    status_message: Option<String>,
+    program_ended: bool, // The ROM ran 00FD, the status bar offers a reset
+    clear_display_on_reset: bool,
+    allow_rom_truncation: bool,
+    chip8e: bool,
+    schip: bool,
+    xochip: bool,
+    timer_hz: u32,
+    clock_hz: u32, // Instructions per second, 0 for unlimited
+    rewind_frames: usize, // Frames of rewind the emulator keeps, 0 for none
+    rewinding: bool, // REWIND_KEY is held, the emulator is held paused meanwhile
+    fx0a_min_hold_frames: u32,
+    font_char_size: u8,
+    sprite_read_overflow: SpriteReadOverflow,
+    opcode_filter_text: String, // Opcode patterns as typed in the Settings menu
+    opcode_filter_allow: bool, // The patterns are the only opcodes allowed, rather than the ones denied
+    opcode_filter: OpcodeFilter,
+    cpu_state_receiver: mpsc::Receiver<CpuView>,
+    cpu_state: Option<CpuView>,
+    opcode_counts_receiver: mpsc::Receiver<OpcodeCounts>,
+    opcode_counts: OpcodeCounts, // Latest counts from the emulator, for the histogram
+    show_opcode_histogram: bool,
+    recent_opcodes_receiver: mpsc::Receiver<Vec<TraceEntry>>,
+    recent_opcodes: Vec<TraceEntry>, // Latest instructions from the emulator, oldest first, for the timeline
+    show_opcode_timeline: bool,
+    log: VecDeque<(LogLevel, String)>, // The last LOG_CAPACITY emulator events, oldest first
+    show_log: bool,
+    palette: Palette,
+    filtering: Filtering,
+    display_transform: DisplayTransform,
+    capture: Capture,
+    display_texture: Option<egui::TextureHandle>, // The display, updated from pixels every frame
+    pause_after_draw: bool,
+    spin_idle: bool,
+    double_buffered: bool,
+    quirks: Quirks,
+    show_quirks: bool,
+    rom_browser: Option<(PathBuf, Vec<(PathBuf, u64)>)>, // Folder being browsed and its ROMs with their sizes
+    ram_size: usize,
+    watchdog: bool,
+    watchdog_threshold: u64,
+    show_registers: bool,
+    register_format: NumberFormat,
+    key_mapping: KeyMapping,
+    key_map: KeyMap, // Keyboard key for each CHIP-8 key
+    rebinding_key: Option<usize>, // CHIP-8 key the input test is waiting to bind to the next key press
+    physical_keys_held: [bool; 16], // Keypad keys held by position, tracked from key events for KeyMapping::Physical
+    show_stack: bool,
+    register_changed_at: [f64; 21], // Time each register last changed, V0 to VF then I, PC, SP, DT, ST
+    stack_changed_at: [f64; 16], // Time each stack slot was last pushed or popped
+    save_slots: Option<SaveSlotManager>,
+    slot_metadata: [Option<SlotMetadata>; SLOT_COUNT],
+    state_diff: Option<StateDiff>,
+    grid_spacing: usize, // Pixels between gridlines in the coordinate overlay
+    rom: Vec<u8>, // The loaded ROM, for the disassembly
+    show_disassembly: bool,
+    show_planes: bool, // Shows plane 0 and plane 1 on their own, for XO-CHIP
+    plane_textures: [Option<egui::TextureHandle>; 2], // The planes window's images, updated from pixels every frame
+    follow_control_flow: bool, // Only show reachable instructions as code in the disassembly
+    breakpoints: HashSet<u16>, // The GUI's copy of the emulator's breakpoints
+    paused: bool,
+    show_input_test: bool, // Replaces the display with the keypad tester and holds the emulator
     emulator_thread: thread::JoinHandle<()>,
 }
 
 impl Drop for Pico8Emulator {
     fn drop(&mut self) {
-        if self.emulator_thread.is_finished() == false {
+        if !self.emulator_thread.is_finished() {
             // If the thread is still running, we should probably do something to stop it
             // For now, we'll just detach it
             self.emulator_thread.thread().unpark();
@@ -53,55 +334,228 @@ impl Drop for Pico8Emulator {
 impl Pico8Emulator {
     fn new(
     ) -> Self {
-        let frame_buffer_channel: (mpsc::Sender<PixelBuffer>, mpsc::Receiver<PixelBuffer>) =
-            mpsc::channel::<PixelBuffer>(1);
-        let keys_channel: (mpsc::Sender<[bool; 16]>, mpsc::Receiver<[bool; 16]>) =
-            mpsc::channel::<[bool; 16]>(1);
-        let rom_content_channel: (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) =
-            mpsc::channel::<Vec<u8>>(1);
-        let font_content_channel: (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) =
-            mpsc::channel::<Vec<u8>>(1);
-
-
-        let mut emulator: emulator::Emulator = emulator::Emulator::new(emulator::EmulatorData {
-            file_content: rom_content_channel.1,
-            font_file_content: font_content_channel.1,
-            keys: keys_channel.1,
-        }, frame_buffer_channel.0);
-        emulator.set_font(Emulator::get_default_font());
-        
-
-        let emulator_thread: thread::JoinHandle<()> = thread::spawn(move || {
-            loop {
-                emulator.cycle();
-                // Thread sleeping until we want to FPS sleep again
-            }
-        });
+        let (link, emulator_thread): (EmulatorLink, thread::JoinHandle<()>) = spawn_emulator();
 
         Pico8Emulator {
             selected_file: None,
             selected_font_file: None,
             requested_quit: false,
             pixels: PixelBuffer::default(),
-            frame_buffer_receiver: frame_buffer_channel.1,
-            keys_sender: keys_channel.0,
-            file_content_sender: rom_content_channel.0,
-            font_file_content_sender: font_content_channel.0,
-            emulator_thread: emulator_thread,
+            previous_pixels: PixelBuffer::default(),
+            frame_buffer_receiver: link.frame_buffer_receiver,
+            keys: link.keys,
+            cycles: link.cycles,
+            lap_start: 0,
+            font_file_content_sender: link.font_file_content_sender,
+            command_sender: link.command_sender,
+            event_receiver: link.event_receiver,
+            status_message: None,
+            program_ended: false,
+            clear_display_on_reset: true,
+            allow_rom_truncation: false,
+            chip8e: false,
+            schip: false,
+            xochip: false,
+            timer_hz: DEFAULT_TIMER_HZ,
+            clock_hz: DEFAULT_CPU_HZ,
+            rewind_frames: DEFAULT_REWIND_FRAMES,
+            rewinding: false,
+            fx0a_min_hold_frames: 0,
+            font_char_size: 5,
+            sprite_read_overflow: SpriteReadOverflow::default(),
+            opcode_filter_text: String::new(),
+            opcode_filter_allow: false,
+            opcode_filter: OpcodeFilter::AllowAll,
+            cpu_state_receiver: link.cpu_state_receiver,
+            cpu_state: None,
+            opcode_counts_receiver: link.opcode_counts_receiver,
+            opcode_counts: Vec::new(),
+            show_opcode_histogram: false,
+            recent_opcodes_receiver: link.recent_opcodes_receiver,
+            recent_opcodes: Vec::new(),
+            show_opcode_timeline: false,
+            log: VecDeque::with_capacity(LOG_CAPACITY),
+            show_log: false,
+            palette: saved_palette(1).unwrap_or_else(Palette::chip8),
+            filtering: Filtering::Nearest,
+            display_transform: DisplayTransform::None,
+            capture: Capture::Authentic,
+            display_texture: None,
+            pause_after_draw: false,
+            spin_idle: false,
+            double_buffered: false,
+            quirks: Quirks::default(),
+            show_quirks: false,
+            rom_browser: None,
+            ram_size: DEFAULT_RAM_SIZE,
+            watchdog: false,
+            watchdog_threshold: 1_000_000,
+            show_registers: false,
+            register_format: config::load_setting(REGISTER_FORMAT_SETTING)
+                .and_then(|name| NumberFormat::from_name(&name))
+                .unwrap_or(NumberFormat::Hex),
+            key_mapping: config::load_setting(KEY_MAPPING_SETTING)
+                .and_then(|name| KeyMapping::from_name(&name))
+                .unwrap_or(KeyMapping::Logical),
+            key_map: config::load_setting(KEY_MAP_SETTING)
+                .map(|setting| KeyMap::from_setting(&setting))
+                .unwrap_or_default(),
+            rebinding_key: None,
+            physical_keys_held: [false; 16],
+            show_stack: false,
+            register_changed_at: [f64::NEG_INFINITY; 21],
+            stack_changed_at: [f64::NEG_INFINITY; 16],
+            save_slots: None,
+            slot_metadata: [None; SLOT_COUNT],
+            state_diff: None,
+            grid_spacing: 8,
+            rom: Vec::new(),
+            show_disassembly: false,
+            show_planes: false,
+            plane_textures: [None, None],
+            follow_control_flow: true,
+            breakpoints: HashSet::new(),
+            paused: false,
+            show_input_test: false,
+            emulator_thread,
         }
     }
+
+    // Replace a dead emulator thread with a fresh one and bring it back to where the GUI is
+    fn restart_emulator(&mut self) {
+        let (link, emulator_thread): (EmulatorLink, thread::JoinHandle<()>) = spawn_emulator();
+        self.frame_buffer_receiver = link.frame_buffer_receiver;
+        self.keys = link.keys;
+        self.cycles = link.cycles;
+        self.font_file_content_sender = link.font_file_content_sender;
+        self.command_sender = link.command_sender;
+        self.event_receiver = link.event_receiver;
+        self.cpu_state_receiver = link.cpu_state_receiver;
+        self.opcode_counts_receiver = link.opcode_counts_receiver;
+        self.recent_opcodes_receiver = link.recent_opcodes_receiver;
+        self.emulator_thread = emulator_thread;
+        self.cpu_state = None;
+
+        let commands: [EmulatorCommand; 21] = [
+            EmulatorCommand::SetClearDisplayOnReset(self.clear_display_on_reset),
+            EmulatorCommand::SetAllowRomTruncation(self.allow_rom_truncation),
+            EmulatorCommand::SetChip8E(self.chip8e),
+            EmulatorCommand::SetSchip(self.schip),
+            EmulatorCommand::SetXoChip(self.xochip),
+            EmulatorCommand::SetFx0aMinHoldFrames(self.fx0a_min_hold_frames),
+            EmulatorCommand::SetFontCharSize(self.font_char_size),
+            EmulatorCommand::SetTimerHz(self.timer_hz),
+            EmulatorCommand::SetClockHz(self.clock_hz),
+            EmulatorCommand::SetRewindCapacity(self.rewind_frames),
+            EmulatorCommand::SetSpriteReadOverflow(self.sprite_read_overflow),
+            EmulatorCommand::SetPaused(self.paused || self.show_input_test),
+            EmulatorCommand::SetOpcodeFilter(self.opcode_filter.clone()),
+            EmulatorCommand::SetSendOpcodeCounts(self.show_opcode_histogram),
+            EmulatorCommand::SetSendRecentOpcodes(self.show_opcode_timeline),
+            EmulatorCommand::SetPauseAfterDraw(self.pause_after_draw),
+            EmulatorCommand::SetSpinIdle(self.spin_idle),
+            EmulatorCommand::SetDoubleBuffered(self.double_buffered),
+            EmulatorCommand::SetQuirks(self.quirks),
+            EmulatorCommand::SetRamSize(self.ram_size),
+            EmulatorCommand::SetWatchdogThreshold(self.watchdog.then_some(self.watchdog_threshold)),
+        ];
+        for command in commands {
+            let _ = self.command_sender.try_send(command);
+        }
+        for &address in &self.breakpoints {
+            let _ = self.command_sender.try_send(EmulatorCommand::AddBreakpoint(address));
+        }
+        if let Some(font_path) = self.selected_font_file.as_ref() {
+            let _ = self.font_file_content_sender.try_send(std::fs::read(font_path).unwrap_or_default());
+        }
+        // The ROM starts over from the beginning
+        if !self.rom.is_empty() {
+            let _ = self.command_sender.try_send(EmulatorCommand::LoadRom(self.rom.clone()));
+        }
+        self.status_message = Some("Emulator restarted".to_string());
+    }
 }
 
-struct PixelBuffer {
-    pixels: [[bool; WIDTH]; HEIGHT],
+// The GUI's ends of the channels to the emulator thread
+struct EmulatorLink {
+    frame_buffer_receiver: mpsc::Receiver<PixelBuffer>,
+    keys: Arc<AtomicU16>,
+    cycles: Arc<AtomicU64>,
+    font_file_content_sender: mpsc::Sender<Vec<u8>>,
+    command_sender: mpsc::Sender<EmulatorCommand>,
+    event_receiver: mpsc::Receiver<EmulatorEvent>,
+    cpu_state_receiver: mpsc::Receiver<CpuView>,
+    opcode_counts_receiver: mpsc::Receiver<OpcodeCounts>,
+    recent_opcodes_receiver: mpsc::Receiver<Vec<TraceEntry>>,
 }
 
-impl Default for PixelBuffer {
-    fn default() -> Self {
-        PixelBuffer {
-            pixels: [[false; WIDTH]; HEIGHT],
+// Create the channels and start an emulator running on its own thread
+fn spawn_emulator() -> (EmulatorLink, thread::JoinHandle<()>) {
+    let frame_buffer_channel: (mpsc::Sender<PixelBuffer>, mpsc::Receiver<PixelBuffer>) =
+        mpsc::channel::<PixelBuffer>(1);
+    let keys: Arc<AtomicU16> = Arc::new(AtomicU16::new(0));
+    let cycles: Arc<AtomicU64> = Arc::new(AtomicU64::new(0));
+    let font_content_channel: (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Vec<u8>>) =
+        mpsc::channel::<Vec<u8>>(1);
+    let command_channel: (mpsc::Sender<EmulatorCommand>, mpsc::Receiver<EmulatorCommand>) =
+        mpsc::channel::<EmulatorCommand>(COMMAND_CAPACITY);
+    let event_channel: (mpsc::Sender<EmulatorEvent>, mpsc::Receiver<EmulatorEvent>) =
+        mpsc::channel::<EmulatorEvent>(16);
+    let cpu_state_channel: (mpsc::Sender<CpuView>, mpsc::Receiver<CpuView>) =
+        mpsc::channel::<CpuView>(1);
+    let opcode_counts_channel: (mpsc::Sender<OpcodeCounts>, mpsc::Receiver<OpcodeCounts>) =
+        mpsc::channel::<OpcodeCounts>(1);
+    let recent_opcodes_channel: (mpsc::Sender<Vec<TraceEntry>>, mpsc::Receiver<Vec<TraceEntry>>) =
+        mpsc::channel::<Vec<TraceEntry>>(1);
+
+    let mut emulator: emulator::Emulator = emulator::Emulator::new(emulator::EmulatorData {
+        font_file_content: font_content_channel.1,
+        keys: Arc::clone(&keys),
+        commands: command_channel.1,
+        events: event_channel.0,
+        cpu_state: cpu_state_channel.0,
+        opcode_counts: opcode_counts_channel.0,
+        recent_opcodes: recent_opcodes_channel.0,
+        cycles: Arc::clone(&cycles),
+        cpu_hz: DEFAULT_CPU_HZ,
+    }, frame_buffer_channel.0, Quirks::default());
+    emulator.set_font(&Emulator::get_default_font());
+    emulator.persist_flags = true;
+
+    let emulator_thread: thread::JoinHandle<()> = thread::spawn(move || {
+        let panic_message: Arc<Mutex<Option<String>>> = crash::capture_panic_message();
+        let result: thread::Result<()> = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            loop {
+                emulator.cycle();
+                // cycle() sleeps as needed to keep to the clock speed
+            }
+        }));
+        // The thread is about to end, leave a report for the GUI to point at
+        if result.is_err() {
+            let message: String = panic_message
+                .lock()
+                .ok()
+                .and_then(|message| message.clone())
+                .unwrap_or_else(|| "Emulator panicked".to_string());
+            match crash::write_crash_report(&message, &emulator) {
+                Ok(path) => println!("Crash report written to {}", path.display()),
+                Err(err) => println!("Could not write crash report: {}", err),
+            }
         }
-    }
+    });
+
+    let link: EmulatorLink = EmulatorLink {
+        frame_buffer_receiver: frame_buffer_channel.1,
+        keys,
+        cycles,
+        font_file_content_sender: font_content_channel.0,
+        command_sender: command_channel.0,
+        event_receiver: event_channel.1,
+        cpu_state_receiver: cpu_state_channel.1,
+        opcode_counts_receiver: opcode_counts_channel.1,
+        recent_opcodes_receiver: recent_opcodes_channel.1,
+    };
+    (link, emulator_thread)
 }
 
 impl eframe::App for Pico8Emulator {
@@ -124,6 +578,16 @@ impl eframe::App for Pico8Emulator {
                         ui.close();
                     }
 
+                    if ui.button("Open Folder").clicked() {
+                        if let Some(folder) = rfd::FileDialog::new().pick_folder() {
+                            match find_roms(&folder) {
+                                Ok(roms) => self.rom_browser = Some((folder, roms)),
+                                Err(err) => self.status_message = Some(format!("Could not read folder: {}", err)),
+                            }
+                        }
+                        ui.close();
+                    }
+
                     if ui.button("Font File").clicked() {
                         if let Some(font_path) = rfd::FileDialog::new().pick_file() {
                             self.selected_font_file = Some(font_path.display().to_string());
@@ -135,96 +599,595 @@ impl eframe::App for Pico8Emulator {
                         }
                         ui.close();
                     }
+                    if ui.button("Input Script").clicked() {
+                        if let Some(script_path) = rfd::FileDialog::new().pick_file() {
+                            self.load_input_script(script_path);
+                        }
+                        ui.close();
+                    }
+                    if ui.add_enabled(self.selected_file.is_some(), egui::Button::new("Reset")).on_hover_text("Reload the ROM into cleared RAM").clicked() {
+                        let _ = self.command_sender.try_send(EmulatorCommand::ColdReset);
+                        ui.close();
+                    }
+                    if ui.add_enabled(self.selected_file.is_some(), egui::Button::new("Warm Reset")).on_hover_text("Restart the CPU, keeping RAM as the ROM left it").clicked() {
+                        let _ = self.command_sender.try_send(EmulatorCommand::WarmReset);
+                        self.program_ended = false;
+                        ui.close();
+                    }
+                    if ui.add_enabled(self.selected_file.is_some(), egui::Button::new("Export SVG")).clicked() {
+                        if let Some(svg_path) = rfd::FileDialog::new().add_filter("SVG", &["svg"]).save_file()
+                            && let Err(err) = std::fs::write(&svg_path, self.capture.frame(&self.pixels, &self.previous_pixels).to_svg(&self.palette))
+                        {
+                            self.status_message = Some(format!("Could not export SVG: {}", err));
+                        }
+                        ui.close();
+                    }
+                    if ui.add_enabled(self.selected_file.is_some(), egui::Button::new("Save Replay")).clicked() {
+                        if let Some(replay_path) = rfd::FileDialog::new().add_filter("Replay", &["p8replay"]).save_file() {
+                            let _ = self.command_sender.try_send(EmulatorCommand::SaveReplay(replay_path));
+                        }
+                        ui.close();
+                    }
+                    if ui.button("Reproduce Replay").clicked() {
+                        if let Some(replay_path) = rfd::FileDialog::new().add_filter("Replay", &["p8replay"]).pick_file() {
+                            self.reproduce_replay(replay_path);
+                        }
+                        ui.close();
+                    }
                     if ui.button("Exit").clicked() {
                         // Close the application
                         self.requested_quit = true;
                     }
                 });
+                ui.menu_button("Settings", |ui| {
+                    if ui.checkbox(&mut self.clear_display_on_reset, "Clear display on reset").changed() {
+                        let _ = self.command_sender.try_send(EmulatorCommand::SetClearDisplayOnReset(self.clear_display_on_reset));
+                    }
+                    if ui.checkbox(&mut self.allow_rom_truncation, "Truncate oversized ROMs").changed() {
+                        let _ = self.command_sender.try_send(EmulatorCommand::SetAllowRomTruncation(self.allow_rom_truncation));
+                    }
+                    if ui.checkbox(&mut self.chip8e, "CHIP-8E opcodes").changed() {
+                        let _ = self.command_sender.try_send(EmulatorCommand::SetChip8E(self.chip8e));
+                    }
+                    if ui.checkbox(&mut self.schip, "SUPER-CHIP opcodes").changed() {
+                        let _ = self.command_sender.try_send(EmulatorCommand::SetSchip(self.schip));
+                    }
+                    if ui.checkbox(&mut self.xochip, "XO-CHIP opcodes").changed() {
+                        let _ = self.command_sender.try_send(EmulatorCommand::SetXoChip(self.xochip));
+                        self.match_palette_to_planes();
+                    }
+                    ui.horizontal(|ui| {
+                        ui.label("RAM");
+                        let ram_size: usize = self.ram_size;
+                        ui.radio_value(&mut self.ram_size, DEFAULT_RAM_SIZE, "4 KB");
+                        ui.radio_value(&mut self.ram_size, MAX_RAM_SIZE, "64 KB (XO-CHIP)");
+                        if self.ram_size != ram_size {
+                            let _ = self.command_sender.try_send(EmulatorCommand::SetRamSize(self.ram_size));
+                            // Changing the RAM resets the machine, start the ROM again
+                            if !self.rom.is_empty() {
+                                let _ = self.command_sender.try_send(EmulatorCommand::LoadRom(self.rom.clone()));
+                            }
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        let mut changed: bool = ui.checkbox(&mut self.watchdog, "Warn when stuck after").changed();
+                        changed |= ui.add(egui::DragValue::new(&mut self.watchdog_threshold).range(1000..=u64::MAX).speed(1000)).changed();
+                        ui.label("instructions");
+                        if changed {
+                            let threshold: Option<u64> = self.watchdog.then_some(self.watchdog_threshold);
+                            let _ = self.command_sender.try_send(EmulatorCommand::SetWatchdogThreshold(threshold));
+                        }
+                    });
+                    if ui.checkbox(&mut self.spin_idle, "Idle on jump-to-self loops").on_hover_text("Saves CPU, but the cycle count stops").changed() {
+                        let _ = self.command_sender.try_send(EmulatorCommand::SetSpinIdle(self.spin_idle));
+                    }
+                    ui.checkbox(&mut self.show_quirks, "Quirks");
+                    if ui.checkbox(&mut self.double_buffered, "Only show complete frames").on_hover_text("Draws appear at the end of each 60 Hz frame instead of right away").changed() {
+                        let _ = self.command_sender.try_send(EmulatorCommand::SetDoubleBuffered(self.double_buffered));
+                    }
+                    ui.horizontal(|ui| {
+                        ui.label("CPU clock (Hz)");
+                        let slider: egui::Slider = egui::Slider::new(&mut self.clock_hz, 0..=5000)
+                            .custom_formatter(|hz, _| if hz == 0.0 { "Unlimited".to_string() } else { format!("{}", hz) });
+                        if ui.add(slider).changed() {
+                            let _ = self.command_sender.try_send(EmulatorCommand::SetClockHz(self.clock_hz));
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Rewind (frames)");
+                        let rewind: egui::Response = ui.add(egui::DragValue::new(&mut self.rewind_frames).range(0..=3600));
+                        if rewind.on_hover_text("Hold Backspace to step back, 0 turns rewind off").changed() {
+                            let _ = self.command_sender.try_send(EmulatorCommand::SetRewindCapacity(self.rewind_frames));
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Timer rate (Hz)");
+                        if ui.add(egui::DragValue::new(&mut self.timer_hz).range(1..=1000)).changed() {
+                            let _ = self.command_sender.try_send(EmulatorCommand::SetTimerHz(self.timer_hz));
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Frames to hold a key for FX0A");
+                        if ui.add(egui::DragValue::new(&mut self.fx0a_min_hold_frames).range(0..=120)).changed() {
+                            let _ = self.command_sender.try_send(EmulatorCommand::SetFx0aMinHoldFrames(self.fx0a_min_hold_frames));
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        let key_mapping: KeyMapping = self.key_mapping;
+                        ui.label("Keypad keys");
+                        ui.radio_value(&mut self.key_mapping, KeyMapping::Logical, "By label");
+                        ui.radio_value(&mut self.key_mapping, KeyMapping::Physical, "By position")
+                            .on_hover_text("For AZERTY, QWERTZ and other layouts");
+                        if self.key_mapping != key_mapping {
+                            self.physical_keys_held = [false; 16];
+                            let _ = config::save_setting(KEY_MAPPING_SETTING, self.key_mapping.name());
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        let sprite_read_overflow: SpriteReadOverflow = self.sprite_read_overflow;
+                        ui.label("Sprites past the end of RAM");
+                        ui.radio_value(&mut self.sprite_read_overflow, SpriteReadOverflow::Wrap, "Wrap");
+                        ui.radio_value(&mut self.sprite_read_overflow, SpriteReadOverflow::Clamp, "Cut off");
+                        ui.radio_value(&mut self.sprite_read_overflow, SpriteReadOverflow::WarnAndClamp, "Cut off and warn");
+                        if self.sprite_read_overflow != sprite_read_overflow {
+                            let _ = self.command_sender.try_send(EmulatorCommand::SetSpriteReadOverflow(self.sprite_read_overflow));
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        // 16 characters of up to 27 bytes fit between the font address and the ROM
+                        ui.label("Bytes per font character");
+                        if ui.add(egui::DragValue::new(&mut self.font_char_size).range(1..=27)).changed() {
+                            let _ = self.command_sender.try_send(EmulatorCommand::SetFontCharSize(self.font_char_size));
+                        }
+                    });
+                    ui.separator();
+                    ui.horizontal(|ui| {
+                        ui.label("Opcodes");
+                        ui.text_edit_singleline(&mut self.opcode_filter_text).on_hover_text("e.g. DXYN, 00FF, FX1E");
+                    });
+                    ui.horizontal(|ui| {
+                        ui.radio_value(&mut self.opcode_filter_allow, false, "Deny listed");
+                        ui.radio_value(&mut self.opcode_filter_allow, true, "Allow only listed");
+                        if ui.button("Apply").clicked() {
+                            self.apply_opcode_filter();
+                        }
+                    });
+                    ui.separator();
+                    let palette: Palette = self.palette.clone();
+                    ui.menu_button("Palette", |ui| {
+                        for (name, palette) in Palette::well_known() {
+                            // Only palettes for the display's plane count fit
+                            if palette.planes() == self.palette.planes() {
+                                let selected: bool = palette == self.palette;
+                                if ui.selectable_label(selected, name).clicked() {
+                                    self.palette = palette;
+                                }
+                            }
+                        }
+                        ui.separator();
+                        // One swatch per plane combination, background first
+                        ui.horizontal(|ui| {
+                            for index in 0..self.palette.colors().len() {
+                                let mut color: [u8; 3] = self.palette.colors()[index];
+                                if ui.color_edit_button_srgb(&mut color).changed() {
+                                    self.palette.set_color(index, color);
+                                }
+                            }
+                        });
+                        ui.separator();
+                        if ui.button("Import").clicked() {
+                            if let Some(palette_path) = rfd::FileDialog::new().add_filter("Palette", &["hex", "txt"]).pick_file() {
+                                self.import_palette(palette_path);
+                            }
+                            ui.close();
+                        }
+                        if ui.button("Export").clicked() {
+                            if let Some(palette_path) = rfd::FileDialog::new().add_filter("Palette", &["hex"]).save_file()
+                                && let Err(err) = self.palette.to_file(&palette_path)
+                            {
+                                self.status_message = Some(format!("Could not export palette: {}", err));
+                            }
+                            ui.close();
+                        }
+                    });
+                    if self.palette != palette {
+                        save_palette(&self.palette);
+                    }
+                    ui.horizontal(|ui| {
+                        ui.label("Scaling");
+                        ui.radio_value(&mut self.filtering, Filtering::Nearest, "Sharp");
+                        ui.radio_value(&mut self.filtering, Filtering::Linear, "Smooth");
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Exports");
+                        ui.radio_value(&mut self.capture, Capture::Authentic, "Authentic").on_hover_text("The frame as the ROM drew it, flicker and all");
+                        ui.radio_value(&mut self.capture, Capture::Clean, "Clean").on_hover_text("Blend in the frame before, so sprites the ROM erases and redraws are never missing");
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Orientation");
+                        ui.radio_value(&mut self.display_transform, DisplayTransform::None, "Normal");
+                        ui.radio_value(&mut self.display_transform, DisplayTransform::FlipHorizontal, "Mirrored");
+                        ui.radio_value(&mut self.display_transform, DisplayTransform::FlipVertical, "Flipped");
+                        ui.radio_value(&mut self.display_transform, DisplayTransform::Rotate180, "Rotated 180°");
+                    });
+                    ui.separator();
+                    ui.horizontal(|ui| {
+                        ui.label("Grid spacing (hold G)");
+                        ui.add(egui::DragValue::new(&mut self.grid_spacing).range(1..=MAX_WIDTH));
+                    });
+                });
+                ui.menu_button("Slots", |ui| {
+                    if self.save_slots.is_none() {
+                        ui.label("No ROM loaded");
+                        return;
+                    }
+                    for (slot, metadata) in self.slot_metadata.iter().enumerate() {
+                        let label: String = match metadata {
+                            Some(metadata) => format!("Slot {}: saved {}, {} cycles", slot, format_age(metadata.timestamp), metadata.cycles),
+                            None => format!("Slot {}: empty", slot),
+                        };
+                        ui.horizontal(|ui| {
+                            ui.label(label);
+                            if ui.button("Save").clicked() {
+                                let _ = self.command_sender.try_send(EmulatorCommand::SaveSlot(slot));
+                            }
+                            if ui.add_enabled(metadata.is_some(), egui::Button::new("Load")).clicked() {
+                                let _ = self.command_sender.try_send(EmulatorCommand::LoadSlot(slot));
+                            }
+                        });
+                    }
+                });
+                ui.menu_button("Debug", |ui| {
+                    ui.checkbox(&mut self.show_registers, "Registers");
+                    ui.checkbox(&mut self.show_stack, "Stack");
+                    ui.checkbox(&mut self.show_disassembly, "Disassembly");
+                    ui.checkbox(&mut self.show_planes, "Planes");
+                    ui.checkbox(&mut self.show_log, "Log");
+                    if ui.checkbox(&mut self.show_opcode_histogram, "Opcode histogram").changed() {
+                        let _ = self.command_sender.try_send(EmulatorCommand::SetSendOpcodeCounts(self.show_opcode_histogram));
+                    }
+                    if ui.checkbox(&mut self.show_opcode_timeline, "Opcode timeline").changed() {
+                        let _ = self.command_sender.try_send(EmulatorCommand::SetSendRecentOpcodes(self.show_opcode_timeline));
+                    }
+                    if ui.checkbox(&mut self.show_input_test, "Input test").changed() {
+                        // The emulator is held while testing and goes back to the Paused setting afterwards
+                        let paused: bool = self.show_input_test || self.paused;
+                        let _ = self.command_sender.try_send(EmulatorCommand::SetPaused(paused));
+                    }
+                    if ui.checkbox(&mut self.paused, "Paused").changed() {
+                        let _ = self.command_sender.try_send(EmulatorCommand::SetPaused(self.paused || self.show_input_test));
+                    }
+                    if ui.add_enabled(self.paused, egui::Button::new("Step")).on_hover_text("Run one instruction").clicked() {
+                        let _ = self.command_sender.try_send(EmulatorCommand::Step);
+                    }
+                    if ui.add_enabled(self.paused, egui::Button::new("Run to next draw")).clicked() {
+                        self.paused = false;
+                        let _ = self.command_sender.try_send(EmulatorCommand::RunToNextDraw);
+                    }
+                    if ui.checkbox(&mut self.pause_after_draw, "Pause after each draw (Space resumes)").changed() {
+                        let _ = self.command_sender.try_send(EmulatorCommand::SetPauseAfterDraw(self.pause_after_draw));
+                    }
+                    if ui.button("Mark lap (L)").clicked() {
+                        self.lap_start = self.cycles.load(Ordering::Relaxed);
+                        ui.close();
+                    }
+                    if ui.button("Compare against trace").clicked() {
+                        if let Some(trace_path) = rfd::FileDialog::new().pick_file() {
+                            self.load_reference_trace(trace_path);
+                        }
+                        ui.close();
+                    }
+                    if ui.button("Compare save states").clicked() {
+                        self.compare_save_states();
+                        ui.close();
+                    }
+                });
             });
         });
 
+        // The emulator thread only ends by panicking
+        if self.emulator_thread.is_finished() {
+            egui::TopBottomPanel::top("crash_banner").show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.colored_label(egui::Color32::RED, "Emulator stopped unexpectedly");
+                    if ui.button("Restart").clicked() {
+                        self.restart_emulator();
+                    }
+                });
+                ui.label(format!("A crash report was written to {}", crash::crash_report_path().display()));
+            });
+        }
+
+        // Every event goes in the log, and all but routine messages also show in the status bar
+        while let Ok(event) = self.event_receiver.try_recv() {
+            match event {
+                EmulatorEvent::SlotSaved(_) => self.refresh_slot_metadata(),
+                EmulatorEvent::ProgramEnded { .. } => self.program_ended = true,
+                EmulatorEvent::RomLoaded { .. } | EmulatorEvent::SlotLoaded(_) => self.program_ended = false,
+                EmulatorEvent::TraceMismatch(_)
+                | EmulatorEvent::OpcodeDenied { .. }
+                | EmulatorEvent::PausedAfterDraw { .. }
+                | EmulatorEvent::BreakpointHit { .. }
+                | EmulatorEvent::WatchTriggered { .. } => self.paused = true,
+                _ => {}
+            }
+            if self.log.len() == LOG_CAPACITY {
+                self.log.pop_front();
+            }
+            self.log.push_back((event.level(), event.to_string()));
+            if !matches!(event, EmulatorEvent::Log { level: LogLevel::Info | LogLevel::Warning, .. } | EmulatorEvent::RomLoaded { .. }) {
+                self.status_message = Some(event.to_string());
+            }
+        }
+        if self.show_log {
+            egui::TopBottomPanel::bottom("log").resizable(true).show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.heading("Log");
+                    if ui.button("Clear").clicked() {
+                        self.log.clear();
+                    }
+                });
+                log_view(ui, &self.log);
+            });
+        }
+        // Lap times count from the last press of LAP_KEY. Reloading the ROM restarts the cycle count, so the lap
+        // can start after it.
+        let cycles: u64 = self.cycles.load(Ordering::Relaxed);
+        if ctx.input(|i| i.key_pressed(LAP_KEY)) {
+            self.lap_start = cycles;
+        }
+        // The emulator stays paused while rewinding so it doesn't run forward between steps back.
+        // Backspace in a text field is left to the text field.
+        if self.selected_file.is_some() && !ctx.wants_keyboard_input() && ctx.input(|i| i.key_down(REWIND_KEY)) {
+            if !self.rewinding {
+                self.rewinding = true;
+                let _ = self.command_sender.try_send(EmulatorCommand::SetPaused(true));
+            }
+            let _ = self.command_sender.try_send(EmulatorCommand::Rewind);
+        } else if self.rewinding {
+            self.rewinding = false;
+            let _ = self.command_sender.try_send(EmulatorCommand::SetPaused(self.paused || self.show_input_test));
+        }
+        if self.status_message.is_some() || self.selected_file.is_some() {
+            egui::TopBottomPanel::bottom("status_bar").show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    if let Some(status_message) = self.status_message.as_ref() {
+                        ui.label(status_message);
+                    }
+                    if self.program_ended && ui.button("Reset").clicked() {
+                        let _ = self.command_sender.try_send(EmulatorCommand::ColdReset);
+                    }
+                    if self.selected_file.is_some() {
+                        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                            ui.label(format!("Lap: {}", cycles.saturating_sub(self.lap_start)));
+                            ui.label(format!("Cycles: {}", cycles));
+                        });
+                    }
+                });
+            });
+        }
+
+        // Track which registers changed since the last state
+        let now: f64 = ctx.input(|i| i.time);
+        while let Ok(cpu_state) = self.cpu_state_receiver.try_recv() {
+            if let Some(previous) = self.cpu_state {
+                self.mark_changed_registers(&previous, &cpu_state, now);
+            }
+            self.cpu_state = Some(cpu_state);
+        }
+        if self.show_registers {
+            egui::SidePanel::right("registers").show(ctx, |ui| {
+                let register_format: NumberFormat = self.register_format;
+                ui.horizontal(|ui| {
+                    for format in [NumberFormat::Hex, NumberFormat::Decimal, NumberFormat::Binary] {
+                        ui.selectable_value(&mut self.register_format, format, format.name());
+                    }
+                });
+                if self.register_format != register_format {
+                    let _ = config::save_setting(REGISTER_FORMAT_SETTING, self.register_format.name());
+                }
+                self.register_panel(ui, now);
+            });
+        }
+        if self.show_stack {
+            egui::SidePanel::right("stack").show(ctx, |ui| {
+                self.stack_panel(ui, now);
+            });
+        }
+
+        if let Some(state_diff) = self.state_diff.as_ref() {
+            let mut open: bool = true;
+            egui::Window::new("Save state diff").open(&mut open).show(ctx, |ui| {
+                state_diff_view(ui, state_diff);
+            });
+            if !open {
+                self.state_diff = None;
+            }
+        }
+
+        while let Ok(opcode_counts) = self.opcode_counts_receiver.try_recv() {
+            self.opcode_counts = opcode_counts;
+        }
+        if self.show_opcode_histogram {
+            let mut open: bool = true;
+            egui::Window::new("Opcode histogram").open(&mut open).show(ctx, |ui| {
+                opcode_histogram_view(ui, &self.opcode_counts);
+            });
+            if !open {
+                self.show_opcode_histogram = false;
+                let _ = self.command_sender.try_send(EmulatorCommand::SetSendOpcodeCounts(false));
+            }
+        }
+
+        while let Ok(recent_opcodes) = self.recent_opcodes_receiver.try_recv() {
+            self.recent_opcodes = recent_opcodes;
+        }
+        if self.show_opcode_timeline {
+            let mut open: bool = true;
+            egui::Window::new("Opcode timeline").open(&mut open).show(ctx, |ui| {
+                opcode_timeline_view(ui, &self.recent_opcodes);
+            });
+            if !open {
+                self.show_opcode_timeline = false;
+                let _ = self.command_sender.try_send(EmulatorCommand::SetSendRecentOpcodes(false));
+            }
+        }
+
+        if self.show_quirks {
+            let quirks: Quirks = self.quirks;
+            egui::Window::new("Quirks").open(&mut self.show_quirks).show(ctx, |ui| {
+                quirks_view(ui, &mut self.quirks);
+            });
+            if self.quirks != quirks {
+                let _ = self.command_sender.try_send(EmulatorCommand::SetQuirks(self.quirks));
+            }
+        }
+
+        if self.show_disassembly {
+            egui::Window::new("Disassembly").open(&mut self.show_disassembly).show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut self.follow_control_flow, "Follow control flow from 0x200");
+                    if ui.add_enabled(!self.breakpoints.is_empty(), egui::Button::new("Clear breakpoints")).clicked() {
+                        self.breakpoints.clear();
+                        let _ = self.command_sender.try_send(EmulatorCommand::ClearBreakpoints);
+                    }
+                });
+                if let Some(address) = disassembly_view(ui, &self.rom, self.follow_control_flow, &self.breakpoints, self.chip8e, &self.quirks) {
+                    let command: EmulatorCommand = if self.breakpoints.remove(&address) {
+                        EmulatorCommand::RemoveBreakpoint(address)
+                    } else {
+                        self.breakpoints.insert(address);
+                        EmulatorCommand::AddBreakpoint(address)
+                    };
+                    let _ = self.command_sender.try_send(command);
+                }
+            });
+        }
+
+        if self.show_planes {
+            egui::Window::new("Planes").open(&mut self.show_planes).show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    let planes: [&Plane; 2] = [&self.pixels.pixels, &self.pixels.second_plane];
+                    for (index, (plane, texture)) in planes.into_iter().zip(self.plane_textures.iter_mut()).enumerate() {
+                        ui.vertical(|ui| {
+                            ui.label(format!("Plane {}", index));
+                            let image: egui::ColorImage = plane_to_color_image(plane, self.pixels.width, self.pixels.height);
+                            let size: egui::Vec2 = egui::Vec2::new(image.width() as f32, image.height() as f32) * PLANE_VIEW_SCALE;
+                            let texture: &mut egui::TextureHandle = texture
+                                .get_or_insert_with(|| ctx.load_texture(format!("plane_{}", index), image.clone(), egui::TextureOptions::NEAREST));
+                            texture.set(image, egui::TextureOptions::NEAREST);
+                            ui.image((texture.id(), size));
+                        });
+                    }
+                });
+            });
+        }
+
+        if let Some((folder, roms)) = self.rom_browser.as_ref() {
+            let mut clicked: Option<PathBuf> = None;
+            let mut close: bool = false;
+            egui::SidePanel::left("rom_browser").show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.heading("ROMs");
+                    close = ui.button("Close").clicked();
+                });
+                ui.label(folder.display().to_string());
+                if roms.is_empty() {
+                    ui.label("No .ch8 or .c8 files");
+                }
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    for (path, size) in roms {
+                        let name: String = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+                        let selected: bool = self.selected_file.as_ref().is_some_and(|file| *file == path.display().to_string());
+                        if ui.selectable_label(selected, format!("{}  ({} bytes)", name, size)).clicked() {
+                            clicked = Some(path.clone());
+                        }
+                    }
+                });
+            });
+            if let Some(path) = clicked {
+                self.rom_selected(path);
+            }
+            if close {
+                self.rom_browser = None;
+            }
+        }
+
+        let keys_held: [bool; 16] = self.read_keypad(ctx);
+
         // This is the main screen
         egui::CentralPanel::default().show(ctx, |ui: &mut egui::Ui| {
-            if let Some(_selected_file) = self.selected_file.as_ref() {
+            if self.show_input_test {
+                if input_test_view(ui, &keys_held, &mut self.key_map, &mut self.rebinding_key) {
+                    let _ = config::save_setting(KEY_MAP_SETTING, &self.key_map.to_setting());
+                }
+            } else if let Some(_selected_file) = self.selected_file.as_ref() {
                 // If we have a selected file, there's probbaly something to display
                 // Render the latest frame
                 while let Ok(frame_buffer) = self.frame_buffer_receiver.try_recv() {
-                    self.pixels = frame_buffer;
+                    self.previous_pixels = std::mem::replace(&mut self.pixels, frame_buffer);
                 }
 
-                // Get pixels from the pixel buffer
-                let pixels = &self.pixels.pixels;
+                // Get pixels from the pixel buffer, at whatever resolution the ROM is using
+                let pixels: &PixelBuffer = &self.pixels;
+                let (width, height): (usize, usize) = (pixels.width as usize, pixels.height as usize);
                 // Get the dimensions of the window
                 let window_size = ui.available_size();
 
                 // Scale the pixel buffer to fit the window while maintaining aspect ratio
-                let scale_x = window_size.x / (WIDTH as f32);
-                let scale_y = window_size.y / (HEIGHT as f32);
+                let scale_x = window_size.x / (width as f32);
+                let scale_y = window_size.y / (height as f32);
                 let scale = scale_x.min(scale_y);
 
                 // Create the Pixel Grid
                 let total_size =
-                    egui::Vec2::new(pixels[0].len() as f32 * scale, pixels.len() as f32 * scale);
+                    egui::Vec2::new(width as f32 * scale, height as f32 * scale);
                 let area = ui.allocate_space(total_size);
                 let painter = ui.painter_at(area.1);
-                painter.rect_filled(area.1, 0.0, egui::Color32::from_gray(10));
-
-                // Draw pixels
-                for y in 0..pixels.len() {
-                    for x in 0..pixels[0].len() {
-                        if pixels[y][x] {
-                            let min = egui::Pos2::new(
-                                area.1.min.x + x as f32 * scale,
-                                area.1.min.y + y as f32 * scale,
-                            );
-                            let max = egui::Pos2::new(min.x + scale, min.y + scale);
-                            painter.rect_filled(
-                                egui::Rect::from_min_max(min, max),
-                                0.0,
-                                egui::Color32::WHITE,
-                            );
-                        }
-                    }
+
+                // The frame is one texture, scaled up by the GPU with the chosen filtering
+                let image: egui::ColorImage = frame_to_color_image(pixels, &self.palette, self.display_transform);
+                let texture_options: egui::TextureOptions = self.filtering.texture_options();
+                let texture: &mut egui::TextureHandle = self
+                    .display_texture
+                    .get_or_insert_with(|| ctx.load_texture("display", image.clone(), texture_options));
+                texture.set(image, texture_options);
+                let uv: egui::Rect = egui::Rect::from_min_max(egui::Pos2::ZERO, egui::Pos2::new(1.0, 1.0));
+                painter.image(texture.id(), area.1, uv, egui::Color32::WHITE);
+
+                if ui.input(|i| i.key_down(GRID_KEY)) {
+                    draw_coordinate_grid(&painter, area.1, scale, self.grid_spacing, width, height);
                 }
 
                 // Get keys
-                let mut keys: [bool; 16] = [false; 16];
-                for i in 0..16 {
-                    let key_code = match i {
-                        0x0 => egui::Key::Num0,
-                        0x1 => egui::Key::Num1,
-                        0x2 => egui::Key::Num2,
-                        0x3 => egui::Key::Num3,
-                        0x4 => egui::Key::Num4,
-                        0x5 => egui::Key::Num5,
-                        0x6 => egui::Key::Num6,
-                        0x7 => egui::Key::Num7,
-                        0x8 => egui::Key::Num8,
-                        0x9 => egui::Key::Num9,
-                        0xA => egui::Key::A,
-                        0xB => egui::Key::B,
-                        0xC => egui::Key::C,
-                        0xD => egui::Key::D,
-                        0xE => egui::Key::E,
-                        0xF => egui::Key::F,
-                        _ => continue,
-                    };
+                self.keys.store(emulator::keys_to_bitmask(&keys_held), Ordering::Relaxed);
+
+                // Run on to the next draw
+                if self.paused && self.pause_after_draw && ui.input(|i| i.key_pressed(RESUME_KEY)) {
+                    self.paused = false;
+                    let _ = self.command_sender.try_send(EmulatorCommand::SetPaused(false));
+                }
 
-                    if ui.input(|i| i.key_pressed(key_code)) {
-                        keys[i] = true;
-                    }else {
-                        keys[i] = false;
+                // F1-F10 load a save slot, Shift+F1-F10 saves to it
+                for (slot, key) in SLOT_KEYS.iter().enumerate() {
+                    let (pressed, shift) = ui.input(|i| (i.key_pressed(*key), i.modifiers.shift));
+                    if pressed {
+                        let command: EmulatorCommand = if shift {
+                            EmulatorCommand::SaveSlot(slot)
+                        } else {
+                            EmulatorCommand::LoadSlot(slot)
+                        };
+                        let _ = self.command_sender.try_send(command);
                     }
                 }
-                let _ = self.keys_sender.try_send(keys);
             } else {
                 ui.heading("Pico8 Emulator");
 
-                if ui.button("Pick a file").clicked() {
-                    if let Some(file_path) = rfd::FileDialog::new().pick_file() {
-                        self.rom_selected(file_path);
-                        ctx.request_repaint();
-                    }
+                if ui.button("Pick a file").clicked()
+                    && let Some(file_path) = rfd::FileDialog::new().pick_file()
+                {
+                    self.rom_selected(file_path);
+                    ctx.request_repaint();
                 }
             }
         });
@@ -241,14 +1204,800 @@ impl Pico8Emulator {
 
         // Read the file content
         let file_content: Vec<u8> = std::fs::read(&file_path).unwrap_or_default();
+        let save_slots: SaveSlotManager = SaveSlotManager::new(&file_content);
+        self.rom = file_content.clone();
         // Send the file content to the emulator
-        let err: Result<(), mpsc::error::TrySendError<Vec<u8>>> =
-            self.file_content_sender.try_send(file_content);
+        let err: Result<(), mpsc::error::TrySendError<EmulatorCommand>> =
+            self.command_sender.try_send(EmulatorCommand::LoadRom(file_content));
         
         if err.is_err() {
             println!("Error sending file content to emulator");
         }else {
             self.selected_file = Some(selected_file);
+            self.save_slots = Some(save_slots);
+            self.refresh_slot_metadata();
+        }
+    }
+
+    // Pick two slot files and diff them
+    fn compare_save_states(&mut self) {
+        let Some(left_path) = rfd::FileDialog::new().set_title("First save state").pick_file() else {
+            return;
+        };
+        let Some(right_path) = rfd::FileDialog::new().set_title("Second save state").pick_file() else {
+            return;
+        };
+        let states: Result<(EmulatorState, EmulatorState), Box<dyn Error>> =
+            save_slots::read_slot_file(&left_path).and_then(|left| Ok((left, save_slots::read_slot_file(&right_path)?)));
+        match states {
+            Ok((left, right)) => self.state_diff = Some(left.diff(&right)),
+            Err(err) => self.status_message = Some(format!("Could not compare save states: {}", err)),
+        }
+    }
+
+    fn load_input_script(&mut self, script_path: PathBuf) {
+        let frames: Result<Vec<[bool; 16]>, Box<dyn Error>> = std::fs::read_to_string(&script_path)
+            .map_err(Box::from)
+            .and_then(|script| Ok(input_script::parse(&script)?));
+        match frames {
+            Ok(frames) => {
+                let _ = self.command_sender.try_send(EmulatorCommand::PlayInputScript(frames));
+                self.status_message = Some(format!("Playing input script {}", script_path.display()));
+            }
+            Err(err) => self.status_message = Some(format!("Could not load input script: {}", err)),
+        }
+    }
+
+    // Run a replay's ROM with its settings and inputs. The GUI's settings follow the replay's.
+    fn reproduce_replay(&mut self, replay_path: PathBuf) {
+        let bundle: ReplayBundle = match ReplayBundle::from_file(&replay_path) {
+            Ok(bundle) => bundle,
+            Err(err) => {
+                self.status_message = Some(format!("Could not load replay: {}", err));
+                return;
+            }
+        };
+        self.chip8e = bundle.chip8e;
+        self.schip = bundle.schip;
+        self.xochip = bundle.xochip;
+        self.match_palette_to_planes();
+        self.quirks = bundle.quirks;
+        self.clock_hz = bundle.clock_hz;
+        self.timer_hz = bundle.timer_hz;
+        self.rom = bundle.rom.clone();
+        self.save_slots = Some(SaveSlotManager::new(&bundle.rom));
+        self.refresh_slot_metadata();
+        self.selected_file = Some(replay_path.display().to_string());
+        let _ = self.command_sender.try_send(EmulatorCommand::StartReplay(Box::new(bundle)));
+        self.status_message = Some(format!("Reproducing {}", replay_path.display()));
+    }
+
+    // Check the run against a trace from another emulator, restarting the ROM so both begin together
+    fn load_reference_trace(&mut self, trace_path: PathBuf) {
+        let reference: Vec<TraceEntry> = match std::fs::read_to_string(&trace_path).map_err(|err| err.to_string()).and_then(|trace| trace::parse(&trace)) {
+            Ok(reference) => reference,
+            Err(err) => {
+                self.status_message = Some(format!("Could not load trace: {}", err));
+                return;
+            }
+        };
+        let _ = self.command_sender.try_send(EmulatorCommand::SetReferenceTrace(Some(reference)));
+        self.paused = false;
+        let _ = self.command_sender.try_send(EmulatorCommand::SetPaused(false));
+        if !self.rom.is_empty() {
+            let _ = self.command_sender.try_send(EmulatorCommand::LoadRom(self.rom.clone()));
+        }
+        self.status_message = Some(format!("Comparing against trace {}", trace_path.display()));
+    }
+
+    // Keypad keys held down, read the way key_mapping says. The emulator gets the held state, FX0A
+    // finds the press itself.
+    fn read_keypad(&mut self, ctx: &egui::Context) -> [bool; 16] {
+        match self.key_mapping {
+            KeyMapping::Logical => ctx.input(|i| self.key_map.keys.map(|key_code| i.key_down(key_code))),
+            KeyMapping::Physical => {
+                // egui only tracks logical keys, so follow the physical ones from the key events
+                ctx.input(|i| {
+                    for event in &i.events {
+                        if let egui::Event::Key { physical_key: Some(physical_key), pressed: down, .. } = event
+                            && let Some(key) = self.key_map.keys.iter().position(|key_code| key_code == physical_key)
+                        {
+                            self.physical_keys_held[key] = *down;
+                        }
+                    }
+                    // Keys released while the window was in the background never send a release
+                    if !i.focused {
+                        self.physical_keys_held = [false; 16];
+                    }
+                });
+                self.physical_keys_held
+            }
+        }
+    }
+
+    // XO-CHIP has two planes and 4 colors, everything else one plane and 2 colors
+    fn match_palette_to_planes(&mut self) {
+        let planes: u32 = if self.xochip { 2 } else { 1 };
+        if self.palette.planes() != planes {
+            self.palette = saved_palette(planes)
+                .unwrap_or_else(|| if self.xochip { Palette::xochip() } else { Palette::chip8() });
+        }
+    }
+
+    fn import_palette(&mut self, palette_path: PathBuf) {
+        match Palette::from_file(&palette_path) {
+            Ok(palette) if palette.planes() != self.palette.planes() => {
+                self.status_message = Some(format!(
+                    "Palette is for {} planes, the display has {}",
+                    palette.planes(),
+                    self.palette.planes()
+                ));
+            }
+            Ok(palette) => self.palette = palette,
+            Err(err) => self.status_message = Some(format!("Could not import palette: {}", err)),
+        }
+    }
+
+    // An empty list lets everything run
+    fn apply_opcode_filter(&mut self) {
+        let patterns: Vec<OpcodePattern> = match opcode_filter::parse_patterns(&self.opcode_filter_text) {
+            Ok(patterns) => patterns,
+            Err(err) => {
+                self.status_message = Some(format!("Invalid opcode filter: {}", err));
+                return;
+            }
+        };
+        self.opcode_filter = if patterns.is_empty() {
+            OpcodeFilter::AllowAll
+        } else if self.opcode_filter_allow {
+            OpcodeFilter::Allow(patterns)
+        } else {
+            OpcodeFilter::Deny(patterns)
+        };
+        let _ = self.command_sender.try_send(EmulatorCommand::SetOpcodeFilter(self.opcode_filter.clone()));
+    }
+
+    fn refresh_slot_metadata(&mut self) {
+        self.slot_metadata = match self.save_slots.as_ref() {
+            Some(save_slots) => save_slots.occupied_slots(),
+            None => [None; SLOT_COUNT],
+        };
+    }
+
+    fn mark_changed_registers(&mut self, previous: &CpuView, current: &CpuView, now: f64) {
+        for (index, (old, new)) in previous.v.iter().zip(current.v.iter()).enumerate() {
+            if old != new {
+                self.register_changed_at[index] = now;
+            }
+        }
+        if previous.i != current.i {
+            self.register_changed_at[REGISTER_I] = now;
+        }
+        if previous.pc != current.pc {
+            self.register_changed_at[REGISTER_PC] = now;
+        }
+        if previous.sp != current.sp {
+            self.register_changed_at[REGISTER_SP] = now;
+        }
+        if previous.delay != current.delay {
+            self.register_changed_at[REGISTER_DT] = now;
+        }
+        if previous.sound != current.sound {
+            self.register_changed_at[REGISTER_ST] = now;
+        }
+
+        // Highlight the slots between the old and new SP, which were just pushed or popped
+        let low: usize = previous.sp.min(current.sp) as usize;
+        let high: usize = previous.sp.max(current.sp) as usize;
+        for slot in low..high {
+            if let Some(changed_at) = self.stack_changed_at.get_mut(slot) {
+                *changed_at = now;
+            }
+        }
+        for (slot, (old, new)) in previous.stack.iter().zip(current.stack.iter()).enumerate() {
+            if old != new {
+                self.stack_changed_at[slot] = now;
+            }
+        }
+    }
+
+    fn register_panel(&self, ui: &mut egui::Ui, now: f64) {
+        ui.heading("Registers");
+        let Some(cpu_state) = self.cpu_state.as_ref() else {
+            ui.label("No ROM running");
+            return;
+        };
+
+        egui::Grid::new("register_grid").show(ui, |ui| {
+            for (index, value) in cpu_state.v.iter().enumerate() {
+                self.register_row(ui, &format!("V{:X}", index), self.register_format.format(*value as u16, 8), index, now);
+            }
+            self.register_row(ui, "I", self.register_format.format(cpu_state.i, 16), REGISTER_I, now);
+            self.register_row(ui, "PC", self.register_format.format(cpu_state.pc, 16), REGISTER_PC, now);
+            self.register_row(ui, "SP", self.register_format.format(cpu_state.sp as u16, 8), REGISTER_SP, now);
+            self.register_row(ui, "DT", self.register_format.format(cpu_state.delay as u16, 8), REGISTER_DT, now);
+            self.register_row(ui, "ST", self.register_format.format(cpu_state.sound as u16, 8), REGISTER_ST, now);
+        });
+        // There's no audio, this stands in for the buzzer
+        if cpu_state.beeping && !self.paused {
+            ui.label("Beeping");
+        }
+    }
+
+    fn register_row(&self, ui: &mut egui::Ui, name: &str, value: String, index: usize, now: f64) {
+        let color: egui::Color32 = highlight_color(ui.visuals().text_color(), self.register_changed_at[index], now);
+        ui.label(name);
+        ui.label(egui::RichText::new(value).monospace().color(color));
+        ui.end_row();
+    }
+
+    // Listed top to bottom like the stack itself, with unused slots dimmed
+    fn stack_panel(&self, ui: &mut egui::Ui, now: f64) {
+        ui.heading("Stack");
+        let Some(cpu_state) = self.cpu_state.as_ref() else {
+            ui.label("No ROM running");
+            return;
+        };
+
+        let sp: usize = cpu_state.sp as usize;
+        egui::Grid::new("stack_grid").show(ui, |ui| {
+            for (slot, address) in cpu_state.stack.iter().enumerate().rev() {
+                let base_color: egui::Color32 = if slot < sp {
+                    ui.visuals().text_color()
+                } else {
+                    ui.visuals().weak_text_color()
+                };
+                let color: egui::Color32 = highlight_color(base_color, self.stack_changed_at[slot], now);
+                // Marks the return address that 00EE will pop next
+                ui.label(if slot + 1 == sp { "top >" } else { "" });
+                ui.label(format!("{:X}", slot));
+                ui.label(egui::RichText::new(format!("{:04X}", address)).monospace().color(color));
+                ui.end_row();
+            }
+        });
+    }
+}
+
+// How the register panel shows values
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum NumberFormat {
+    Hex,
+    Decimal,
+    Binary,
+}
+
+impl NumberFormat {
+    fn name(self) -> &'static str {
+        match self {
+            NumberFormat::Hex => "Hex",
+            NumberFormat::Decimal => "Dec",
+            NumberFormat::Binary => "Bin",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [NumberFormat::Hex, NumberFormat::Decimal, NumberFormat::Binary]
+            .into_iter()
+            .find(|format| format.name() == name)
+    }
+
+    // Hex and binary are zero padded to the register's width in bits
+    fn format(self, value: u16, bits: usize) -> String {
+        match self {
+            NumberFormat::Hex => format!("{:0width$X}", value, width = bits / 4),
+            NumberFormat::Decimal => value.to_string(),
+            NumberFormat::Binary => format!("{:0width$b}", value, width = bits),
+        }
+    }
+}
+
+// Which keyboard keys drive the keypad. Logical follows the key labels of the active layout, so the block
+// moves on AZERTY or QWERTZ. Physical uses key positions, the block is always where 1234/QWER/ASDF/ZXCV
+// are on a US keyboard.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum KeyMapping {
+    Logical,
+    Physical,
+}
+
+impl KeyMapping {
+    fn name(self) -> &'static str {
+        match self {
+            KeyMapping::Logical => "Logical",
+            KeyMapping::Physical => "Physical",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [KeyMapping::Logical, KeyMapping::Physical]
+            .into_iter()
+            .find(|mapping| mapping.name() == name)
+    }
+}
+
+// Keyboard key for each CHIP-8 key, indexed by the CHIP-8 key value. The default puts the COSMAC VIP
+// keypad on the left of a QWERTY keyboard:
+//   1 2 3 C        1 2 3 4
+//   4 5 6 D   ->   Q W E R
+//   7 8 9 E        A S D F
+//   A 0 B F        Z X C V
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct KeyMap {
+    keys: [egui::Key; 16],
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        KeyMap {
+            keys: [
+                egui::Key::X,    // 0
+                egui::Key::Num1, // 1
+                egui::Key::Num2, // 2
+                egui::Key::Num3, // 3
+                egui::Key::Q,    // 4
+                egui::Key::W,    // 5
+                egui::Key::E,    // 6
+                egui::Key::A,    // 7
+                egui::Key::S,    // 8
+                egui::Key::D,    // 9
+                egui::Key::Z,    // A
+                egui::Key::C,    // B
+                egui::Key::Num4, // C
+                egui::Key::R,    // D
+                egui::Key::F,    // E
+                egui::Key::V,    // F
+            ],
+        }
+    }
+}
+
+impl KeyMap {
+    // Bind a CHIP-8 key (0 to F) to a keyboard key
+    fn bind(&mut self, chip8_key: usize, key: egui::Key) {
+        self.keys[chip8_key] = key;
+    }
+
+    // The bindings that differ from the default, e.g. "5:Up,8:Down"
+    fn to_setting(self) -> String {
+        let default: KeyMap = KeyMap::default();
+        (0..16)
+            .filter(|&chip8_key| self.keys[chip8_key] != default.keys[chip8_key])
+            .map(|chip8_key| format!("{:X}:{}", chip8_key, self.keys[chip8_key].name()))
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+
+    // The default bindings with the ones from to_setting on top. Unknown bindings are skipped.
+    fn from_setting(setting: &str) -> Self {
+        let mut key_map: KeyMap = KeyMap::default();
+        for binding in setting.split(',') {
+            let Some((chip8_key, key)) = binding.split_once(':') else {
+                continue;
+            };
+            match (usize::from_str_radix(chip8_key.trim(), 16), egui::Key::from_name(key.trim())) {
+                (Ok(chip8_key), Some(key)) if chip8_key < 16 => key_map.bind(chip8_key, key),
+                _ => println!("Ignoring key binding {}", binding),
+            }
+        }
+        key_map
+    }
+}
+
+// How the display is scaled up to the window
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Filtering {
+    Nearest, // Square pixels, as on the original hardware
+    Linear,  // Softer edges
+}
+
+impl Filtering {
+    fn texture_options(self) -> egui::TextureOptions {
+        match self {
+            Filtering::Nearest => egui::TextureOptions::NEAREST,
+            Filtering::Linear => egui::TextureOptions::LINEAR,
+        }
+    }
+}
+
+// How the frame is turned for unusual screen or cabinet setups. Only the picture changes, the
+// emulator and its collisions never see it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum DisplayTransform {
+    None,
+    FlipHorizontal,
+    FlipVertical,
+    Rotate180,
+}
+
+impl DisplayTransform {
+    // The frame pixel that ends up at (x, y) on a width x height screen
+    fn source(self, x: usize, y: usize, width: usize, height: usize) -> (usize, usize) {
+        match self {
+            DisplayTransform::None => (x, y),
+            DisplayTransform::FlipHorizontal => (width - 1 - x, y),
+            DisplayTransform::FlipVertical => (x, height - 1 - y),
+            DisplayTransform::Rotate180 => (width - 1 - x, height - 1 - y),
+        }
+    }
+}
+
+// Which frame an export captures. XOR drawing makes sprites flicker, so the latest frame can
+// catch one erased.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Capture {
+    Authentic, // The latest frame as it is
+    Clean,     // The latest frame blended with the one before
+}
+
+impl Capture {
+    fn frame(self, current: &PixelBuffer, previous: &PixelBuffer) -> PixelBuffer {
+        match self {
+            Capture::Authentic => PixelBuffer { ..*current },
+            Capture::Clean => current.blend(previous),
+        }
+    }
+}
+
+// The frame in palette colors, one texel per CHIP-8 pixel
+fn frame_to_color_image(frame: &PixelBuffer, palette: &Palette, transform: DisplayTransform) -> egui::ColorImage {
+    let (width, height): (usize, usize) = (frame.width as usize, frame.height as usize);
+    let colors: Vec<egui::Color32> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| transform.source(x, y, width, height)))
+        .map(|(x, y)| to_color32(palette.color(&[frame.pixels[y][x], frame.second_plane[y][x]])))
+        .collect();
+    egui::ColorImage::new([width, height], colors)
+}
+
+// One plane at the live resolution, white where a pixel is set whatever the palette
+fn plane_to_color_image(plane: &Plane, width: u32, height: u32) -> egui::ColorImage {
+    let (width, height): (usize, usize) = (width as usize, height as usize);
+    let colors: Vec<egui::Color32> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| if plane[y][x] { egui::Color32::WHITE } else { egui::Color32::BLACK }))
+        .collect();
+    egui::ColorImage::new([width, height], colors)
+}
+
+// One control per quirk, applied from the next instruction
+fn quirks_view(ui: &mut egui::Ui, quirks: &mut Quirks) {
+    ui.checkbox(&mut quirks.shift_uses_vy, "8XY6/8XYE shift VY").on_hover_text(
+        "Copy VY into VX, then shift. The COSMAC VIP does this; CHIP-48 and SUPER-CHIP shift VX in place and ignore VY.",
+    );
+    ui.checkbox(&mut quirks.jump_uses_vx, "BNNN jumps with VX").on_hover_text(
+        "Jump to XNN + VX instead of NNN + V0. A CHIP-48 and SUPER-CHIP mistake that some ROMs for them rely on.",
+    );
+    ui.checkbox(&mut quirks.vf_reset, "8XY1/8XY2/8XY3 reset VF").on_hover_text(
+        "OR, AND and XOR clear VF, a side effect of how the COSMAC VIP ran them. Later interpreters leave VF alone.",
+    );
+    ui.horizontal(|ui| {
+        ui.label("FX55/FX65 move I").on_hover_text(
+            "The COSMAC VIP leaves I just past the last register saved or loaded, CHIP-48 on the last one. SUPER-CHIP leaves I unchanged.",
+        );
+        ui.radio_value(&mut quirks.load_store_increments_i, LoadStoreIncrement::Unchanged, "No");
+        ui.radio_value(&mut quirks.load_store_increments_i, LoadStoreIncrement::X, "By X");
+        ui.radio_value(&mut quirks.load_store_increments_i, LoadStoreIncrement::XPlusOne, "By X + 1");
+    });
+    ui.checkbox(&mut quirks.wrap_x, "Wrap sprites horizontally").on_hover_text(
+        "Sprites running off the right edge come back on the left. The COSMAC VIP and SUPER-CHIP clip them.",
+    );
+    ui.checkbox(&mut quirks.wrap_y, "Wrap sprites vertically").on_hover_text(
+        "Sprites running off the bottom come back at the top. The COSMAC VIP and SUPER-CHIP clip them.",
+    );
+    ui.checkbox(&mut quirks.i_overflow_sets_vf, "FX1E sets VF on overflow").on_hover_text(
+        "Set VF to 1 when I goes past 0xFFF, as the Amiga interpreter does. Spacefight 2091! relies on it.",
+    );
+    ui.checkbox(&mut quirks.display_wait, "DXYN waits for vblank").on_hover_text(
+        "Run nothing after a sprite draw until the next 60 Hz frame, as the COSMAC VIP does. Caps drawing at 60 sprites a second and cuts flicker.",
+    );
+    ui.horizontal(|ui| {
+        ui.label("Minimum sound timer for a beep").on_hover_text(
+            "The COSMAC VIP stays silent for a sound timer of 1, so it needs at least 2. Other platforms beep for any value.",
+        );
+        ui.add(egui::DragValue::new(&mut quirks.sound_timer_min).range(0..=255));
+    });
+    ui.horizontal(|ui| {
+        if ui.button("Defaults").clicked() {
+            *quirks = Quirks::default();
+        }
+        if ui.button("CHIP-8").clicked() {
+            *quirks = Quirks::chip8();
+        }
+        if ui.button("SUPER-CHIP").clicked() {
+            *quirks = Quirks::superchip();
+        }
+        if ui.button("XO-CHIP").clicked() {
+            *quirks = Quirks::xochip();
+        }
+    });
+}
+
+// Light up each CHIP-8 key while its keyboard key is held. Clicking a key binds it to the next key
+// pressed. Returns true when a binding changed.
+fn input_test_view(ui: &mut egui::Ui, held: &[bool; 16], key_map: &mut KeyMap, rebinding_key: &mut Option<usize>) -> bool {
+    let mut changed: bool = false;
+    ui.heading("Input test");
+    egui::Grid::new("input_test_grid").spacing([8.0, 8.0]).show(ui, |ui| {
+        for row in KEYPAD_LAYOUT {
+            for key in row {
+                let fill: egui::Color32 = if held[key] { egui::Color32::WHITE } else { egui::Color32::from_gray(40) };
+                let text_color: egui::Color32 = if held[key] { egui::Color32::BLACK } else { egui::Color32::WHITE };
+                let text: String = if *rebinding_key == Some(key) { "?".to_string() } else { format!("{:X}", key) };
+                let label: egui::RichText = egui::RichText::new(text).monospace().size(24.0).color(text_color);
+                let button = ui.add(egui::Button::new(label).fill(fill).min_size(egui::Vec2::splat(48.0)));
+                if button.on_hover_text(format!("{} (click to rebind)", key_map.keys[key].name())).clicked() {
+                    *rebinding_key = Some(key);
+                }
+            }
+            ui.end_row();
+        }
+    });
+
+    if let Some(chip8_key) = *rebinding_key {
+        ui.label(format!("Press a key for {:X}, Escape cancels", chip8_key));
+        let pressed: Option<egui::Key> = ui.input(|i| {
+            i.events.iter().find_map(|event| match event {
+                egui::Event::Key { key, pressed: true, repeat: false, .. } => Some(*key),
+                _ => None,
+            })
+        });
+        if let Some(key) = pressed {
+            if key != egui::Key::Escape {
+                key_map.bind(chip8_key, key);
+                changed = true;
+            }
+            *rebinding_key = None;
+        }
+    }
+    if ui.button("Default bindings").clicked() {
+        *key_map = KeyMap::default();
+        changed = true;
+    }
+
+    for (key, _) in held.iter().enumerate().filter(|&(_, &down)| down) {
+        ui.label(format!("{:?} -> CHIP-8 key {:X} (index {})", key_map.keys[key], key, key));
+    }
+    changed
+}
+
+// The palette last picked for this plane count, None if there isn't one
+fn saved_palette(planes: u32) -> Option<Palette> {
+    let setting: String = config::load_setting(&format!("{}_{}", PALETTE_SETTING, planes))?;
+    // Settings are one line each, so the colors are kept space separated
+    Palette::parse(&setting.replace(' ', "\n")).ok().filter(|palette| palette.planes() == planes)
+}
+
+fn save_palette(palette: &Palette) {
+    let colors: String = palette.to_text().lines().collect::<Vec<&str>>().join(" ");
+    let _ = config::save_setting(&format!("{}_{}", PALETTE_SETTING, palette.planes()), &colors);
+}
+
+fn to_color32([red, green, blue]: [u8; 3]) -> egui::Color32 {
+    egui::Color32::from_rgb(red, green, blue)
+}
+
+// A recently changed value is drawn in yellow, fading back to its normal color
+fn highlight_color(color: egui::Color32, changed_at: f64, now: f64) -> egui::Color32 {
+    let age: f64 = now - changed_at;
+    if age < HIGHLIGHT_FADE_SECONDS {
+        let strength: f32 = (1.0 - age / HIGHLIGHT_FADE_SECONDS) as f32;
+        return color.lerp_to_gamma(egui::Color32::YELLOW, strength);
+    }
+    color
+}
+
+// The CHIP-8 ROMs directly inside a folder with their sizes, sorted by name
+fn find_roms(folder: &Path) -> std::io::Result<Vec<(PathBuf, u64)>> {
+    let mut roms: Vec<(PathBuf, u64)> = Vec::new();
+    for entry in std::fs::read_dir(folder)? {
+        let entry: std::fs::DirEntry = entry?;
+        let path: PathBuf = entry.path();
+        let is_rom: bool = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("ch8") || extension.eq_ignore_ascii_case("c8"));
+        if is_rom && entry.file_type()?.is_file() {
+            roms.push((path, entry.metadata()?.len()));
         }
     }
+    roms.sort();
+    Ok(roms)
+}
+
+// Describe a Unix timestamp relative to now, e.g. "5 min ago"
+fn format_age(timestamp: u64) -> String {
+    let now: u64 = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(timestamp);
+    let age: u64 = now.saturating_sub(timestamp);
+    match age {
+        0..60 => format!("{} s ago", age),
+        60..3600 => format!("{} min ago", age / 60),
+        3600..86400 => format!("{} h ago", age / 3600),
+        _ => format!("{} days ago", age / 86400),
+    }
+}
+
+fn state_diff_view(ui: &mut egui::Ui, state_diff: &StateDiff) {
+    if state_diff.is_empty() {
+        ui.label("The save states are identical");
+        return;
+    }
+
+    egui::ScrollArea::vertical().show(ui, |ui| {
+        ui.heading(format!("Registers ({})", state_diff.registers.len()));
+        for register in state_diff.registers.iter() {
+            ui.monospace(format!("{:<8} {:04X} -> {:04X}", register.name, register.left, register.right));
+        }
+
+        ui.heading(format!("Memory ({})", state_diff.memory.len()));
+        for memory in state_diff.memory.iter() {
+            ui.monospace(format!("{:04X}: {:02X} -> {:02X}", memory.address, memory.left, memory.right));
+        }
+
+        ui.heading(format!("Pixels ({})", state_diff.pixels.len()));
+        for (x, y) in state_diff.pixels.iter() {
+            ui.monospace(format!("({}, {})", x, y));
+        }
+    });
+}
+
+// Faint gridlines every `spacing` display pixels, labelled with their X/Y coordinate
+fn draw_coordinate_grid(painter: &egui::Painter, area: egui::Rect, scale: f32, spacing: usize, width: usize, height: usize) {
+    let line_color: egui::Color32 = egui::Color32::from_rgba_unmultiplied(0, 160, 255, 60);
+    let label_color: egui::Color32 = egui::Color32::from_rgba_unmultiplied(0, 160, 255, 180);
+    let stroke: egui::Stroke = egui::Stroke::new(1.0, line_color);
+    let font: egui::FontId = egui::FontId::monospace(10.0);
+
+    for x in (0..width).step_by(spacing.max(1)) {
+        let screen_x: f32 = area.min.x + x as f32 * scale;
+        painter.vline(screen_x, area.y_range(), stroke);
+        painter.text(egui::Pos2::new(screen_x + 2.0, area.min.y), egui::Align2::LEFT_TOP, x.to_string(), font.clone(), label_color);
+    }
+    for y in (0..height).step_by(spacing.max(1)) {
+        let screen_y: f32 = area.min.y + y as f32 * scale;
+        painter.hline(area.x_range(), screen_y, stroke);
+        painter.text(egui::Pos2::new(area.min.x + 2.0, screen_y), egui::Align2::LEFT_TOP, y.to_string(), font.clone(), label_color);
+    }
+}
+
+// Oldest message at the top, warnings and errors in the theme's warning and error colors
+fn log_view(ui: &mut egui::Ui, log: &VecDeque<(LogLevel, String)>) {
+    let row_height: f32 = ui.text_style_height(&egui::TextStyle::Monospace);
+    egui::ScrollArea::vertical().stick_to_bottom(true).auto_shrink(false).show_rows(ui, row_height, log.len(), |ui, rows| {
+        for (level, message) in log.range(rows) {
+            let color: egui::Color32 = match level {
+                LogLevel::Info => ui.visuals().text_color(),
+                LogLevel::Warning => ui.visuals().warn_fg_color,
+                LogLevel::Error => ui.visuals().error_fg_color,
+            };
+            ui.label(egui::RichText::new(message).monospace().color(color));
+        }
+    });
+}
+
+// One bar per instruction, most executed first
+fn opcode_histogram_view(ui: &mut egui::Ui, opcode_counts: &[(u16, u64)]) {
+    let mut family_counts: Vec<(&str, u64)> = Vec::new();
+    for &(opcode, count) in opcode_counts {
+        let family: &str = disasm::opcode_family(opcode).unwrap_or("Other");
+        match family_counts.iter_mut().find(|(name, _)| *name == family) {
+            Some((_, total)) => *total += count,
+            None => family_counts.push((family, count)),
+        }
+    }
+    if family_counts.is_empty() {
+        ui.label("Nothing has run yet");
+        return;
+    }
+    family_counts.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+
+    let most: u64 = family_counts[0].1;
+    egui::Grid::new("opcode_histogram_grid").show(ui, |ui| {
+        for (family, count) in family_counts {
+            ui.monospace(family);
+            ui.add(egui::ProgressBar::new(count as f32 / most as f32).desired_width(200.0).text(count.to_string()));
+            ui.end_row();
+        }
+    });
+}
+
+// The last instructions run, oldest at the top so the newest stays in view at the bottom
+fn opcode_timeline_view(ui: &mut egui::Ui, recent_opcodes: &[TraceEntry]) {
+    if recent_opcodes.is_empty() {
+        ui.label("Nothing has run yet");
+        return;
+    }
+
+    egui::ScrollArea::vertical().stick_to_bottom(true).max_height(400.0).show(ui, |ui| {
+        for entry in recent_opcodes {
+            let family: &str = disasm::opcode_family(entry.opcode)
+                .or_else(|| disasm::extension_family(entry.opcode).map(|(family, _)| family))
+                .unwrap_or("????");
+            ui.monospace(format!("{:03X}: {:04X}  {}", entry.pc, entry.opcode, family));
+        }
+    });
+}
+
+// Code is listed as opcodes and data as `db` bytes in a dimmer color.
+// Clicking an instruction toggles a breakpoint on it, the address clicked is returned
+fn disassembly_view(
+    ui: &mut egui::Ui,
+    rom: &[u8],
+    follow_control_flow: bool,
+    breakpoints: &HashSet<u16>,
+    chip8e: bool,
+    quirks: &Quirks,
+) -> Option<u16> {
+    if rom.is_empty() {
+        ui.label("No ROM loaded");
+        return None;
+    }
+    let mut clicked: Option<u16> = None;
+
+    let lines: Vec<ListingLine> = disasm::listing(rom, follow_control_flow);
+    let data_color: egui::Color32 = ui.visuals().weak_text_color();
+    let row_height: f32 = ui.text_style_height(&egui::TextStyle::Monospace);
+    egui::ScrollArea::vertical().show_rows(ui, row_height, lines.len(), |ui, rows| {
+        for line in &lines[rows] {
+            match *line {
+                ListingLine::Code { address, opcode } => {
+                    let text: String = disasm::mnemonic_for(opcode, chip8e, quirks).unwrap_or_default();
+                    let marker: char = if breakpoints.contains(&address) { '●' } else { ' ' };
+                    let line: String = format!("{} {:04X}: {:04X}  {}", marker, address, opcode, text);
+                    if ui.add(egui::Label::new(egui::RichText::new(line).monospace()).sense(egui::Sense::click()))
+                        .on_hover_text("Click to toggle a breakpoint")
+                        .clicked()
+                    {
+                        clicked = Some(address);
+                    }
+                }
+                ListingLine::Data { address, byte } => {
+                    ui.label(egui::RichText::new(format!("  {:04X}: db 0x{:02X}", address, byte)).monospace().color(data_color));
+                }
+            }
+        }
+    });
+    clicked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_headless_limits() {
+        let args: Args = parse(&["--headless", "--cycles", "500", "--max-seconds", "1.5", "rom.ch8"]).unwrap();
+        assert!(args.headless);
+        assert_eq!(args.cycles, 500);
+        assert_eq!(args.max_time, Some(Duration::from_millis(1500)));
+        assert_eq!(args.rom, Some(PathBuf::from("rom.ch8")));
+
+        assert_eq!(parse(&[]).unwrap().max_time, None);
+        assert!(parse(&["--max-seconds"]).is_err());
+        assert!(parse(&["--max-seconds", "-1"]).is_err());
+        assert!(parse(&["--max-seconds", "NaN"]).is_err());
+        assert!(parse(&["--max-seconds", "1e30"]).is_err());
+    }
+
+    #[test]
+    fn plane_images_use_the_live_resolution() {
+        let mut plane: Plane = [[false; MAX_WIDTH]; pico_rs_8::display::MAX_HEIGHT];
+        plane[1][2] = true;
+        let image: egui::ColorImage = plane_to_color_image(&plane, 64, 32);
+        assert_eq!(image.size, [64, 32]);
+        assert_eq!(image.pixels[64 + 2], egui::Color32::WHITE);
+        assert_eq!(image.pixels.iter().filter(|&&color| color == egui::Color32::WHITE).count(), 1);
+    }
+
+    #[test]
+    fn frame_output_needs_headless() {
+        assert!(parse(&["--headless", "--ascii", "rom.ch8"]).unwrap().ascii);
+        assert!(parse(&["--ascii", "rom.ch8"]).is_err());
+        assert!(parse(&["--headless", "--raw-stream", "rom.ch8"]).unwrap().raw_stream);
+        assert!(parse(&["--raw-stream", "rom.ch8"]).is_err());
+        assert!(parse(&["--headless", "--ascii", "--raw-stream", "rom.ch8"]).is_err());
+    }
 }
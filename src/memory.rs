@@ -1,18 +1,42 @@
-
-const RAM_SIZE: usize = 4 * 1024; // 4 KB
+pub const DEFAULT_RAM_SIZE: usize = 4 * 1024; // 4 KB, CHIP-8
+pub const MAX_RAM_SIZE: usize = 64 * 1024; // 64 KB, XO-CHIP, everything a 16-bit I can reach
 
 pub struct Memory {
-    pub data: [u8; RAM_SIZE],
+    pub data: Vec<u8>,
 }
 impl Memory {
     pub fn new() -> Self {
         Memory {
-            data: [0; RAM_SIZE],
+            data: vec![0; DEFAULT_RAM_SIZE],
+        }
+    }
+
+    // RAM of any size up to MAX_RAM_SIZE. It has to be larger than the interpreter area below the ROM.
+    pub fn with_size(size: usize) -> Result<Self, String> {
+        if size <= 0x200 || size > MAX_RAM_SIZE {
+            return Err(format!("RAM size must be between {} and {} bytes, got {}", 0x200 + 1, MAX_RAM_SIZE, size));
         }
+        Ok(Memory { data: vec![0; size] })
+    }
+
+    // Checked accessors. ROMs are untrusted, so addresses past the end of RAM wrap around instead of panicking
+    pub fn read(&self, address: usize) -> u8 {
+        self.data[address % self.data.len()]
     }
+
+    pub fn write(&mut self, address: usize, value: u8) {
+        let size: usize = self.data.len();
+        self.data[address % size] = value;
+    }
+
     pub fn clear(&mut self) {
         for byte in self.data.iter_mut() {
             *byte = 0;
         }
     }
-}
\ No newline at end of file
+}
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
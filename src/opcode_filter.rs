@@ -0,0 +1,62 @@
+// An opcode or opcode family written the usual way, e.g. "00E0", "DXYN" or "FX1E".
+// Hex digits must match, the letters X, Y, N and K stand for any nibble.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OpcodePattern {
+    mask: u16,  // Bits that must match
+    value: u16, // What those bits must be
+}
+
+impl OpcodePattern {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let text: &str = text.trim();
+        if text.chars().count() != 4 {
+            return Err(format!("'{}' should be 4 characters, like DXYN", text));
+        }
+        let mut mask: u16 = 0;
+        let mut value: u16 = 0;
+        for character in text.chars() {
+            mask <<= 4;
+            value <<= 4;
+            match character.to_ascii_uppercase() {
+                'X' | 'Y' | 'N' | 'K' => {}
+                digit => {
+                    let nibble: u32 = digit.to_digit(16).ok_or_else(|| format!("'{}' is not a hex digit or X, Y, N, K", character))?;
+                    mask |= 0xF;
+                    value |= nibble as u16;
+                }
+            }
+        }
+        Ok(OpcodePattern { mask, value })
+    }
+
+    pub fn matches(&self, opcode: u16) -> bool {
+        opcode & self.mask == self.value
+    }
+}
+
+// Which opcodes the emulator may run. A denied opcode pauses the emulator instead of running.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum OpcodeFilter {
+    #[default]
+    AllowAll,
+    Allow(Vec<OpcodePattern>), // Only these may run
+    Deny(Vec<OpcodePattern>),  // Everything but these may run
+}
+
+impl OpcodeFilter {
+    pub fn allows(&self, opcode: u16) -> bool {
+        match self {
+            OpcodeFilter::AllowAll => true,
+            OpcodeFilter::Allow(patterns) => patterns.iter().any(|pattern| pattern.matches(opcode)),
+            OpcodeFilter::Deny(patterns) => !patterns.iter().any(|pattern| pattern.matches(opcode)),
+        }
+    }
+}
+
+// Read a comma or space separated list of patterns, e.g. "DXYN, 00FF"
+pub fn parse_patterns(text: &str) -> Result<Vec<OpcodePattern>, String> {
+    text.split(|character: char| character == ',' || character.is_whitespace())
+        .filter(|pattern| !pattern.is_empty())
+        .map(OpcodePattern::parse)
+        .collect()
+}
@@ -0,0 +1,128 @@
+// Behaviors that differ between CHIP-8 interpreters. ROMs written for one platform can
+// misbehave on another, so each difference can be switched on its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    pub shift_uses_vy: bool,      // 8XY6 and 8XYE copy VY into VX before shifting
+    pub jump_uses_vx: bool,       // BNNN jumps to XNN + VX instead of NNN + V0
+    pub vf_reset: bool,           // 8XY1, 8XY2 and 8XY3 set VF to 0
+    pub load_store_increments_i: LoadStoreIncrement, // Where FX55 and FX65 leave I
+    pub wrap_x: bool,             // Sprites past the right edge wrap to the left instead of being clipped
+    pub wrap_y: bool,             // Sprites past the bottom edge wrap to the top instead of being clipped
+    pub i_overflow_sets_vf: bool, // FX1E sets VF to 1 when I goes past 0xFFF
+    pub display_wait: bool,       // DXYN waits for the next 60 Hz frame before the next instruction runs
+    pub sound_timer_min: u8,      // The buzzer stays silent while the sound timer is below this
+}
+
+impl Default for Quirks {
+    // The original COSMAC VIP interpreter, apart from sprite wrapping and the display wait
+    fn default() -> Self {
+        Quirks {
+            shift_uses_vy: true,
+            jump_uses_vx: false,
+            vf_reset: true,
+            load_store_increments_i: LoadStoreIncrement::XPlusOne,
+            wrap_x: true,
+            wrap_y: true,
+            i_overflow_sets_vf: false,
+            display_wait: false,
+            sound_timer_min: 0,
+        }
+    }
+}
+
+impl Quirks {
+    // The COSMAC VIP interpreter, for the original CHIP-8 ROMs. Its buzzer can't sound for a sound
+    // timer of 1, so FX18 with 1 is silent.
+    pub fn chip8() -> Self {
+        Quirks {
+            shift_uses_vy: true,
+            jump_uses_vx: false,
+            vf_reset: true,
+            load_store_increments_i: LoadStoreIncrement::XPlusOne,
+            wrap_x: false,
+            wrap_y: false,
+            i_overflow_sets_vf: false,
+            display_wait: true,
+            sound_timer_min: 2,
+        }
+    }
+
+    // SUPER-CHIP 1.1 on the HP 48
+    pub fn superchip() -> Self {
+        Quirks {
+            shift_uses_vy: false,
+            jump_uses_vx: true,
+            vf_reset: false,
+            load_store_increments_i: LoadStoreIncrement::Unchanged,
+            wrap_x: false,
+            wrap_y: false,
+            i_overflow_sets_vf: false,
+            display_wait: false,
+            sound_timer_min: 0,
+        }
+    }
+
+    // XO-CHIP as Octo runs it
+    pub fn xochip() -> Self {
+        Quirks {
+            shift_uses_vy: true,
+            jump_uses_vx: false,
+            vf_reset: false,
+            load_store_increments_i: LoadStoreIncrement::XPlusOne,
+            wrap_x: true,
+            wrap_y: true,
+            i_overflow_sets_vf: false,
+            display_wait: false,
+            sound_timer_min: 0,
+        }
+    }
+}
+
+// How far FX55 and FX65 move I, for X the last register saved or loaded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadStoreIncrement {
+    Unchanged = 0, // SUPER-CHIP and most modern ROMs
+    XPlusOne = 1, // Just past the last register, the COSMAC VIP
+    X = 2, // Onto the last register, CHIP-48
+}
+
+impl LoadStoreIncrement {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(LoadStoreIncrement::Unchanged),
+            1 => Some(LoadStoreIncrement::XPlusOne),
+            2 => Some(LoadStoreIncrement::X),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets() {
+        let chip8: Quirks = Quirks::chip8();
+        assert!(chip8.shift_uses_vy && !chip8.jump_uses_vx && chip8.vf_reset);
+        assert_eq!(chip8.load_store_increments_i, LoadStoreIncrement::XPlusOne);
+        assert!(!chip8.wrap_x && !chip8.wrap_y && !chip8.i_overflow_sets_vf && chip8.display_wait);
+        assert_eq!(chip8.sound_timer_min, 2);
+
+        let superchip: Quirks = Quirks::superchip();
+        assert!(!superchip.shift_uses_vy && superchip.jump_uses_vx && !superchip.vf_reset);
+        assert_eq!(superchip.load_store_increments_i, LoadStoreIncrement::Unchanged);
+        assert!(!superchip.wrap_x && !superchip.wrap_y && !superchip.i_overflow_sets_vf && !superchip.display_wait);
+        assert_eq!(superchip.sound_timer_min, 0);
+
+        let xochip: Quirks = Quirks::xochip();
+        assert!(xochip.shift_uses_vy && !xochip.jump_uses_vx && !xochip.vf_reset);
+        assert_eq!(xochip.load_store_increments_i, LoadStoreIncrement::XPlusOne);
+        assert!(xochip.wrap_x && xochip.wrap_y && !xochip.i_overflow_sets_vf && !xochip.display_wait);
+        assert_eq!(xochip.sound_timer_min, 0);
+
+        // The default is the COSMAC VIP with wrapping and no display wait or buzzer minimum
+        let vip_with_wrapping: Quirks = Quirks { wrap_x: true, wrap_y: true, display_wait: false, sound_timer_min: 0, ..Quirks::chip8() };
+        assert_eq!(Quirks::default(), vip_with_wrapping);
+    }
+}
@@ -0,0 +1,167 @@
+use std::error::Error;
+use std::path::Path;
+
+use crate::quirks::{LoadStoreIncrement, Quirks};
+use crate::state::StateReader;
+
+// Identifies a replay file, followed by the format version
+const REPLAY_MAGIC: &[u8; 4] = b"PRSR";
+const REPLAY_VERSION: u8 = 1;
+
+// Everything needed to run a ROM again the way it ran the first time: the ROM, the settings that
+// change how it behaves, the random seed and every keypad change. The timers count emulated time,
+// so the clock and timer rates come along too.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplayBundle {
+    pub rom: Vec<u8>,
+    pub seed: u64, // Seed for CXNN, set when the ROM was loaded
+    pub chip8e: bool,
+    pub schip: bool,
+    pub xochip: bool,
+    pub quirks: Quirks,
+    pub clock_hz: u32, // Instructions per second, 0 for unlimited, see Emulator::set_clock_hz
+    pub timer_hz: u32,
+    pub inputs: Vec<(u64, u16)>, // (cycle, keypad bitmask) for each keypad change, in cycle order
+}
+
+impl ReplayBundle {
+    // Layout, all numbers little endian:
+    // magic, version, seed, chip8e, schip, xochip, one byte per quirk, clock_hz, timer_hz,
+    // ROM length + ROM, input count + (cycle, keypad bitmask) per input
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(32 + self.rom.len() + self.inputs.len() * 10);
+        bytes.extend_from_slice(REPLAY_MAGIC);
+        bytes.push(REPLAY_VERSION);
+
+        bytes.extend_from_slice(&self.seed.to_le_bytes());
+        bytes.push(self.chip8e as u8);
+        bytes.push(self.schip as u8);
+        bytes.push(self.xochip as u8);
+        bytes.extend(quirk_bytes(&self.quirks));
+        bytes.extend_from_slice(&self.clock_hz.to_le_bytes());
+        bytes.extend_from_slice(&self.timer_hz.to_le_bytes());
+
+        bytes.extend_from_slice(&(self.rom.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.rom);
+
+        bytes.extend_from_slice(&(self.inputs.len() as u32).to_le_bytes());
+        for &(cycle, keys) in &self.inputs {
+            bytes.extend_from_slice(&cycle.to_le_bytes());
+            bytes.extend_from_slice(&keys.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut reader: StateReader = StateReader { bytes };
+        if reader.take(4)? != REPLAY_MAGIC {
+            return Err(Box::from("Not a replay file"));
+        }
+        let version: u8 = reader.u8()?;
+        if version != REPLAY_VERSION {
+            return Err(Box::from(format!("Unsupported replay version {}", version)));
+        }
+
+        let seed: u64 = reader.u64()?;
+        let chip8e: bool = reader.u8()? != 0;
+        let schip: bool = reader.u8()? != 0;
+        let xochip: bool = reader.u8()? != 0;
+        let quirks: Quirks = quirks_from_bytes(reader.take(QUIRK_COUNT)?)?;
+        let clock_hz: u32 = reader.u32()?;
+        let timer_hz: u32 = reader.u32()?;
+
+        let rom_len: usize = reader.u32()? as usize;
+        let rom: Vec<u8> = reader.take(rom_len)?.to_vec();
+
+        let input_count: usize = reader.u32()? as usize;
+        let mut inputs: Vec<(u64, u16)> = Vec::new();
+        for _ in 0..input_count {
+            inputs.push((reader.u64()?, reader.u16()?));
+        }
+
+        Ok(ReplayBundle { rom, seed, chip8e, schip, xochip, quirks, clock_hz, timer_hz, inputs })
+    }
+
+    pub fn to_file(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        std::fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        ReplayBundle::from_bytes(&std::fs::read(path)?)
+    }
+}
+
+const QUIRK_COUNT: usize = 9;
+
+// The quirks in file order, one byte each
+fn quirk_bytes(quirks: &Quirks) -> [u8; QUIRK_COUNT] {
+    [
+        quirks.shift_uses_vy as u8,
+        quirks.jump_uses_vx as u8,
+        quirks.vf_reset as u8,
+        quirks.load_store_increments_i as u8,
+        quirks.wrap_x as u8,
+        quirks.wrap_y as u8,
+        quirks.i_overflow_sets_vf as u8,
+        quirks.display_wait as u8,
+        quirks.sound_timer_min,
+    ]
+}
+
+// Read quirk_bytes back
+fn quirks_from_bytes(bytes: &[u8]) -> Result<Quirks, Box<dyn Error>> {
+    let flag = |index: usize| bytes[index] != 0;
+    let load_store_increments_i: LoadStoreIncrement =
+        LoadStoreIncrement::from_byte(bytes[3]).ok_or_else(|| format!("Unknown FX55/FX65 mode {}", bytes[3]))?;
+    Ok(Quirks {
+        shift_uses_vy: flag(0),
+        jump_uses_vx: flag(1),
+        vf_reset: flag(2),
+        load_store_increments_i,
+        wrap_x: flag(4),
+        wrap_y: flag(5),
+        i_overflow_sets_vf: flag(6),
+        display_wait: flag(7),
+        sound_timer_min: bytes[8],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_every_field() {
+        let bundle: ReplayBundle = ReplayBundle {
+            rom: vec![0x60, 0x01, 0x12, 0x02],
+            seed: 0x0123_4567_89AB_CDEF,
+            chip8e: false,
+            schip: true,
+            xochip: true,
+            quirks: Quirks { load_store_increments_i: LoadStoreIncrement::X, display_wait: true, ..Quirks::superchip() },
+            clock_hz: 1000,
+            timer_hz: 120,
+            inputs: vec![(10, 0x0020), (25, 0x0000)],
+        };
+        assert_eq!(ReplayBundle::from_bytes(&bundle.to_bytes()).unwrap(), bundle);
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut bytes: Vec<u8> = ReplayBundle {
+            rom: Vec::new(),
+            seed: 0,
+            chip8e: false,
+            schip: false,
+            xochip: false,
+            quirks: Quirks::default(),
+            clock_hz: 0,
+            timer_hz: 60,
+            inputs: Vec::new(),
+        }
+        .to_bytes();
+        bytes[4] = REPLAY_VERSION + 1;
+        assert!(ReplayBundle::from_bytes(&bytes).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::config_dir;
+use crate::cpu::FLAG_COUNT;
+use crate::state::EmulatorState;
+
+pub const SLOT_COUNT: usize = 10;
+
+// Stored in front of the save state in each slot file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SlotMetadata {
+    pub timestamp: u64, // Seconds since the Unix epoch
+    pub cycles: u64,    // Instructions executed since the ROM was loaded
+}
+
+const METADATA_SIZE: usize = 16;
+
+// Save slots for one ROM, stored as <config dir>/saves/<rom hash>/slot_<n>.state
+pub struct SaveSlotManager {
+    directory: PathBuf,
+}
+
+impl SaveSlotManager {
+    pub fn new(rom: &[u8]) -> Self {
+        SaveSlotManager {
+            directory: config_dir().join("saves").join(format!("{:016x}", rom_hash(rom))),
+        }
+    }
+
+    pub fn slot_path(&self, slot: usize) -> PathBuf {
+        self.directory.join(format!("slot_{}.state", slot))
+    }
+
+    pub fn save(&self, slot: usize, state: &EmulatorState, cycles: u64) -> Result<(), Box<dyn Error>> {
+        check_slot(slot)?;
+        let timestamp: u64 = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend_from_slice(&timestamp.to_le_bytes());
+        bytes.extend_from_slice(&cycles.to_le_bytes());
+        bytes.extend_from_slice(&state.to_bytes());
+
+        std::fs::create_dir_all(&self.directory)?;
+        std::fs::write(self.slot_path(slot), bytes)?;
+        Ok(())
+    }
+
+    pub fn load(&self, slot: usize) -> Result<EmulatorState, Box<dyn Error>> {
+        check_slot(slot)?;
+        read_slot_file(&self.slot_path(slot))
+    }
+
+    // None if the slot is empty or unreadable
+    pub fn metadata(&self, slot: usize) -> Option<SlotMetadata> {
+        let bytes: Vec<u8> = std::fs::read(self.slot_path(slot)).ok()?;
+        if bytes.len() < METADATA_SIZE {
+            return None;
+        }
+        Some(SlotMetadata {
+            timestamp: u64::from_le_bytes(bytes[0..8].try_into().ok()?),
+            cycles: u64::from_le_bytes(bytes[8..16].try_into().ok()?),
+        })
+    }
+
+    pub fn occupied_slots(&self) -> [Option<SlotMetadata>; SLOT_COUNT] {
+        std::array::from_fn(|slot| self.metadata(slot))
+    }
+
+    // The SUPER-CHIP flag registers (FX75/FX85) live next to the slots, in flags.bin
+    pub fn flags_path(&self) -> PathBuf {
+        self.directory.join("flags.bin")
+    }
+
+    pub fn save_flags(&self, flags: &[u8; FLAG_COUNT]) -> Result<(), Box<dyn Error>> {
+        std::fs::create_dir_all(&self.directory)?;
+        std::fs::write(self.flags_path(), flags)?;
+        Ok(())
+    }
+
+    // None if the ROM has never saved flags or the file is unreadable
+    pub fn load_flags(&self) -> Option<[u8; FLAG_COUNT]> {
+        std::fs::read(self.flags_path()).ok()?.try_into().ok()
+    }
+}
+
+// Read the state out of any slot file, e.g. one picked in a file dialog
+pub fn read_slot_file(path: &Path) -> Result<EmulatorState, Box<dyn Error>> {
+    let bytes: Vec<u8> = std::fs::read(path)?;
+    if bytes.len() < METADATA_SIZE {
+        return Err(Box::from("Save slot file is truncated"));
+    }
+    EmulatorState::from_bytes(&bytes[METADATA_SIZE..])
+}
+
+fn check_slot(slot: usize) -> Result<(), Box<dyn Error>> {
+    if slot >= SLOT_COUNT {
+        return Err(Box::from(format!("Save slot {} does not exist", slot)));
+    }
+    Ok(())
+}
+
+// FNV-1a, so the directory name is stable between builds
+fn rom_hash(rom: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in rom {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
@@ -0,0 +1,207 @@
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cpu::CpuSnapshot;
+
+// Identifies a save state file, followed by the format version
+const STATE_MAGIC: &[u8; 4] = b"PRS8";
+const STATE_VERSION: u8 = 1;
+
+// Everything needed to put the machine back exactly where it was. to_bytes is the save file format,
+// the serde derives let other tools store it however they like.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmulatorState {
+    pub cpu: CpuSnapshot,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub memory: Vec<u8>,
+    pub display_width: u32,
+    pub display_height: u32,
+    pub pixels: Vec<bool>, // Row by row, display_width * display_height pixels
+    pub selected_planes: u8, // See Display::selected_planes
+    pub second_plane: Vec<bool>, // Laid out like pixels
+}
+
+impl EmulatorState {
+    // Layout, all numbers little endian:
+    // magic, version, PC, SP, stack, V0-VF, I, delay timer, sound timer,
+    // memory length + memory, display width + height, one byte per pixel,
+    // selected planes, one byte per second plane pixel
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(64 + self.memory.len() + self.pixels.len() * 2);
+        bytes.extend_from_slice(STATE_MAGIC);
+        bytes.push(STATE_VERSION);
+
+        bytes.extend_from_slice(&self.cpu.pc.to_le_bytes());
+        bytes.push(self.cpu.sp);
+        for address in self.cpu.stack {
+            bytes.extend_from_slice(&address.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.cpu.v);
+        bytes.extend_from_slice(&self.cpu.i.to_le_bytes());
+        bytes.push(self.delay_timer);
+        bytes.push(self.sound_timer);
+
+        bytes.extend_from_slice(&(self.memory.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.memory);
+
+        bytes.extend_from_slice(&self.display_width.to_le_bytes());
+        bytes.extend_from_slice(&self.display_height.to_le_bytes());
+        bytes.extend(self.pixels.iter().map(|&pixel| pixel as u8));
+        bytes.push(self.selected_planes);
+        bytes.extend(self.second_plane.iter().map(|&pixel| pixel as u8));
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut reader: StateReader = StateReader { bytes };
+        if reader.take(4)? != STATE_MAGIC {
+            return Err(Box::from("Not a save state file"));
+        }
+        let version: u8 = reader.u8()?;
+        if version != STATE_VERSION {
+            return Err(Box::from(format!("Unsupported save state version {}", version)));
+        }
+
+        let pc: u16 = reader.u16()?;
+        let sp: u8 = reader.u8()?;
+        let mut stack: [u16; 16] = [0; 16];
+        for address in stack.iter_mut() {
+            *address = reader.u16()?;
+        }
+        let v: [u8; 16] = reader.take(16)?.try_into()?;
+        let i: u16 = reader.u16()?;
+        let delay_timer: u8 = reader.u8()?;
+        let sound_timer: u8 = reader.u8()?;
+
+        let memory_len: usize = reader.u32()? as usize;
+        let memory: Vec<u8> = reader.take(memory_len)?.to_vec();
+
+        let display_width: u32 = reader.u32()?;
+        let display_height: u32 = reader.u32()?;
+        let pixel_count: usize = display_width as usize * display_height as usize;
+        let pixels: Vec<bool> = reader.take(pixel_count)?.iter().map(|&pixel| pixel != 0).collect();
+        let selected_planes: u8 = reader.u8()?;
+        let second_plane: Vec<bool> = reader.take(pixel_count)?.iter().map(|&pixel| pixel != 0).collect();
+
+        Ok(EmulatorState {
+            cpu: CpuSnapshot { pc, sp, stack, v, i },
+            delay_timer,
+            sound_timer,
+            memory,
+            display_width,
+            display_height,
+            pixels,
+            selected_planes,
+            second_plane,
+        })
+    }
+}
+
+// Reads fields off the front of a save state, also used for replay bundles
+pub(crate) struct StateReader<'a> {
+    pub(crate) bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        if self.bytes.len() < len {
+            return Err(Box::from("File is truncated"));
+        }
+        let (field, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(field)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, Box<dyn Error>> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, Box<dyn Error>> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+}
+
+// A register (or other single value) that differs between two states
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegisterDiff {
+    pub name: String,
+    pub left: u16,
+    pub right: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryDiff {
+    pub address: usize,
+    pub left: u8,
+    pub right: u8,
+}
+
+// Everything that differs between two states. Only changed addresses and pixels are listed
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StateDiff {
+    pub registers: Vec<RegisterDiff>,
+    pub memory: Vec<MemoryDiff>,
+    pub pixels: Vec<(u32, u32)>, // (x, y) of each pixel that differs
+}
+
+impl StateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty() && self.memory.is_empty() && self.pixels.is_empty()
+    }
+
+    fn compare(&mut self, name: &str, left: u16, right: u16) {
+        if left != right {
+            self.registers.push(RegisterDiff { name: name.to_string(), left, right });
+        }
+    }
+}
+
+impl EmulatorState {
+    pub fn diff(&self, other: &EmulatorState) -> StateDiff {
+        let mut diff: StateDiff = StateDiff::default();
+
+        for (index, (&left, &right)) in self.cpu.v.iter().zip(other.cpu.v.iter()).enumerate() {
+            diff.compare(&format!("V{:X}", index), left as u16, right as u16);
+        }
+        diff.compare("I", self.cpu.i, other.cpu.i);
+        diff.compare("PC", self.cpu.pc, other.cpu.pc);
+        diff.compare("SP", self.cpu.sp as u16, other.cpu.sp as u16);
+        for (index, (&left, &right)) in self.cpu.stack.iter().zip(other.cpu.stack.iter()).enumerate() {
+            diff.compare(&format!("Stack {:X}", index), left, right);
+        }
+        diff.compare("DT", self.delay_timer as u16, other.delay_timer as u16);
+        diff.compare("ST", self.sound_timer as u16, other.sound_timer as u16);
+
+        for (address, (&left, &right)) in self.memory.iter().zip(other.memory.iter()).enumerate() {
+            if left != right {
+                diff.memory.push(MemoryDiff { address, left, right });
+            }
+        }
+
+        // Pixels can only be lined up when both displays are the same size
+        diff.compare("Width", self.display_width as u16, other.display_width as u16);
+        diff.compare("Height", self.display_height as u16, other.display_height as u16);
+        diff.compare("Planes", self.selected_planes as u16, other.selected_planes as u16);
+        if self.display_width == other.display_width && self.display_height == other.display_height {
+            let left_pixels = self.pixels.iter().zip(self.second_plane.iter());
+            let right_pixels = other.pixels.iter().zip(other.second_plane.iter());
+            for (index, (left, right)) in left_pixels.zip(right_pixels).enumerate() {
+                if left != right {
+                    let index: u32 = index as u32;
+                    diff.pixels.push((index % self.display_width, index / self.display_width));
+                }
+            }
+        }
+        diff
+    }
+}
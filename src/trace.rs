@@ -0,0 +1,110 @@
+use std::fmt;
+
+use crate::cpu::CpuSnapshot;
+
+// One executed instruction: the PC it was fetched from and the opcode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub pc: u16,
+    pub opcode: u16,
+}
+
+// Read a reference trace, one step per line as hex PC then hex opcode:
+//
+//     0200 00E0
+//     0x0202 0x6A02    # 0x prefixes are optional
+//
+// Anything after the first two columns (registers, disassembly) is ignored so
+// traces from other emulators can be used with little editing.
+pub fn parse(trace: &str) -> Result<Vec<TraceEntry>, String> {
+    let mut entries: Vec<TraceEntry> = Vec::new();
+    for (index, line) in trace.lines().enumerate() {
+        let line: &str = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut columns = line.split_whitespace();
+        let (Some(pc), Some(opcode)) = (columns.next(), columns.next()) else {
+            return Err(format!("Line {}: expected a PC and an opcode", index + 1));
+        };
+        entries.push(TraceEntry {
+            pc: parse_hex(pc).map_err(|message| format!("Line {}: {}", index + 1, message))?,
+            opcode: parse_hex(opcode).map_err(|message| format!("Line {}: {}", index + 1, message))?,
+        });
+    }
+    Ok(entries)
+}
+
+fn parse_hex(text: &str) -> Result<u16, String> {
+    let digits: &str = text.trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("'{}' is not a hex number", text))
+}
+
+// Checks each step of a run against a reference trace
+pub struct TraceComparator {
+    reference: Vec<TraceEntry>,
+    step: usize, // Index of the next reference entry
+}
+
+impl TraceComparator {
+    pub fn new(reference: Vec<TraceEntry>) -> Self {
+        TraceComparator { reference, step: 0 }
+    }
+
+    // Go back to the first step, for when the ROM is reloaded
+    pub fn restart(&mut self) {
+        self.step = 0;
+    }
+
+    // Every reference step has been matched
+    pub fn is_finished(&self) -> bool {
+        self.step >= self.reference.len()
+    }
+
+    // Number of reference steps matched so far
+    pub fn steps(&self) -> usize {
+        self.step
+    }
+
+    // Compare the instruction about to run. Steps past the end of the reference always match.
+    pub fn check(&mut self, actual: TraceEntry, cpu: &CpuSnapshot) -> Result<(), TraceMismatch> {
+        let Some(&expected) = self.reference.get(self.step) else {
+            return Ok(());
+        };
+        if expected != actual {
+            return Err(TraceMismatch { step: self.step, expected, actual, cpu: *cpu });
+        }
+        self.step += 1;
+        Ok(())
+    }
+}
+
+// The first step where the run left the reference, with the CPU state at that point
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceMismatch {
+    pub step: usize,
+    pub expected: TraceEntry,
+    pub actual: TraceEntry,
+    pub cpu: CpuSnapshot,
+}
+
+impl fmt::Display for TraceMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Trace differs at step {}: expected {:04X} {:04X}, got {:04X} {:04X}. ",
+            self.step, self.expected.pc, self.expected.opcode, self.actual.pc, self.actual.opcode
+        )?;
+        for (index, value) in self.cpu.v.iter().enumerate() {
+            write!(f, "V{:X}={:02X} ", index, value)?;
+        }
+        write!(f, "I={:04X} SP={} Stack=[", self.cpu.i, self.cpu.sp)?;
+        for (index, address) in self.cpu.stack.iter().take(self.cpu.sp as usize).enumerate() {
+            if index > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{:04X}", address)?;
+        }
+        write!(f, "]")
+    }
+}
@@ -0,0 +1,23 @@
+use std::path::{Path, PathBuf};
+
+use pico_rs_8::headless::assert_frames_match;
+
+// V0 = 8, I = glyph 8, draw it at (2, 3), then loop forever
+const DIGIT_ROM: [u8; 12] = [0x60, 0x08, 0xF0, 0x29, 0x61, 0x02, 0x62, 0x03, 0xD1, 0x25, 0x12, 0x0A];
+
+fn reference(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/frames").join(name)
+}
+
+#[test]
+fn frames_match_the_reference() {
+    let digit: PathBuf = reference("digit_8.png");
+    assert_frames_match(&DIGIT_ROM, &[(1, &digit), (30, &digit)]);
+}
+
+#[test]
+#[should_panic(expected = "Frame 0: 16 pixels differ")]
+fn a_mismatch_counts_the_pixels() {
+    // Nothing has run yet at frame 0, so every pixel of the glyph is missing
+    assert_frames_match(&DIGIT_ROM, &[(0, &reference("digit_8.png"))]);
+}
@@ -0,0 +1,242 @@
+use std::time::Duration;
+
+use pico_rs_8::emulator::{EmulatorEvent, LogLevel, Watch};
+use pico_rs_8::headless::{HeadlessEmulator, StopReason};
+use pico_rs_8::opcode_filter::{self, OpcodeFilter};
+
+// A 64x32 screen with art drawn at (x, y) and every other pixel unlit
+fn expected_screen(art: &[&str], x: usize, y: usize) -> Vec<String> {
+    (0..32)
+        .map(|row: usize| {
+            let mut line: Vec<char> = vec!['.'; 64];
+            if let Some(art_row) = row.checked_sub(y).and_then(|art_row| art.get(art_row)) {
+                for (column, pixel) in art_row.chars().enumerate() {
+                    line[x + column] = pixel;
+                }
+            }
+            line.into_iter().collect()
+        })
+        .collect()
+}
+
+#[test]
+fn draws_a_font_digit() {
+    // V0 = 8, I = glyph 8, draw it at (2, 3), then loop forever
+    let rom: [u8; 12] = [0x60, 0x08, 0xF0, 0x29, 0x61, 0x02, 0x62, 0x03, 0xD1, 0x25, 0x12, 0x0A];
+    let mut headless: HeadlessEmulator = HeadlessEmulator::new(&rom).unwrap();
+    headless.run(1000);
+    let eight: [&str; 5] = ["####", "#..#", "####", "#..#", "####"];
+    assert_eq!(headless.screen(), expected_screen(&eight, 2, 3));
+    assert_eq!(headless.cpu().pc, 0x20A);
+}
+
+#[test]
+fn input_script_presses_a_key() {
+    // Wait for key 5 with EX9E, then draw glyph 5 at (0, 0)
+    let rom: [u8; 12] = [0x60, 0x05, 0xE0, 0x9E, 0x12, 0x02, 0xF0, 0x29, 0xD1, 0x15, 0x12, 0x0A];
+    let mut headless: HeadlessEmulator = HeadlessEmulator::new(&rom).unwrap().with_input_script("frame 2: press 5").unwrap();
+
+    // Nothing is drawn before the press
+    headless.run_frames(2);
+    assert_eq!(headless.screen(), expected_screen(&[], 0, 0));
+
+    headless.run_frames(1);
+    let five: [&str; 5] = ["####", "#...", "####", "...#", "####"];
+    assert_eq!(headless.screen(), expected_screen(&five, 0, 0));
+}
+
+#[test]
+fn run_until_reports_why_it_stopped() {
+    // 1200 jumps to itself forever
+    let mut headless: HeadlessEmulator = HeadlessEmulator::new(&[0x12, 0x00]).unwrap();
+    assert_eq!(headless.run_until(50, None), StopReason::CycleLimit);
+    assert_eq!(headless.emulator.cycles, 50);
+    assert_eq!(headless.run_until(u64::MAX, Some(Duration::from_millis(20))), StopReason::TimeLimit);
+
+    // 00FD exits at 0x202, it's a SUPER-CHIP instruction
+    let mut headless: HeadlessEmulator = HeadlessEmulator::new(&[0x60, 0x01, 0x00, 0xFD, 0x12, 0x04]).unwrap();
+    headless.emulator.cpu.schip = true;
+    assert_eq!(headless.run_until(u64::MAX, None), StopReason::ProgramEnded);
+    assert_eq!(headless.emulator.cycles, 2);
+}
+
+#[test]
+fn each_frame_sees_the_display() {
+    // 1200 jumps to itself forever
+    let mut headless: HeadlessEmulator = HeadlessEmulator::new(&[0x12, 0x00]).unwrap();
+    let mut frames: u64 = 0;
+    headless.run_until_each_frame(1000, None, |display| {
+        assert_eq!((display.width, display.height), (64, 32));
+        frames += 1;
+    });
+    assert_eq!(frames, headless.frames());
+    assert_eq!(frames, 120);
+}
+
+#[test]
+fn font_survives_a_rom_load() {
+    // The first ROM zeroes glyph 8 with FF55, the second draws glyph 8 from it with FX29
+    let mut headless: HeadlessEmulator = HeadlessEmulator::new(&[0xA0, 0x78, 0xFF, 0x55, 0x12, 0x04]).unwrap();
+    headless.run(100);
+    let rom: [u8; 12] = [0x60, 0x08, 0xF0, 0x29, 0x61, 0x02, 0x62, 0x03, 0xD1, 0x25, 0x12, 0x0A];
+    headless.emulator.load_rom(&rom).unwrap();
+    headless.run(100);
+    let eight: [&str; 5] = ["####", "#..#", "####", "#..#", "####"];
+    assert_eq!(headless.screen(), expected_screen(&eight, 2, 3));
+}
+
+#[test]
+fn timers_keep_their_rate_across_resets() {
+    // DT = 60, then loop forever
+    let mut headless: HeadlessEmulator = HeadlessEmulator::new(&[0x60, 0x3C, 0xF0, 0x15, 0x12, 0x04]).unwrap();
+    for _ in 0..10 {
+        headless.run_frames(30);
+        assert_eq!(headless.cpu().delay, 30);
+        headless.emulator.cold_reset().unwrap();
+        assert_eq!((headless.cpu().delay, headless.frames()), (0, 0));
+    }
+}
+
+#[test]
+fn odd_sized_fonts_draw_without_panicking() {
+    // Draw glyph F at (0, 0) with a font of all lit rows, one byte short or one byte over
+    let rom: [u8; 10] = [0x60, 0x0F, 0xF0, 0x29, 0x61, 0x00, 0xD1, 0x15, 0x12, 0x08];
+    for (length, lit_rows) in [(79, 4), (81, 5)] {
+        let mut headless: HeadlessEmulator = HeadlessEmulator::new(&rom).unwrap();
+        headless.emulator.set_font(&vec![0xF0; length]);
+        headless.emulator.load_rom(&rom).unwrap();
+        headless.run(100);
+        let glyph: Vec<&str> = vec!["####"; lit_rows];
+        assert_eq!(headless.screen(), expected_screen(&glyph, 0, 0), "{} byte font", length);
+        let warnings: usize = headless.take_events().iter().filter(|event| event.level() == LogLevel::Warning).count();
+        assert_eq!(warnings, (length < 80) as usize, "{} byte font", length);
+    }
+}
+
+#[test]
+fn flags_survive_a_warm_reset() {
+    // 0x200: V0 = 0x11, V7 = 0x77, V8 = 0x88, F975 (X above 7 stops at V7), loop
+    // 0x20A: F985, loop
+    let rom: [u8; 14] = [0x60, 0x11, 0x67, 0x77, 0x68, 0x88, 0xF9, 0x75, 0x12, 0x08, 0xF9, 0x85, 0x12, 0x0C];
+    let mut headless: HeadlessEmulator = HeadlessEmulator::new(&rom).unwrap();
+    // FX75 and FX85 are SUPER-CHIP instructions
+    headless.emulator.cpu.schip = true;
+    headless.run(10);
+    assert_eq!(headless.emulator.cpu.flags, [0x11, 0, 0, 0, 0, 0, 0, 0x77]);
+
+    headless.emulator.warm_reset();
+    assert_eq!(headless.cpu().v, [0; 16]);
+    headless.emulator.cpu.pc = 0x20A;
+    headless.run(10);
+    assert_eq!(headless.cpu().v, [0x11, 0, 0, 0, 0, 0, 0, 0x77, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+    // Another ROM load starts without them, unless persist_flags keeps them on disk
+    headless.emulator.cold_reset().unwrap();
+    assert_eq!(headless.emulator.cpu.flags, [0; 8]);
+}
+
+#[test]
+fn denied_opcodes_pause_before_running() {
+    // V0 = 1, I = glyph 0, draw it at (1, 1), V1 = 1, loop
+    let rom: [u8; 10] = [0x60, 0x01, 0xA0, 0x50, 0xD0, 0x05, 0x61, 0x01, 0x12, 0x08];
+    let mut headless: HeadlessEmulator = HeadlessEmulator::new(&rom).unwrap();
+    headless.emulator.opcode_filter = OpcodeFilter::Deny(opcode_filter::parse_patterns("DXYN").unwrap());
+    headless.run(100);
+    assert!(headless.emulator.is_paused());
+    assert_eq!((headless.cpu().pc, headless.cpu().v[0x1]), (0x204, 0));
+    assert_eq!(headless.screen(), expected_screen(&[], 0, 0));
+    let denied: Vec<EmulatorEvent> = headless
+        .take_events()
+        .into_iter()
+        .filter(|event| matches!(event, EmulatorEvent::OpcodeDenied { .. }))
+        .collect();
+    assert!(matches!(denied[..], [EmulatorEvent::OpcodeDenied { pc: 0x204, opcode: 0xD005 }]));
+
+    // Allowing it again and resuming runs the draw
+    headless.emulator.opcode_filter = OpcodeFilter::AllowAll;
+    headless.emulator.set_paused(false);
+    headless.run(100);
+    assert_eq!(headless.cpu().v[0x1], 1);
+    let zero: [&str; 5] = ["####", "#..#", "#..#", "#..#", "####"];
+    assert_eq!(headless.screen(), expected_screen(&zero, 1, 1));
+}
+
+#[test]
+fn pausing_freezes_the_timers() {
+    // DT = ST = 60, then loop forever
+    let mut headless: HeadlessEmulator = HeadlessEmulator::new(&[0x60, 0x3C, 0xF0, 0x15, 0xF0, 0x18, 0x12, 0x06]).unwrap();
+    headless.run_frames(10);
+    assert_eq!((headless.cpu().delay, headless.cpu().sound), (50, 50));
+
+    headless.emulator.set_paused(true);
+    headless.run(1000);
+    assert_eq!((headless.cpu().delay, headless.cpu().sound, headless.frames()), (50, 50, 10));
+
+    headless.emulator.set_paused(false);
+    headless.run_frames(10);
+    assert_eq!((headless.cpu().delay, headless.cpu().sound), (40, 40));
+}
+
+#[test]
+fn register_watch_pauses_on_a_change() {
+    // V3 = 0 leaves it as it was, then V3 = 5, V3 = 6
+    let rom: [u8; 8] = [0x63, 0x00, 0x63, 0x05, 0x63, 0x06, 0x12, 0x06];
+    let mut headless: HeadlessEmulator = HeadlessEmulator::new(&rom).unwrap();
+    headless.emulator.add_register_watch(0x3);
+    let watch_events = |headless: &mut HeadlessEmulator| -> Vec<String> {
+        headless
+            .take_events()
+            .into_iter()
+            .filter(|event| matches!(event, EmulatorEvent::WatchTriggered { .. }))
+            .map(|event| event.to_string())
+            .collect()
+    };
+
+    headless.run(100);
+    assert!(headless.emulator.is_paused());
+    assert_eq!((headless.cpu().pc, headless.cpu().v[0x3]), (0x204, 5));
+    assert_eq!(watch_events(&mut headless), ["Paused after 0202 changed V3 from 00 to 05"]);
+
+    headless.emulator.set_paused(false);
+    headless.run(100);
+    assert_eq!(watch_events(&mut headless), ["Paused after 0204 changed V3 from 05 to 06"]);
+
+    // Once removed, running the changes again pauses nothing
+    headless.emulator.remove_watch(Watch::Register(0x3));
+    headless.emulator.cpu.pc = 0x202;
+    headless.emulator.set_paused(false);
+    headless.run(100);
+    assert!(!headless.emulator.is_paused());
+    assert_eq!(watch_events(&mut headless), Vec::<String>::new());
+}
+
+#[test]
+fn display_wait_draws_once_per_frame() {
+    // Draw, count the draw in V1, repeat
+    let rom: [u8; 6] = [0xD0, 0x01, 0x71, 0x01, 0x12, 0x00];
+    for display_wait in [false, true] {
+        let mut headless: HeadlessEmulator = HeadlessEmulator::new(&rom).unwrap();
+        headless.emulator.cpu.quirks.display_wait = display_wait;
+        for frame in 1..=60 {
+            headless.run_frames(1);
+            let draws: u64 = headless.cpu().v[0x1] as u64;
+            if display_wait {
+                // The draw at the start of each frame, the first one maybe still to come
+                assert!((frame - 1..=frame).contains(&draws), "{} draws in {} frames", draws, frame);
+            } else {
+                assert!(draws > frame, "{} draws in {} frames", draws, frame);
+            }
+        }
+    }
+}
+
+#[test]
+fn cpu_errors_are_logged_with_the_pc() {
+    // 00EE with an empty stack, then an unknown opcode, then loop
+    let mut headless: HeadlessEmulator = HeadlessEmulator::new(&[0x00, 0xEE, 0xE1, 0xFF, 0x12, 0x04]).unwrap();
+    headless.run(10);
+    assert_eq!(headless.cpu().pc, 0x204);
+    let messages: Vec<String> = headless.take_events().iter().map(|event| event.to_string()).collect();
+    assert!(messages.contains(&"Stack underflow: cannot return from subroutine at 0200".to_string()), "{:?}", messages);
+    assert!(messages.contains(&"Unknown opcode E1FF at 0202".to_string()), "{:?}", messages);
+}
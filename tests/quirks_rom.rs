@@ -0,0 +1,49 @@
+use std::path::{Path, PathBuf};
+
+use pico_rs_8::headless::HeadlessEmulator;
+use pico_rs_8::quirks::{LoadStoreIncrement, Quirks};
+
+// Timendus' quirks test ROM from the CHIP-8 test suite, fetched by tests/roms/fetch.sh. Without it
+// the test only says it was skipped.
+fn quirks_rom() -> Option<Vec<u8>> {
+    let path: PathBuf = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/roms/5-quirks.ch8");
+    let rom: Option<Vec<u8>> = std::fs::read(&path).ok();
+    if rom.is_none() {
+        eprintln!("Skipped, {} is missing. Run tests/roms/fetch.sh to get it.", path.display());
+    }
+    rom
+}
+
+// The ROM's result screen after testing the platform it finds at 0x1FF (1 for CHIP-8, 2 for
+// SUPER-CHIP) with FX55 and FX65 moving I the given way
+fn result_screen(rom: &[u8], platform: u8, quirks: Quirks, mode: LoadStoreIncrement) -> Vec<String> {
+    let mut headless: HeadlessEmulator = HeadlessEmulator::new(rom).unwrap();
+    headless.emulator.memory.write(0x1FF, platform);
+    headless.emulator.cpu.schip = platform == 2;
+    headless.emulator.cpu.quirks = Quirks { load_store_increments_i: mode, ..quirks };
+    headless.emulator.display.wrap_x = quirks.wrap_x;
+    headless.emulator.display.wrap_y = quirks.wrap_y;
+    // Long enough for the display wait test, which times itself on the delay timer
+    headless.run_frames(600);
+    headless.screen()
+}
+
+// The memory row shows a pass only for the mode the platform expects. Every other row is the same
+// between runs on one platform, so a pass shows up as the same screen as the expected mode's.
+#[test]
+fn memory_quirk_matches_the_reference_rom() {
+    let Some(rom) = quirks_rom() else {
+        return;
+    };
+    let modes: [LoadStoreIncrement; 3] = [LoadStoreIncrement::Unchanged, LoadStoreIncrement::XPlusOne, LoadStoreIncrement::X];
+    for (platform, quirks, expected) in [
+        (1, Quirks::chip8(), LoadStoreIncrement::XPlusOne),
+        (2, Quirks::superchip(), LoadStoreIncrement::Unchanged),
+    ] {
+        let passing: Vec<String> = result_screen(&rom, platform, quirks, expected);
+        for mode in modes {
+            let screen: Vec<String> = result_screen(&rom, platform, quirks, mode);
+            assert_eq!(screen == passing, mode == expected, "platform {} with {:?}:\n{}", platform, mode, screen.join("\n"));
+        }
+    }
+}
@@ -0,0 +1,22 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use pico_rs_8::headless::HeadlessEmulator;
+
+// A quick, repeatable version of the fuzz target in fuzz/: ROMs are untrusted, so random bytes must
+// never panic the emulator, whatever platforms are switched on
+#[test]
+fn random_roms_never_panic() {
+    let mut rng: StdRng = StdRng::seed_from_u64(0x5EED);
+    for run in 0..100 {
+        let length: usize = rng.random_range(1..=0xE00);
+        let rom: Vec<u8> = (0..length).map(|_| rng.random()).collect();
+        let mut headless: HeadlessEmulator = HeadlessEmulator::new(&rom).unwrap();
+        // Every other run with all the extensions on
+        let extensions: bool = run % 2 == 1;
+        headless.emulator.cpu.chip8e = extensions;
+        headless.emulator.cpu.schip = extensions;
+        headless.emulator.cpu.xochip = extensions;
+        headless.run(3000);
+    }
+}
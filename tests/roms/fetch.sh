@@ -0,0 +1,6 @@
+#!/bin/sh
+# Download the reference ROMs the tests run when they are present. They aren't ours to license,
+# so they stay out of the repository.
+set -e
+cd "$(dirname "$0")"
+curl -fsSL -o 5-quirks.ch8 https://github.com/Timendus/chip8-test-suite/raw/main/bin/5-quirks.ch8